@@ -1,4 +1,3 @@
-#![feature(let_chains)]
 use std::{collections::HashMap, fs::read_to_string};
 
 const ROLES: &[&str] = &[
@@ -1,4 +1,11 @@
-use std::{fmt::Display, fs::File, io::Write, iter::once, ops::Range, path::Path};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::Write,
+    iter::once,
+    ops::{Not, Range},
+    path::Path,
+};
 
 use crate::{
     PHASE, SEP,
@@ -200,6 +207,10 @@ pub fn generate(epub: &Epub, meta: &Meta) -> Vec<Chapter> {
     let roles = heuristics::infer_roles(all_chapters.clone().map(|(name, _)| name.as_str()));
 
     for ((name, idxs), role) in all_chapters.zip(roles) {
+        // the notes are output separately, so chapters of only notes are skipped
+        let only_notes = body.get(idxs.clone()).is_some_and(|files| {
+            files.is_empty().not() && files.iter().all(|(href, _)| epub.notes.is_note_doc(href))
+        });
         chapters.push(Chapter {
             book_name: meta.title.clone(),
             chap_name: name.to_owned(),
@@ -215,7 +226,7 @@ pub fn generate(epub: &Epub, meta: &Meta) -> Vec<Chapter> {
                 .cloned()
                 .collect(),
             role,
-            skip: heuristics::is_skip(role),
+            skip: heuristics::is_skip(role) || only_notes,
         });
     }
     chapters
@@ -0,0 +1,146 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    ops::{Not, Range},
+};
+
+use crate::{error::OrDie, 死};
+
+/// The brackets that quote speech, with their closing counterparts
+const BRACKETS: &[(char, char)] = &[('「', '」'), ('『', '』'), ('（', '）')];
+
+/// What a paragraph of body text consists of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speech {
+    Narration,
+    Dialogue,  // 「」 or 『』
+    Monologue, // （）, inner speech
+    Mixed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpeechSpan {
+    pub span: Range<usize>,
+    pub speech: Speech,
+}
+
+/// Classifies paragraphs in reading order, following the quotations that continue
+/// over several paragraphs
+#[derive(Debug, Default)]
+pub struct Classifier {
+    open: Vec<(char, char, Speech)>, // the brackets that are open, and what they quote
+}
+
+impl Classifier {
+    pub fn classify(&mut self, text: &str) -> Speech {
+        let text = text.trim();
+        // A quotation over several paragraphs opens each of them again, and closes only
+        // at the end. If a paragraph doesn't, the previous one just lacked a closing bracket.
+        let mut chars = text.chars().peekable();
+        match (self.open.first(), chars.peek()) {
+            (Some(&(opening, ..)), Some(&first)) if first == opening => {
+                self.open.truncate(1);
+                chars.next();
+            }
+            _ => self.open.clear(),
+        }
+        let mut counts = [0; 3]; // narration, dialogue, monologue
+        let mut count = |speech| match speech {
+            Speech::Dialogue => counts[1] += 1,
+            Speech::Monologue => counts[2] += 1,
+            Speech::Narration | Speech::Mixed => counts[0] += 1,
+        };
+        if let Some(&(_, _, speech)) = self.open.first() {
+            count(speech);
+        }
+        let mut in_annotation = false; // ［＃…］ isn't text
+        let mut at_start = self.open.is_empty();
+        while let Some(ch) = chars.next() {
+            if ch == '［' && chars.peek() == Some(&'＃') {
+                in_annotation = true;
+            }
+            if in_annotation || ch.is_whitespace() {
+                in_annotation &= ch != '］';
+                continue;
+            }
+            let current = self
+                .open
+                .last()
+                .map_or(Speech::Narration, |&(.., speech)| speech);
+            if let Some(&(opening, closing)) = BRACKETS.iter().find(|(opening, _)| *opening == ch) {
+                let speech = match (current, opening) {
+                    // parentheses within narration are asides, unless they span the paragraph
+                    (Speech::Narration, '（') if at_start.not() => Speech::Narration,
+                    (Speech::Narration, '（') => Speech::Monologue,
+                    (Speech::Narration, _) => Speech::Dialogue,
+                    (speech, _) => speech,
+                };
+                self.open.push((opening, closing, speech));
+                count(speech);
+            } else if let Some(depth) = self.open.iter().rposition(|&(_, closing, _)| closing == ch)
+            {
+                // also recovers from missing closing brackets of nested quotations
+                let (.., speech) = self.open[depth];
+                self.open.truncate(depth);
+                count(speech);
+            } else {
+                count(current);
+            }
+            at_start = false;
+        }
+        match counts {
+            [_, 0, 0] => Speech::Narration,
+            [0, _, 0] => Speech::Dialogue,
+            [0, 0, _] => Speech::Monologue,
+            _ => Speech::Mixed,
+        }
+    }
+}
+
+impl Display for Speech {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let speech = match self {
+            Speech::Narration => "narration",
+            Speech::Dialogue => "dialogue",
+            Speech::Monologue => "monologue",
+            Speech::Mixed => "mixed",
+        };
+        f.write_str(speech)
+    }
+}
+
+pub fn write_speech(speech: &[SpeechSpan], mut file: impl Write) {
+    for SpeechSpan {
+        span: Range { start, end },
+        speech,
+    } in speech
+    {
+        writeln!(file, "{start}:{end}:{speech}").or_(死!());
+    }
+}
+
+#[test]
+fn test_classify() {
+    use Speech::*;
+
+    let mut classifier = Classifier::default();
+    let paragraphs = [
+        ("　朝が来た。", Narration),
+        ("「おはよう。『元気？』って聞いてよ」", Dialogue),
+        ("「ええ」と彼女は言った。", Mixed),
+        ("（眠い……）", Monologue),
+        ("　彼（十七歳）は頷いた。", Narration),
+        // a quotation over three paragraphs
+        ("「昔々、", Dialogue),
+        ("「あるところに（多分）、", Dialogue),
+        ("「お爺さんがいました」", Dialogue),
+        ("　話は終わった。", Narration),
+        // an unclosed bracket doesn't spill over
+        ("「閉じ忘れ", Dialogue),
+        ("　地の文。", Narration),
+        ("［＃３字下げ］「はい」", Dialogue),
+    ];
+    for (text, expected) in paragraphs {
+        assert_eq!(classifier.classify(text), expected, "{text}");
+    }
+}
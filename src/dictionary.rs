@@ -0,0 +1,294 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    ops::Not,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{OrDie, 死};
+use crate::readings;
+use crate::yomi::{self, Ruby, RubyFormat};
+
+/// The MeCab cost of a word seen once; every doubling of the count makes it this much cheaper
+const BASE_COST: f64 = 7000.0;
+const COST_PER_DOUBLING: f64 = 350.0;
+const MIN_COST: f64 = 1000.0;
+
+/// A reading of a base text, as the editors of the books wrote it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub rb: String,
+    pub rt: String,
+    pub count: usize,
+    pub books: BTreeMap<String, usize>, // the count in each book, by its name
+}
+
+/// The readings of the corpus, by the base text and the reading
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    entries: HashMap<(String, String), Entry>,
+}
+
+/// The ruby outputs of the books in a directory: `ラノベ.ruby.yomi` etc., but not the
+/// further tiers of double-sided ruby, which are glosses rather than readings
+fn ruby_files(dir: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        let Some(fname) = path.file_name().and_then(|fname| fname.to_str()) else {
+            continue;
+        };
+        let book = fname
+            .rsplit_once(".ruby.")
+            .filter(|(_, ext)| RubyFormat::from_extension(ext).is_some())
+            .map(|(book, _)| book);
+        if let Some(book) = book {
+            files.push((book.to_owned(), path.clone()));
+        }
+    }
+    // a book output in several formats once
+    files.sort();
+    files.dedup_by(|a, b| a.0 == b.0);
+    files
+}
+
+/// Reads the ruby of a book in any of the output formats; the offset formats need the txt
+fn read_ruby(path: &Path) -> (String, Vec<Ruby>) {
+    let source = std::fs::read_to_string(path).or_(死!("failed to read {path:?}"));
+    let fname = path.to_string_lossy();
+    let (stem, format) = fname
+        .rsplit_once(".ruby.")
+        .and_then(|(stem, ext)| Some((stem, RubyFormat::from_extension(ext)?)))
+        .or_(死!("{path:?} isn't a ruby output"));
+    format.read(&source, || {
+        let txt_fname = PathBuf::from(format!("{stem}.txt"));
+        std::fs::read_to_string(&txt_fname).or_(死!("failed to read {txt_fname:?}"))
+    })
+}
+
+/// Whether the ruby is a reading of kanji, rather than e.g. a gloss like 本気《マジ》 or ruby on kana
+fn is_reading(rb: &str, rt: &str) -> bool {
+    rb.chars().any(yomi::is_kanji)
+        && rb.chars().any(char::is_whitespace).not()
+        && readings::classify(rb, rt).is_reading()
+}
+
+fn to_katakana(reading: &str) -> String {
+    reading
+        .chars()
+        .map(|ch| match ch {
+            'ぁ'..='ゖ' | 'ゝ' | 'ゞ' => char::from_u32(ch as u32 + 0x60).unwrap_or(ch),
+            ch => ch,
+        })
+        .collect()
+}
+
+fn to_hiragana(reading: &str) -> String {
+    reading
+        .chars()
+        .map(|ch| match ch {
+            'ァ'..='ヶ' | 'ヽ' | 'ヾ' => char::from_u32(ch as u32 - 0x60).unwrap_or(ch),
+            ch => ch,
+        })
+        .collect()
+}
+
+impl Dictionary {
+    pub fn add(&mut self, book: &str, rb: &str, rt: &str) {
+        let mut buf = String::new();
+        let rt = yomi::fix_little_yomi(rb, rt, &mut buf);
+        if is_reading(rb, rt).not() {
+            return;
+        }
+        let entry = self
+            .entries
+            .entry((rb.to_owned(), rt.to_owned()))
+            .or_insert_with(|| Entry {
+                rb: rb.to_owned(),
+                rt: rt.to_owned(),
+                ..Entry::default()
+            });
+        entry.count += 1;
+        *entry.books.entry(book.to_owned()).or_default() += 1;
+    }
+
+    /// Adds the books in the output directories, or in their subdirectories
+    pub fn add_dir(&mut self, dir: &Path) -> usize {
+        let mut files = ruby_files(dir);
+        if files.is_empty() {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                return 0;
+            };
+            let mut subdirs: Vec<_> = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_dir())
+                .collect();
+            subdirs.sort();
+            files = subdirs.iter().flat_map(|dir| ruby_files(dir)).collect();
+        }
+        for (book, path) in &files {
+            let (txt, ruby) = read_ruby(path);
+            for Ruby { span, rt } in ruby {
+                self.add(book, &txt[span], &rt);
+            }
+        }
+        files.len()
+    }
+
+    /// The entries, the most frequent first
+    pub fn ranked(&self) -> Vec<&Entry> {
+        let mut entries: Vec<_> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            (b.count.cmp(&a.count))
+                .then_with(|| a.rt.cmp(&b.rt))
+                .then_with(|| a.rb.cmp(&b.rb))
+        });
+        entries
+    }
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// A MeCab user dictionary source, for `mecab-dict-index`. The context ids are left for
+/// the model to assign (`-a`); the cost falls with the count, and the count and the books
+/// (`book:count|…`) follow the reading and pronunciation as extra features.
+pub fn write_mecab(entries: &[&Entry], mut file: impl Write) {
+    for Entry {
+        rb,
+        rt,
+        count,
+        books,
+    } in entries
+    {
+        let cost = (BASE_COST - COST_PER_DOUBLING * (*count as f64).log2()).max(MIN_COST);
+        let reading = to_katakana(rt);
+        let books: Vec<_> = books
+            .iter()
+            .map(|(book, count)| format!("{book}:{count}"))
+            .collect();
+        writeln!(
+            file,
+            "{},,,{cost:.0},名詞,一般,*,*,*,*,{},{reading},{reading},{count},{}",
+            csv_field(rb),
+            csv_field(rb),
+            csv_field(&books.join("|")),
+        )
+        .or_(死!());
+    }
+}
+
+/// Training data of the small kana model, like `aux_data/small_kana_train.tsv`: the base text
+/// and the reading in hiragana, tab-separated
+pub fn write_training(entries: &[&Entry], mut file: impl Write) {
+    for Entry { rb, rt, .. } in entries {
+        writeln!(file, "{rb}\t{}", to_hiragana(rt)).or_(死!());
+    }
+}
+
+/// An SKK dictionary candidate, in the `(concat "…")` form if it has / or ;
+fn skk_candidate(rb: &str) -> String {
+    if rb.contains(['/', ';']) {
+        let escaped = rb.replace('/', "\\057").replace(';', "\\073");
+        format!("(concat \"{escaped}\")")
+    } else {
+        rb.to_owned()
+    }
+}
+
+/// An SKK-JISYO of okuri-nasi entries, with the candidates of each reading the most frequent
+/// first, annotated with their counts and books
+pub fn write_skk(entries: &[&Entry], mut file: impl Write) {
+    let mut readings: BTreeMap<String, Vec<&Entry>> = BTreeMap::new();
+    for &entry in entries {
+        readings
+            .entry(to_hiragana(&entry.rt))
+            .or_default()
+            .push(entry);
+    }
+    writeln!(file, ";; -*- mode: fundamental; coding: utf-8 -*-").or_(死!());
+    writeln!(file, ";; okuri-ari entries.").or_(死!());
+    writeln!(file, ";; okuri-nasi entries.").or_(死!());
+    for (reading, entries) in readings {
+        let mut candidates: Vec<(String, usize, Vec<&str>)> = Vec::new();
+        // katakana and hiragana readings of the same base are one candidate
+        for entry in entries {
+            match candidates.iter_mut().find(|(rb, ..)| *rb == entry.rb) {
+                Some((_, count, books)) => {
+                    *count += entry.count;
+                    books.extend(entry.books.keys().map(String::as_str));
+                }
+                None => candidates.push((
+                    entry.rb.clone(),
+                    entry.count,
+                    entry.books.keys().map(String::as_str).collect(),
+                )),
+            }
+        }
+        candidates.sort_by_key(|(_, count, _)| Reverse(*count));
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .map(|(rb, count, mut books)| {
+                books.sort();
+                books.dedup();
+                let books = books.join(",").replace(['/', ';'], "_");
+                format!("{};{count} {books}", skk_candidate(&rb))
+            })
+            .collect();
+        writeln!(file, "{reading} /{}/", candidates.join("/")).or_(死!());
+    }
+}
+
+#[test]
+fn test_dictionary() {
+    let mut dictionary = Dictionary::default();
+    dictionary.add("A", "喋", "しやべ");
+    dictionary.add("A", "喋", "しゃべ");
+    dictionary.add("B", "喋", "しゃべ");
+    dictionary.add("B", "本気", "マジ");
+    dictionary.add("B", "本気", "ほんき");
+    dictionary.add("B", "ほんき", "ホンキ"); // not a reading of kanji
+    dictionary.add("B", "Rust", "さび");
+    dictionary.add("B", "業", "Karma");
+    let entries = dictionary.ranked();
+    assert_eq!(entries.len(), 2); // 本気《マジ》 is a gloss
+    assert_eq!(
+        (entries[0].rb.as_str(), entries[0].rt.as_str()),
+        ("喋", "しゃべ")
+    );
+    assert_eq!(entries[0].count, 3);
+    assert_eq!(
+        entries[0].books,
+        BTreeMap::from([("A".into(), 2), ("B".into(), 1)])
+    );
+
+    let mut mecab = Vec::new();
+    write_mecab(&entries, &mut mecab);
+    let mecab = String::from_utf8(mecab).unwrap();
+    assert_eq!(
+        mecab.lines().next(),
+        Some("喋,,,6445,名詞,一般,*,*,*,*,喋,シャベ,シャベ,3,A:2|B:1")
+    );
+
+    let mut training = Vec::new();
+    write_training(&entries, &mut training);
+    let training = String::from_utf8(training).unwrap();
+    assert_eq!(training, "喋\tしゃべ\n本気\tほんき\n");
+
+    let mut skk = Vec::new();
+    write_skk(&entries, &mut skk);
+    let skk = String::from_utf8(skk).unwrap();
+    assert!(skk.contains("\nしゃべ /喋;3 A,B/\n"));
+    assert!(skk.contains("\nほんき /本気;1 B/\n"));
+    assert_eq!(skk_candidate("a/b"), r#"(concat "a\057b")"#);
+    assert_eq!(csv_field("a,\"b\""), r#""a,""b""""#);
+}
@@ -0,0 +1,175 @@
+use std::{fmt::Display, io::Write, ops::Range};
+
+use crate::{error::OrDie, 死};
+
+/// The shape of 傍点 marks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shape {
+    Sesame,
+    Dot,
+    Circle,
+    DoubleCircle,
+    Triangle,
+    Custom(String), // a string given in CSS, or a character in rt
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mark {
+    pub shape: Shape,
+    pub open: bool, // hollow instead of filled
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emphasis {
+    pub span: Range<usize>,
+    pub mark: Mark,
+}
+
+impl Shape {
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        let shape = match keyword {
+            "sesame" => Shape::Sesame,
+            "dot" => Shape::Dot,
+            "circle" => Shape::Circle,
+            "double-circle" => Shape::DoubleCircle,
+            "triangle" => Shape::Triangle,
+            _ => return None,
+        };
+        Some(shape)
+    }
+}
+
+impl Mark {
+    /// Parses the value of `text-emphasis-style`, e.g. `filled sesame`, `open`, `"﹅"`.
+    /// The shape defaults to sesame, as in vertical writing.
+    pub fn from_css(value: &str) -> Self {
+        let value = value.trim();
+        if let Some(custom) = value
+            .strip_prefix(['"', '\''])
+            .and_then(|value| value.strip_suffix(['"', '\'']))
+        {
+            return Mark {
+                shape: Shape::Custom(custom.to_owned()),
+                open: false,
+            };
+        }
+        let mut mark = Mark {
+            shape: Shape::Sesame,
+            open: false,
+        };
+        for keyword in value.split_ascii_whitespace() {
+            match keyword {
+                "open" => mark.open = true,
+                "filled" => mark.open = false,
+                keyword => mark.shape = Shape::from_keyword(keyword).unwrap_or(mark.shape),
+            }
+        }
+        mark
+    }
+
+    /// Recognizes class names like `bouten`, `em-dot` or `bouten-open-circle`.
+    /// A bare shape like `dot` is too common a class name to mean emphasis.
+    pub fn from_class(class: &str) -> Option<Self> {
+        if ["bouten", "boten"].contains(&class) {
+            return Some(Mark {
+                shape: Shape::Sesame,
+                open: false,
+            });
+        }
+        let class = ["em-", "bouten-", "boten-"]
+            .iter()
+            .find_map(|prefix| class.strip_prefix(prefix))?;
+        let (class, open) = match class.strip_prefix("open-") {
+            Some(class) => (class, true),
+            None => (class.strip_prefix("filled-").unwrap_or(class), false),
+        };
+        let shape = Shape::from_keyword(class)?;
+        Some(Mark { shape, open })
+    }
+
+    /// Recognizes ruby that marks 傍点 instead of a reading, like ・・・ or ﹅﹅
+    pub fn from_rt(rt: &str) -> Option<Self> {
+        let mut chars = rt.trim().chars();
+        let first = chars.next()?;
+        if chars.any(|ch| ch != first) {
+            return None;
+        }
+        let (shape, open) = match first {
+            '﹅' => (Shape::Sesame, false),
+            '﹆' => (Shape::Sesame, true),
+            '・' | '･' | '•' => (Shape::Dot, false),
+            '◦' => (Shape::Dot, true),
+            '●' => (Shape::Circle, false),
+            '○' => (Shape::Circle, true),
+            '◉' => (Shape::DoubleCircle, false),
+            '◎' => (Shape::DoubleCircle, true),
+            '▲' => (Shape::Triangle, false),
+            '△' => (Shape::Triangle, true),
+            _ => return None,
+        };
+        Some(Mark { shape, open })
+    }
+}
+
+impl Display for Mark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fill = if self.open { "open" } else { "filled" };
+        match &self.shape {
+            Shape::Sesame => write!(f, "{fill} sesame"),
+            Shape::Dot => write!(f, "{fill} dot"),
+            Shape::Circle => write!(f, "{fill} circle"),
+            Shape::DoubleCircle => write!(f, "{fill} double-circle"),
+            Shape::Triangle => write!(f, "{fill} triangle"),
+            Shape::Custom(custom) => write!(f, "\"{custom}\""),
+        }
+    }
+}
+
+pub fn write_emphasis(emphasis: &[Emphasis], mut file: impl Write, txt: &str) {
+    for Emphasis {
+        span: Range { start, end },
+        mark,
+    } in emphasis
+    {
+        let text = &txt[*start..*end];
+        writeln!(file, "{start}:{end}:{text}:{mark}").or_(死!());
+    }
+}
+
+#[test]
+fn test_marks() {
+    for (css, expected) in [
+        ("filled sesame", "filled sesame"),
+        ("sesame", "filled sesame"),
+        ("open", "open sesame"),
+        ("dot", "filled dot"),
+        ("open circle", "open circle"),
+        ("triangle filled", "filled triangle"),
+        ("\"﹅\"", "\"﹅\""),
+    ] {
+        assert_eq!(Mark::from_css(css).to_string(), expected);
+    }
+    assert_eq!(
+        Mark::from_class("em-sesame").unwrap().to_string(),
+        "filled sesame"
+    );
+    assert_eq!(
+        Mark::from_class("bouten").unwrap().to_string(),
+        "filled sesame"
+    );
+    assert_eq!(
+        Mark::from_class("em-open-circle").unwrap().to_string(),
+        "open circle"
+    );
+    assert_eq!(
+        Mark::from_class("bouten-circle").unwrap().to_string(),
+        "filled circle"
+    );
+    assert_eq!(Mark::from_class("calibre3"), None);
+    assert_eq!(Mark::from_class("dot"), None);
+    assert_eq!(Mark::from_class("circle"), None);
+    assert_eq!(Mark::from_rt("・・・").unwrap().to_string(), "filled dot");
+    assert_eq!(Mark::from_rt("﹅﹅").unwrap().to_string(), "filled sesame");
+    assert_eq!(Mark::from_rt("・か"), None);
+    assert_eq!(Mark::from_rt(""), None);
+}
@@ -1,23 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::ops::Not;
 
+pub use ast::{Glyph, Inline, RubyAnnotation, is_private_use, walk};
 pub use meta::Meta;
+pub use notes::{NoteRef, Notes, write_notes};
+pub use xhtml::{take_repairs, write_repairs};
+
+use css::Stylesheet;
+use xhtml::dom::Dom;
 
 use crate::chapters::Chapter;
+use crate::dialogue::SpeechSpan;
+use crate::emphasis::Emphasis;
 use crate::error::OrDie;
+use crate::font::{Font, Obfuscation};
+use crate::layout::Layout;
 use crate::yomi::Yomi;
 use crate::{PHASE, 即死, 死};
 
+mod ast;
+mod css;
 mod doc;
 mod meta;
+mod notes;
 mod xhtml;
 mod zip;
 
+/// A gaiji of the book, by its key in gaiji.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GaijiRef<'a> {
+    pub key: String,
+    pub glyph: Glyph<'a>,
+    pub path: Option<String>, // of the image in the archive
+    pub alt: Option<String>,  // what the alt or title text says it is
+}
+
 pub struct Epub {
+    archive: File,
+    files: HashMap<String, zip::DeflatedFile>, // the other files than the body, e.g. images
+    obfuscated: HashMap<String, Obfuscation>,  // the embedded fonts, by encryption.xml
     pub content: String,
     pub body: Vec<(String, String)>,
+    pub css: Vec<Stylesheet>, // the styles that apply to each file in body
     pub href_to_spine_idx: HashMap<String, usize>,
     pub toc: Vec<(String, String)>,
+    pub notes: Notes,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -27,23 +55,79 @@ pub enum PType {
     StandaloneImage,
     Empty,
     Transparent,
+    Hidden,     // display: none etc.
+    Note,       // footnotes, endnotes etc.; they are output separately
+    SceneBreak, // <hr>, ◇◇◇ lines, spacer images, or runs of blank paragraphs
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Paragraph<'src> {
     pub text: &'src str,
     pub kind: PType,
+    pub content: Vec<Inline<'src>>, // parsed from the text for body text and headers
+    pub layout: Layout,
+    pub href: &'src str, // the file the paragraph is in
+    pub css: &'src Stylesheet,
+    pub notes: &'src Notes,
+}
+
+/// The things collected from the running text besides the text itself;
+/// the spans and offsets point to the text.
+#[derive(Debug, Default)]
+pub struct Annotations<'src> {
+    pub yomi: Vec<Yomi<'src>>,
+    pub ruby_tiers: Vec<Vec<Yomi<'src>>>, // the further ruby tiers, e.g. glosses under the text
+    pub emphasis: Vec<Emphasis>,
+    pub notes: Vec<NoteRef<'src>>,
+    pub speech: Vec<SpeechSpan>, // of the body text paragraphs
 }
 
 impl<'src> Paragraph<'src> {
-    pub fn with_fmt_stripped<'b>(
-        &self,
-        gaiji: &mut HashMap<String, char>,
-        yomi: &mut Vec<Yomi<'src>>,
-        buf: &'b mut String,
-    ) -> &'b str {
-        doc::with_fmt_stripped(gaiji, yomi, buf, self.text)
+    #[cfg(test)]
+    pub fn body_text(
+        text: &'src str,
+        href: &'src str,
+        css: &'src Stylesheet,
+        notes: &'src Notes,
+    ) -> Self {
+        Paragraph {
+            text,
+            kind: PType::BodyText,
+            content: doc::parse_inline(&xhtml::Tag::root(text), href, css, notes),
+            layout: Layout::default(),
+            href,
+            css,
+            notes,
+        }
+    }
+}
+
+/// Resolves a href relative to the file it appears in into a path in the archive
+pub fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split_once('#').map(|(file, _)| file).unwrap_or(href);
+    let mut path: Vec<_> = base.split('/').collect();
+    path.pop(); // the file name of the base
+    for part in href.split('/') {
+        match part {
+            "." => (),
+            ".." => {
+                path.pop();
+            }
+            part => path.push(part),
+        }
     }
+    path.join("/")
+}
+
+#[test]
+fn test_resolve_href() {
+    assert_eq!(resolve_href("text/ch1.xhtml", "../style.css"), "style.css");
+    assert_eq!(
+        resolve_href("text/ch1.xhtml", "ch2.xhtml#n3"),
+        "text/ch2.xhtml"
+    );
+    assert_eq!(resolve_href("ch1.xhtml", "./images/a.png"), "images/a.png");
+    assert_eq!(resolve_href("a/b/c.xhtml", "../../d.css"), "d.css");
 }
 
 impl Epub {
@@ -68,42 +152,160 @@ impl Epub {
 
         let toc = toc.extract_string(file);
         let content = content.extract_string(file);
+        let opf = Dom::parse("content.opf", &content);
 
         // manifest is a id->href map of the EPUB file contents (including images, style sheets, metadata etc.)
-        let manifest = doc::get_manifest(&content);
+        let manifest = doc::get_manifest(&opf);
 
         // spine is a list of ids that are in the reading order
-        let spine = doc::get_spine(&content);
+        let spine = doc::get_spine(&opf);
 
         // toc is a list of (chapter title, href) tuples, defining the starting point of each chapter
-        let toc = doc::get_toc(&toc);
+        let toc = doc::get_toc(&Dom::parse("toc.ncx", &toc));
 
         // hrefs is a manifest href -> spine idx map
         let mut href_to_spine_idx = HashMap::new();
 
+        // stylesheets is a path -> parsed stylesheet map of all the CSS files
+        let stylesheets: HashMap<_, _> = files
+            .iter()
+            .filter(|(name, _)| name.ends_with(".css"))
+            .map(|(name, css_file)| {
+                let css = Stylesheet::parse(&css_file.extract_string(file));
+                (name.as_str(), css)
+            })
+            .collect();
+
         // texts is essentially the spine, but instead of ids, it has hrefs and xhtml file contents
         let mut body = Vec::new();
+        let mut css = Vec::new();
 
         for (idx, idref) in spine.iter().enumerate() {
             let href = manifest.get(idref).or_(死!("idref not found in manifest!"));
             let text_file = files.get(href).or_(死!("href not found in zipped files!"));
             let text_string = text_file.extract_string(file);
             href_to_spine_idx.insert(href.to_owned(), idx);
+            css.push(doc::get_stylesheet(href, &text_string, &stylesheets));
             body.push((href.to_owned(), text_string));
         }
 
+        // notes is the footnotes and endnotes that are referred to from the text
+        let notes = Notes::find(&body);
+
+        // obfuscated is the fonts whose first bytes are XORed with a key from the identifiers
+        let mut obfuscated = HashMap::new();
+        if let Some(encryption) = files.get("META-INF/encryption.xml") {
+            let encryption = encryption.extract_string(file);
+            let identifiers = doc::get_identifiers(&opf);
+            let identifiers: Vec<_> = identifiers.iter().map(|id| id.as_ref()).collect();
+            for (uri, algorithm) in
+                doc::get_encrypted(&Dom::parse("META-INF/encryption.xml", &encryption))
+            {
+                match Obfuscation::new(algorithm, &identifiers) {
+                    Some(obfuscation) => {
+                        obfuscated.insert(uri.to_owned(), obfuscation);
+                    }
+                    None => eprintln!("{uri} is encrypted with {algorithm}, which isn't supported"),
+                }
+            }
+        }
+
         Epub {
+            archive: file.try_clone().or_(死!()),
+            files,
+            obfuscated,
             content,
             body,
+            css,
             href_to_spine_idx,
             toc,
+            notes,
         }
     }
 
-    pub fn paragraph_iter(&self, chapter: &Chapter) -> impl Iterator<Item = Paragraph> {
+    pub fn paragraph_iter(&self, chapter: &Chapter) -> impl Iterator<Item = Paragraph<'_>> {
         self.body[chapter.idxs.clone()]
             .iter()
-            .flat_map(|(href, passage)| doc::parse_passage(href, passage))
+            .zip(&self.css[chapter.idxs.clone()])
+            .filter(|((href, _), _)| self.notes.is_note_doc(href).not())
+            .flat_map(|((href, passage), css)| doc::parse_passage(href, passage, css, &self.notes))
+    }
+
+    /// Extracts a file, e.g. an image, by its path in the archive
+    pub fn extract(&self, path: &str) -> Option<Vec<u8>> {
+        Some(self.files.get(path)?.extract_bytes(&self.archive))
+    }
+
+    /// The embedded fonts that can be parsed, de-obfuscated, in the order of their paths
+    pub fn fonts(&self) -> Vec<(String, Font)> {
+        let mut paths: Vec<_> = self
+            .files
+            .keys()
+            .filter(|path| {
+                let path = path.to_lowercase();
+                [".ttf", ".otf", ".ttc", ".woff"]
+                    .iter()
+                    .any(|ext| path.ends_with(ext))
+            })
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let mut bytes = self.extract(path)?;
+                if let Some(obfuscation) = self.obfuscated.get(path) {
+                    obfuscation.apply(&mut bytes);
+                }
+                match Font::parse(&bytes) {
+                    Some(font) => Some((path.clone(), font)),
+                    None => {
+                        eprintln!("Font {path:?} couldn't be parsed");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// The gaiji of the book, one per key, in the order of the keys
+    pub fn gaiji(&self) -> Vec<GaijiRef<'_>> {
+        let mut gaiji: BTreeMap<String, GaijiRef> = BTreeMap::new();
+        for ((href, passage), css) in self.body.iter().zip(&self.css) {
+            for paragraph in doc::parse_passage(href, passage, css, &self.notes) {
+                walk(&paragraph.content, &mut |inline| {
+                    let Inline::Gaiji { glyph, alt } = inline else {
+                        return;
+                    };
+                    let key = glyph.key().into_owned();
+                    let found = gaiji.entry(key.clone()).or_insert_with(|| GaijiRef {
+                        key,
+                        glyph: glyph.clone(),
+                        path: match glyph {
+                            Glyph::Image(src) => Some(resolve_href(href, src)),
+                            Glyph::Svg(_) | Glyph::Char(_) => None,
+                        },
+                        alt: None,
+                    });
+                    if found.alt.is_none() {
+                        found.alt = alt.map(|alt| xhtml::de_entitify(alt).into_owned());
+                    }
+                });
+            }
+        }
+        gaiji.into_values().collect()
+    }
+
+    /// The text around the places where each gaiji occurs, by its key
+    pub fn gaiji_contexts(&self, width: usize) -> HashMap<String, Vec<(String, String)>> {
+        let mut contexts: HashMap<_, Vec<_>> = HashMap::new();
+        for ((href, passage), css) in self.body.iter().zip(&self.css) {
+            for paragraph in doc::parse_passage(href, passage, css, &self.notes) {
+                for (key, before, after) in ast::gaiji_contexts(&paragraph.content, width) {
+                    contexts.entry(key).or_default().push((before, after));
+                }
+            }
+        }
+        contexts
     }
 
     pub fn get_meta(&self) -> Meta {
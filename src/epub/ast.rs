@@ -0,0 +1,242 @@
+use std::{borrow::Cow, ops::Not};
+
+use crate::{emphasis::Mark, gaiji};
+
+use super::xhtml::Tag;
+
+/// The inline content of a paragraph, parsed once from the XHTML.
+/// The output formats are rendered from this.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inline<'src> {
+    Text(&'src str),
+    Ruby(Vec<RubyGroup<'src>>),
+    Break,
+    /// A character that isn't in Unicode, or at least not in the fonts of the publisher.
+    /// Inline images in the running text are gaiji too, marked as such or not.
+    Gaiji {
+        glyph: Glyph<'src>,
+        alt: Option<&'src str>, // the alt or title text, which might name the character
+    },
+    Emphasis {
+        mark: Mark,
+        children: Vec<Inline<'src>>,
+    },
+    /// A reference to a note; the marker has been removed from the text
+    NoteRef {
+        marker: String,
+        note: &'src str,
+    },
+    Link {
+        href: &'src str,
+        children: Vec<Inline<'src>>,
+    },
+    /// A table cell; a row is flattened to a line of cells
+    Cell(Vec<Inline<'src>>),
+    /// Other phrasing content, handled according to the inline policies
+    Element {
+        tag: Tag<'src>,
+        children: Vec<Inline<'src>>,
+    },
+}
+
+/// Where the glyph of a gaiji comes from
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Glyph<'src> {
+    Image(&'src str), // the src of an img, or the href of an image in an svg
+    Svg(&'src str),   // an inline svg that draws the glyph
+    Char(char),       // a Private Use Area character in an embedded font
+}
+
+/// A run of ruby base text, and what's above (or below) it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RubyGroup<'src> {
+    pub base: Vec<Inline<'src>>,
+    /// One per annotation tier: the `rt`s directly in the ruby, then each `rtc`
+    pub annotations: Vec<RubyAnnotation<'src>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RubyAnnotation<'src> {
+    None,
+    Reading(&'src str),
+    Gloss(&'src str), // ruby in another language by its lang, like 業<rt lang="en">karma</rt>
+    Emphasis(Mark),   // 傍点 disguised as ruby, e.g. ・・・
+}
+
+impl<'src> Glyph<'src> {
+    /// The key of the gaiji in gaiji.txt, without a `:` as that ends the key there
+    pub fn key(&self) -> Cow<'src, str> {
+        match *self {
+            Glyph::Image(src) => Cow::Borrowed(src),
+            Glyph::Svg(svg) => {
+                Cow::Owned(format!("svg-{:016x}", gaiji::content_hash(svg.as_bytes())))
+            }
+            Glyph::Char(ch) => Cow::Owned(format!("U+{:04X}", ch as u32)),
+        }
+    }
+}
+
+/// The Private Use Areas of Unicode, which publishers fill with gaiji in their own fonts
+pub fn is_private_use(ch: char) -> bool {
+    matches!(ch, '\u{E000}'..='\u{F8FF}' | '\u{F0000}'..='\u{FFFFD}' | '\u{100000}'..='\u{10FFFD}')
+}
+
+/// The next Private Use Area character in text, either literal or a numeric character
+/// reference like `&#xE001;`, with its position and length in the text
+fn next_private_use(text: &str) -> Option<(usize, char, usize)> {
+    text.char_indices().find_map(|(idx, ch)| {
+        if is_private_use(ch) {
+            return Some((idx, ch, ch.len_utf8()));
+        }
+        let (number, _) = text[idx..].strip_prefix("&#")?.split_once(';')?;
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        let ch = char::from_u32(code).filter(|&ch| is_private_use(ch))?;
+        Some((idx, ch, "&#;".len() + number.len()))
+    })
+}
+
+/// Text, with the Private Use Area characters in it as gaiji
+pub fn text_with_gaiji(text: &str) -> Vec<Inline<'_>> {
+    let mut content = Vec::new();
+    let mut rest = text;
+    while let Some((idx, ch, len)) = next_private_use(rest) {
+        if idx > 0 {
+            content.push(Inline::Text(&rest[..idx]));
+        }
+        content.push(Inline::Gaiji {
+            glyph: Glyph::Char(ch),
+            alt: None,
+        });
+        rest = &rest[idx + len..];
+    }
+    if rest.is_empty().not() {
+        content.push(Inline::Text(rest));
+    }
+    content
+}
+
+/// Visits the inline content and everything nested in it, in document order
+pub fn walk<'a, 'src>(content: &'a [Inline<'src>], f: &mut impl FnMut(&'a Inline<'src>)) {
+    for inline in content {
+        f(inline);
+        match inline {
+            Inline::Ruby(groups) => {
+                for group in groups {
+                    walk(&group.base, f);
+                }
+            }
+            Inline::Emphasis { children, .. }
+            | Inline::Link { children, .. }
+            | Inline::Cell(children)
+            | Inline::Element { children, .. } => walk(children, f),
+            Inline::Text(_) | Inline::Break | Inline::Gaiji { .. } | Inline::NoteRef { .. } => (),
+        }
+    }
+}
+
+/// The text of the content without the annotations, e.g. for recognizing symbol lines
+pub fn plain_text(content: &[Inline]) -> String {
+    let mut text = String::new();
+    push_plain_text(content, &mut text, &mut Vec::new());
+    text
+}
+
+/// The text around each gaiji in the content, by its key, up to `width` characters on each side
+pub fn gaiji_contexts(content: &[Inline], width: usize) -> Vec<(String, String, String)> {
+    let mut text = String::new();
+    let mut gaiji = Vec::new();
+    push_plain_text(content, &mut text, &mut gaiji);
+    let context = |chars: &mut dyn Iterator<Item = char>| -> String {
+        chars
+            .take(width)
+            .map(|ch| if ch == '\n' { ' ' } else { ch })
+            .collect()
+    };
+    gaiji
+        .into_iter()
+        .map(|(key, offset)| {
+            let before: String = context(&mut text[..offset].chars().rev());
+            let after = context(&mut text[offset + '〓'.len_utf8()..].chars());
+            (key, before.chars().rev().collect(), after)
+        })
+        .collect()
+}
+
+/// Gaiji are 〓 in the text; their keys and offsets are collected
+fn push_plain_text(content: &[Inline], text: &mut String, gaiji: &mut Vec<(String, usize)>) {
+    for inline in content {
+        match inline {
+            Inline::Text(t) => text.push_str(t),
+            Inline::Break => text.push('\n'),
+            Inline::Ruby(groups) => {
+                for group in groups {
+                    push_plain_text(&group.base, text, gaiji);
+                }
+            }
+            Inline::Emphasis { children, .. }
+            | Inline::Link { children, .. }
+            | Inline::Cell(children)
+            | Inline::Element { children, .. } => push_plain_text(children, text, gaiji),
+            Inline::Gaiji { glyph, .. } => {
+                gaiji.push((glyph.key().into_owned(), text.len()));
+                text.push('〓');
+            }
+            Inline::NoteRef { .. } => (),
+        }
+    }
+}
+
+#[test]
+fn test_gaiji_contexts() {
+    let content = [
+        Inline::Text("吾輩は猫で"),
+        Inline::Emphasis {
+            mark: crate::emphasis::Mark::from_css("sesame"),
+            children: vec![Inline::Gaiji {
+                glyph: Glyph::Image("g1.png"),
+                alt: None,
+            }],
+        },
+        Inline::Text("る。"),
+        Inline::Break,
+        Inline::Gaiji {
+            glyph: Glyph::Char('\u{E001}'),
+            alt: None,
+        },
+    ];
+    let contexts = gaiji_contexts(&content, 3);
+    assert_eq!(
+        contexts,
+        [
+            ("g1.png".into(), "は猫で".into(), "る。 ".into()),
+            ("U+E001".into(), "る。 ".into(), String::new()),
+        ]
+    );
+    assert_eq!(
+        text_with_gaiji("\u{E001}の&#xF8FF;"),
+        [
+            Inline::Gaiji {
+                glyph: Glyph::Char('\u{E001}'),
+                alt: None
+            },
+            Inline::Text("の"),
+            Inline::Gaiji {
+                glyph: Glyph::Char('\u{F8FF}'),
+                alt: None
+            },
+        ]
+    );
+    assert_eq!(
+        text_with_gaiji("&#57345;&#x4E00;&amp;"),
+        [
+            Inline::Gaiji {
+                glyph: Glyph::Char('\u{E001}'),
+                alt: None
+            },
+            Inline::Text("&#x4E00;&amp;"),
+        ]
+    );
+}
@@ -0,0 +1,394 @@
+use std::ops::Not;
+
+use super::xhtml::Tag;
+
+/// Font size relative to the body text, from which on a paragraph is considered a header
+const HEADER_FONT_SCALE: f32 = 1.3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    element: Option<String>, // None matches any element
+    classes: Vec<String>,
+    props: Vec<(String, String)>,
+}
+
+/// The rules of a stylesheet that select by element and/or class names.
+/// Other selectors (ids, pseudo-classes, combinators) are approximated by
+/// their last compound, which is good enough for the formatting in EPUBs;
+/// rules for pseudo-elements, states like :hover and other media are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stylesheet {
+    rules: Vec<Rule>,
+}
+
+/// The computed properties of a tag; the later ones take precedence
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Style {
+    props: Vec<(String, String)>,
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Finds the end of a {} block that starts after `start`, minding nesting
+fn block_end(css: &str, start: usize) -> usize {
+    let mut depth = 1;
+    for (idx, ch) in css[start..].char_indices() {
+        match ch {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => continue,
+        }
+        if depth == 0 {
+            return start + idx;
+        }
+    }
+    css.len()
+}
+
+pub fn parse_declarations(decls: &str) -> Vec<(String, String)> {
+    let mut props = Vec::new();
+    for decl in decls.split(';') {
+        let Some((name, value)) = decl.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = value.strip_suffix("!important").unwrap_or(value).trim();
+        props.push((name.trim().to_ascii_lowercase(), value.to_owned()));
+    }
+    props
+}
+
+/// A length in em, i.e. in characters; other units can't be converted to them
+fn parse_em(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if value == "0" {
+        return Some(0.0);
+    }
+    let em = value.strip_suffix("rem").or(value.strip_suffix("em"))?;
+    em.trim().parse().ok()
+}
+
+/// The pseudo-elements with a single colon from CSS2; the others have two
+const LEGACY_PSEUDO_ELEMENTS: [&str; 4] = ["before", "after", "first-letter", "first-line"];
+
+/// Pseudo-classes of states that a book at rest is never in
+const STATE_PSEUDO_CLASSES: [&str; 10] = [
+    "hover",
+    "active",
+    "focus",
+    "focus-within",
+    "focus-visible",
+    "visited",
+    "target",
+    "checked",
+    "enabled",
+    "disabled",
+];
+
+/// Parses the last compound of a selector into an element name and classes. Structural
+/// pseudo-classes like :first-child are ignored, but `None` if the compound has a pseudo-element
+/// like ::before or a state like :hover, as the rule doesn't style the element as it is.
+fn parse_compound(selector: &str) -> Option<(Option<String>, Vec<String>)> {
+    let last = selector
+        .rsplit([' ', '\t', '\n', '\r', '>', '+', '~'])
+        .next()
+        .unwrap_or("");
+    let mut pseudos = last.split(':');
+    let last = pseudos.next().unwrap_or("");
+    for pseudo in pseudos {
+        let name = pseudo.split('(').next().unwrap_or("").to_ascii_lowercase();
+        // an empty name is the first colon of ::
+        if name.is_empty()
+            || LEGACY_PSEUDO_ELEMENTS.contains(&name.as_str())
+            || STATE_PSEUDO_CLASSES.contains(&name.as_str())
+        {
+            return None;
+        }
+    }
+    let mut parts = last.split(['.', '#', '[']);
+    let element = parts
+        .next()
+        .filter(|el| el.is_empty().not() && *el != "*")
+        .map(str::to_ascii_lowercase);
+    let classes = last
+        .split('.')
+        .skip(1)
+        .map(|class| class.split(['#', '[']).next().unwrap_or(""))
+        .map(ToOwned::to_owned)
+        .collect();
+    Some((element, classes))
+}
+
+/// Whether the rules of an `@media` block apply to the book as read on a screen. Amazon's
+/// amzn-kf8 and amzn-mobi, and other media types, hide alternatives for other readers.
+fn applies_to_screen(query: &str) -> bool {
+    query.split(',').any(|query| {
+        let query = query.trim().to_ascii_lowercase();
+        let query = query.strip_prefix("only ").unwrap_or(&query).trim_start();
+        let media = query.split_ascii_whitespace().next().unwrap_or("");
+        // media features only, like (min-width: 30em), apply to all media
+        media.is_empty() || media.starts_with('(') || media == "all" || media == "screen"
+    })
+}
+
+impl Stylesheet {
+    pub fn parse(css: &str) -> Self {
+        let mut sheet = Stylesheet::default();
+        sheet.parse_rules(&strip_comments(css));
+        sheet
+    }
+
+    fn parse_rules(&mut self, css: &str) {
+        let mut pos = 0;
+        while let Some(open) = css[pos..].find('{').map(|idx| pos + idx) {
+            let close = block_end(css, open + 1);
+            let prelude = css[pos..open].trim();
+            // statements like @charset and @import end with a semicolon
+            let prelude = prelude.rsplit(';').next().unwrap_or("").trim();
+            let block = &css[open + 1..close];
+            if let Some(query) = prelude.strip_prefix("@media") {
+                if applies_to_screen(query) {
+                    self.parse_rules(block);
+                }
+            } else if prelude.starts_with("@supports") {
+                self.parse_rules(block);
+            } else if prelude.starts_with('@').not() {
+                let props = parse_declarations(block);
+                for selector in prelude.split(',') {
+                    let Some((element, classes)) = parse_compound(selector.trim()) else {
+                        continue;
+                    };
+                    if element.is_none() && classes.is_empty() {
+                        continue; // ids, attribute selectors etc. only
+                    }
+                    self.rules.push(Rule {
+                        element,
+                        classes,
+                        props: props.clone(),
+                    });
+                }
+            }
+            pos = (close + 1).min(css.len());
+        }
+    }
+
+    /// Adds the rules of another stylesheet, as if it was linked after this one
+    pub fn extend(&mut self, other: &Stylesheet) {
+        self.rules.extend(other.rules.iter().cloned());
+    }
+
+    /// Computes the style of a tag from the matching rules and its inline style attribute
+    pub fn style_of(&self, tag: &Tag) -> Style {
+        let mut props = Vec::new();
+        for rule in &self.rules {
+            let element_matches = rule
+                .element
+                .as_deref()
+                .is_none_or(|el| el == tag.local_name());
+            if element_matches && rule.classes.iter().all(|class| tag.has_class(class)) {
+                props.extend(rule.props.iter().cloned());
+            }
+        }
+        if let Some(inline) = tag.style() {
+            props.extend(parse_declarations(inline));
+        }
+        Style { props }
+    }
+}
+
+impl Style {
+    pub fn get(&self, prop: &str) -> Option<&str> {
+        self.props
+            .iter()
+            .rev()
+            .find(|(name, _)| name == prop)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Gets a property that might be prefixed with a vendor prefix
+    fn get_prefixed(&self, prop: &str) -> Option<&str> {
+        let prefixed = self.props.iter().rev().find(|(name, _)| {
+            let name = name
+                .strip_prefix("-webkit-")
+                .or_else(|| name.strip_prefix("-epub-"))
+                .unwrap_or(name);
+            name == prop
+        });
+        prefixed.map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.get("display") == Some("none") || self.get("visibility") == Some("hidden")
+    }
+
+    /// Font size relative to the parent
+    pub fn font_scale(&self) -> Option<f32> {
+        let size = self.get("font-size")?;
+        let scale = match size {
+            "xx-small" => 0.6,
+            "x-small" => 0.75,
+            "small" | "smaller" => 0.89,
+            "medium" => 1.0,
+            "large" | "larger" => 1.2,
+            "x-large" => 1.5,
+            "xx-large" => 2.0,
+            _ => {
+                if let Some(percent) = size.strip_suffix('%') {
+                    percent.trim().parse::<f32>().ok()? / 100.0
+                } else if let Some(em) = size.strip_suffix("rem").or(size.strip_suffix("em")) {
+                    em.trim().parse().ok()?
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(scale)
+    }
+
+    pub fn is_large(&self) -> bool {
+        self.font_scale()
+            .is_some_and(|scale| scale >= HEADER_FONT_SCALE)
+    }
+
+    /// A length property in em
+    pub fn get_em(&self, prop: &str) -> Option<f32> {
+        parse_em(self.get(prop)?)
+    }
+
+    /// The margin or padding on a side (top, right, bottom, left), from the physical or
+    /// logical longhand or the shorthand, whichever comes last
+    fn box_side(&self, prop: &str, side: usize, logical: &str) -> Option<f32> {
+        let physical = format!("{prop}-{}", ["top", "right", "bottom", "left"][side]);
+        let logical = format!("{prop}-{logical}");
+        let (name, value) = self
+            .props
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == physical || *name == logical || name == prop)?;
+        if name != prop {
+            return parse_em(value);
+        }
+        let values: Vec<_> = value.split_ascii_whitespace().collect();
+        let idx = match (values.len(), side) {
+            (1, _) => 0,
+            (2, side) => side % 2,
+            (3, 3) => 1,
+            (3 | 4, side) => side,
+            _ => return None,
+        };
+        parse_em(values[idx])
+    }
+
+    /// The margins plus paddings at the start and the end of the lines, in em
+    pub fn inline_margins(&self, vertical: bool) -> (Option<f32>, Option<f32>) {
+        let (start, end) = if vertical { (0, 2) } else { (3, 1) };
+        let sum = |side, logical| {
+            let margin = self.box_side("margin", side, logical);
+            let padding = self.box_side("padding", side, logical);
+            margin.or(padding)?;
+            Some(margin.unwrap_or(0.0) + padding.unwrap_or(0.0))
+        };
+        (sum(start, "inline-start"), sum(end, "inline-end"))
+    }
+
+    pub fn is_vertical(&self) -> Option<bool> {
+        self.get_prefixed("writing-mode")
+            .map(|mode| mode.starts_with("vertical"))
+    }
+
+    /// The text-emphasis style, e.g. `filled sesame`, unless there's none
+    pub fn emphasis(&self) -> Option<&str> {
+        self.get_prefixed("text-emphasis-style")
+            .or_else(|| self.get_prefixed("text-emphasis"))
+            .filter(|style| *style != "none")
+    }
+}
+
+#[test]
+fn test_parse_stylesheet() {
+    let css = r#"@charset "utf-8";
+        /* comment { } */
+        .calibre3 { display: block; font-size: 1em }
+        p.big, .huge > span.title { font-size: 150% !important; }
+        .spoiler { display:none }
+        em.sesame, .bouten { -webkit-text-emphasis-style: filled sesame; }
+        @font-face { font-family: "gaiji"; src: url(../fonts/gaiji.otf); }
+        @media amzn-kf8 { .mobi { display: none } }
+        @media amzn-mobi { .kf8 { display: none } }
+        @media print { .screen-only { display: none } }
+        @media only screen and (min-width: 30em), print { .wide { margin: 0 } }
+        @media (orientation: portrait) { .portrait { margin: 0 } }
+        html { -epub-writing-mode: vertical-rl; }
+        a:hover, #id1, p::first-letter, .note::before, .note:after { font-size: 200% }
+        li:first-child, a:not(.x) { color: red }"#;
+    let sheet = Stylesheet::parse(css);
+    assert_eq!(
+        sheet
+            .rules
+            .iter()
+            .map(|rule| (rule.element.as_deref(), rule.classes.join(".")))
+            .collect::<Vec<_>>(),
+        [
+            (None, "calibre3".to_owned()),
+            (Some("p"), "big".to_owned()),
+            (Some("span"), "title".to_owned()),
+            (None, "spoiler".to_owned()),
+            (Some("em"), "sesame".to_owned()),
+            (None, "bouten".to_owned()),
+            (None, "wide".to_owned()),
+            (None, "portrait".to_owned()),
+            (Some("html"), "".to_owned()),
+            (Some("li"), "".to_owned()),
+            (Some("a"), "".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn test_style_of() {
+    let css = r#".calibre3 { font-size: 1em } p.big { font-size: 150% } .spoiler { display:none }
+        .bouten { text-emphasis: filled sesame }"#;
+    let sheet = Stylesheet::parse(css);
+    let source =
+        r#"<p class="calibre3 big"><span class="spoiler bouten"><span style="font-size: x-large">"#;
+    let mut iter = Tag::root(source).iter();
+    let p = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert_eq!(p.font_scale(), Some(1.5));
+    assert!(p.is_large());
+    assert!(p.is_hidden().not());
+    let span = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert!(span.is_hidden());
+    assert_eq!(span.emphasis(), Some("filled sesame"));
+    assert_eq!(span.font_scale(), None);
+    let inline = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert!(inline.is_large());
+}
+
+#[test]
+fn test_inline_margins() {
+    let sheet = Stylesheet::parse(
+        ".jisage_2 { margin-top: 2em } .letter { margin: 0 1em 0 3em; padding-left: 0.5em }",
+    );
+    let source = r#"<p class="jisage_2"><p class="letter"><p style="margin-inline-start: 1rem">"#;
+    let mut iter = Tag::root(source).iter();
+    let jisage = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert_eq!(jisage.inline_margins(true), (Some(2.0), None));
+    assert_eq!(jisage.inline_margins(false), (None, None));
+    let letter = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert_eq!(letter.inline_margins(false), (Some(3.5), Some(1.0)));
+    assert_eq!(letter.inline_margins(true), (Some(0.0), Some(0.0)));
+    let logical = sheet.style_of(&iter.next_by_el(&[]).unwrap());
+    assert_eq!(logical.inline_margins(true), (Some(1.0), None));
+}
@@ -3,54 +3,62 @@ use std::collections::HashMap;
 use std::ops::Not;
 
 use crate::PHASE;
+use crate::emphasis::Mark;
 use crate::epub::PType;
 use crate::epub::xhtml::Tag;
 use crate::error::{OrDie, 即死, 死};
-use crate::yomi::Yomi;
+use crate::gaiji;
+use crate::layout::{Align, Layout};
 
-use super::Paragraph;
+use super::ast::{Glyph, Inline, RubyAnnotation, RubyGroup, plain_text, text_with_gaiji};
+use super::css::{Style, Stylesheet};
+use super::xhtml::dom::{Dom, Node};
 use super::xhtml::iter::TagIter;
-use super::xhtml::{TType, de_entitify};
+use super::xhtml::{TType, de_entitify, ns};
+use super::{Notes, Paragraph, resolve_href};
 
-pub fn get_manifest(source: &str) -> HashMap<String, String> {
+pub fn get_manifest(opf: &Dom) -> HashMap<String, String> {
     let mut id_map = HashMap::new();
-    let mut manifest = Tag::get_first(source, "manifest")
-        .or_(死!("unschematic"))
-        .iter();
-    while let Some(item) = manifest.next_by_tag(&["item"]) {
-        let id = item.get_attr("id").or_(死!("unschematic"));
-        let href = item.get_attr("href").or_(死!("unschematic"));
+    for item in opf.select("opf|manifest > opf|item") {
+        let id = item
+            .tag()
+            .id()
+            .or_(死!(at: item, "unschematic: item without id"));
+        let href = item
+            .get_attr("href")
+            .or_(死!(at: item, "unschematic: item without href"));
         id_map.insert(id.to_owned(), href.to_owned());
     }
     id_map
 }
 
-pub fn get_spine(source: &str) -> Vec<String> {
+pub fn get_spine(opf: &Dom) -> Vec<String> {
     let mut idrefs = Vec::new();
-    let mut spine = Tag::get_first(source, "spine")
-        .or_(死!("unschematic"))
-        .iter();
-    while let Some(item) = spine.next_by_tag(&["itemref"]) {
-        let idref = item.get_attr("idref").or_(死!("unschematic"));
+    for item in opf.select("opf|spine > opf|itemref") {
+        let idref = item
+            .get_attr("idref")
+            .or_(死!(at: item, "unschematic: itemref without idref"));
         idrefs.push(idref.to_owned());
     }
 
     idrefs
 }
 
-pub fn get_toc(source: &str) -> Vec<(String, String)> {
-    let mut navmap = Tag::get_first(source, "navMap")
-        .or_(死!("unschematic"))
-        .iter();
+pub fn get_toc(ncx: &Dom) -> Vec<(String, String)> {
     let mut chapters = Vec::new();
 
-    while let Some(navpoint) = navmap.next_by_el(&["navPoint"]) {
-        let label = navpoint.get_first_child("navLabel").or_(死!("unschematic"));
-        let text = label.get_first_child("text").or_(死!("unschematic"));
-        let (_, title) = text.get_end();
-        let content = navpoint.get_first_child("content").or_(死!("unschematic"));
+    for navpoint in ncx.select("ncx|navMap ncx|navPoint") {
+        let text = navpoint
+            .select_first("ncx|navLabel > ncx|text")
+            .or_(死!(at: navpoint, "unschematic: navPoint without label"));
+        let title = text.inner();
+        let content = navpoint
+            .select_first("ncx|content")
+            .or_(死!(at: navpoint, "unschematic: navPoint without content"));
 
-        let src = content.get_attr("src").or_(死!("unschematic"));
+        let src = content
+            .get_attr("src")
+            .or_(死!(at: content, "unschematic: content without src"));
         let src_file = src.split_once('#').map(|(file, _)| file).unwrap_or(src);
 
         chapters.push((title.to_owned(), src_file.to_owned()));
@@ -59,225 +67,988 @@ pub fn get_toc(source: &str) -> Vec<(String, String)> {
     chapters
 }
 
-fn parse_paragraph<'src>(tag: &Tag<'src>) -> Paragraph<'src> {
-    let (end_tag, inner) = tag.get_end();
-    let inner = inner.trim();
+/// Elements that are rendered as blocks of their own
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "aside",
+    "header",
+    "footer",
+    "nav",
+    "main",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "ul",
+    "ol",
+    "li",
+    "dl",
+    "dt",
+    "dd",
+    "table",
+    "caption",
+    "thead",
+    "tbody",
+    "tfoot",
+    "tr",
+    "figure",
+    "figcaption",
+    "hr",
+];
 
-    if ["div", "section"].contains(&tag.name) {
-        return Paragraph {
-            text: "",
-            kind: PType::Transparent,
+/// Elements that only group other blocks, with no text of their own
+const CONTAINER_ELEMENTS: &[&str] =
+    &["div", "section", "main", "ul", "ol", "dl", "table", "thead", "tbody", "tfoot"];
+
+/// Elements that contain either text, or other blocks
+const FLOW_ELEMENTS: &[&str] = &[
+    "blockquote",
+    "article",
+    "aside",
+    "header",
+    "footer",
+    "nav",
+    "figure",
+    "figcaption",
+    "li",
+    "dt",
+    "dd",
+];
+
+/// Elements that are a line of text of their own; table rows have their cells joined
+const LINE_ELEMENTS: &[&str] = &["p", "a", "span", "pre", "tr", "caption", "td", "th"];
+
+/// The symbols that make up scene break lines, like ◇◇◇ or ＊　＊　＊
+const SCENE_BREAK_CHARS: &str = "◇◆◈○●◎☆★□■△▲▽▼♢♦❖＊*✽✻✳✴※";
+
+/// The words in the class or file name of spacer images that separate scenes.
+/// Not `break`, which is as likely to be a page break.
+const SPACER_NAMES: &[&str] = &["spacer", "separator", "scene", "kugiri", "dinkus"];
+
+/// The number of blank paragraphs in a row that separate scenes
+const SCENE_BREAK_BLANKS: usize = 2;
+
+/// Inline images up to this size are gaiji even without a class or an alt text
+const GAIJI_MAX_EM: f32 = 2.0;
+const GAIJI_MAX_PX: u32 = 64;
+
+fn is_scene_break_text(text: &str) -> bool {
+    let symbols: Vec<_> = text.chars().filter(|ch| ch.is_whitespace().not()).collect();
+    (1..=12).contains(&symbols.len()) && symbols.iter().all(|&ch| SCENE_BREAK_CHARS.contains(ch))
+}
+
+fn is_spacer(node: Node) -> bool {
+    let Some(img) = [node]
+        .into_iter()
+        .chain(node.descendants())
+        .find(|n| ["img", "image"].contains(&n.tag().local_name()))
+    else {
+        return false;
+    };
+    let tag = img.tag();
+    let src = tag.get_attr("src").or(tag.get_attr(ns!(xlink: "href")));
+    let file = src.and_then(|src| src.rsplit('/').next()).unwrap_or("");
+    // whole words only, as in spacer_01.png but not scene01.jpg
+    let named = |name: &str| {
+        name.split(['_', '-', '.'])
+            .any(|word| SPACER_NAMES.contains(&word.to_ascii_lowercase().as_str()))
+    };
+    named(file) || tag.classes().any(named) || tag.get_attr("alt").is_some_and(is_scene_break_text)
+}
+
+fn parse_paragraph<'src>(
+    node: Node<'_, 'src>,
+    href: &'src str,
+    css: &'src Stylesheet,
+    notes: &'src Notes,
+    parent: &Layout,
+) -> Paragraph<'src> {
+    let tag = node.tag();
+    let style = css.style_of(tag);
+    let layout = layout(parent, tag, &style);
+    let paragraph = |text, kind| {
+        let content = match kind {
+            PType::BodyText | PType::Header => {
+                parse_inline(&tag.inner_root(node.end_tag()), href, css, notes)
+            }
+            _ => Vec::new(),
         };
+        Paragraph {
+            text,
+            kind,
+            content,
+            layout,
+            href,
+            css,
+            notes,
+        }
+    };
+    let name = node.name();
+    let end_tag = node.end_tag();
+    let inner = node.inner().trim();
+
+    if style.is_hidden() {
+        return paragraph(inner, PType::Hidden);
+    }
+
+    if notes.is_note_block(href, tag) {
+        return paragraph(inner, PType::Note);
+    }
+
+    let has_block_children = || {
+        node.children()
+            .any(|child| BLOCK_ELEMENTS.contains(&child.name()))
+    };
+    if CONTAINER_ELEMENTS.contains(&name) || FLOW_ELEMENTS.contains(&name) && has_block_children() {
+        return paragraph("", PType::Transparent);
+    }
+    if ["h1", "h2", "h3", "h4", "h5", "h6"].contains(&name) {
+        return paragraph(inner, PType::Header);
     }
-    if ["h1", "h2", "h3", "h4"].contains(&tag.name) {
+
+    if ["svg", "img"].contains(&name) {
+        if is_spacer(node) {
+            return paragraph(inner, PType::SceneBreak);
+        }
+        return paragraph(inner, PType::StandaloneImage);
+    }
+
+    if ["hr"].contains(&name) {
+        return paragraph(inner, PType::SceneBreak);
+    }
+
+    if LINE_ELEMENTS.contains(&name).not() && FLOW_ELEMENTS.contains(&name).not() {
+        即死!(at: node, "unknown formatting <{name}>");
+    }
+
+    // a line of a single gaiji is still text
+    if let Some(img) = node.select_first("img, svg")
+        && is_gaiji_node(img, css).not()
+        && tag.span_with(img.tag()).trim().is_empty()
+        && img.end_tag().span_with(end_tag).trim().is_empty()
+    {
+        if is_spacer(img) {
+            return paragraph(inner, PType::SceneBreak);
+        }
+        return paragraph(inner, PType::StandaloneImage);
+    }
+
+    if let Some(br) = node.select_first("br")
+        && tag.span_with(br.tag()).trim().is_empty()
+        && br.tag().span_with(end_tag).trim().is_empty()
+    {
+        return paragraph(inner, PType::Empty);
+    }
+
+    let body_text = paragraph(inner, PType::BodyText);
+    let text = plain_text(&body_text.content);
+    if is_scene_break_text(&text) {
         return Paragraph {
-            text: inner,
-            kind: PType::Header,
+            kind: PType::SceneBreak,
+            ..body_text
         };
     }
-
-    if ["svg", "img"].contains(&tag.name) {
+    let has_images = || node.select_first("img, svg").is_some();
+    if text.trim().is_empty() && has_images().not() {
         return Paragraph {
-            text: inner,
-            kind: PType::StandaloneImage,
+            kind: PType::Empty,
+            ..body_text
         };
     }
 
-    if ["hr"].contains(&tag.name) {
+    // pseudo-headers, either the paragraph itself or a span that covers all of it
+    let only_child = node.children().next().filter(|child| {
+        tag.span_with(child.tag()).trim().is_empty()
+            && child.end_tag().span_with(end_tag).trim().is_empty()
+    });
+    if style.is_large() || only_child.is_some_and(|child| css.style_of(child.tag()).is_large()) {
         return Paragraph {
-            text: inner,
-            kind: PType::Empty,
+            kind: PType::Header,
+            ..body_text
         };
     }
 
-    if ["p", "a", "span"].contains(&tag.name).not() {
-        即死!("unknown formatting");
+    body_text
+}
+
+/// Collects the paragraphs, descending into the transparent containers
+fn parse_paragraphs<'src>(
+    node: Node<'_, 'src>,
+    href: &'src str,
+    css: &'src Stylesheet,
+    notes: &'src Notes,
+    parent: &Layout,
+    paragraphs: &mut Vec<Paragraph<'src>>,
+) {
+    for child in node.children() {
+        let parsed = parse_paragraph(child, href, css, notes, parent);
+        if parsed.kind == PType::Transparent {
+            parse_paragraphs(child, href, css, notes, &parsed.layout, paragraphs);
+        } else {
+            paragraphs.push(parsed);
+        }
     }
+}
 
-    if let Some(img) = tag.iter().next_by_el(&["img", "svg"]) {
-        if tag.span_with(&img).trim().is_empty() && img.span_with(&end_tag).trim().is_empty() {
-            return Paragraph {
-                text: inner,
-                kind: PType::StandaloneImage,
-            };
+/// Turns runs of blank paragraphs between text into scene breaks
+fn blanks_to_scene_breaks(paragraphs: Vec<Paragraph>) -> Vec<Paragraph> {
+    let is_text = |p: &Paragraph| [PType::BodyText, PType::Header].contains(&p.kind);
+    let mut out: Vec<Paragraph> = Vec::with_capacity(paragraphs.len());
+    let mut blanks = Vec::new();
+    for p in paragraphs {
+        if p.kind == PType::Empty {
+            blanks.push(p);
+            continue;
+        }
+        let after_text = out
+            .iter()
+            .rev()
+            .find(|p| p.kind != PType::Hidden)
+            .is_some_and(is_text);
+        if blanks.len() >= SCENE_BREAK_BLANKS && after_text && is_text(&p) {
+            let mut scene_break = blanks.swap_remove(0);
+            scene_break.kind = PType::SceneBreak;
+            blanks = vec![scene_break];
         }
+        out.append(&mut blanks);
+        out.push(p);
     }
+    out.append(&mut blanks);
+    out
+}
 
-    if let Some(br) = tag.get_first_child("br") {
-        if tag.span_with(&br).trim().is_empty() && br.span_with(&end_tag).trim().is_empty() {
-            return Paragraph {
-                text: inner,
-                kind: PType::Empty,
-            };
+pub fn parse_passage<'src>(
+    href: &'src str,
+    source: &'src str,
+    css: &'src Stylesheet,
+    notes: &'src Notes,
+) -> impl Iterator<Item = Paragraph<'src>> {
+    PHASE.set(format!("produce: {href}"));
+    let dom = Dom::parse(href, source);
+    let body = dom.select_first("body").or_(死!("unschematic"));
+    // only the writing mode of the page matters, not its margins
+    let mut page = Layout::default();
+    for node in body.ancestors().chain([body]) {
+        if let Some(vertical) = css.style_of(node.tag()).is_vertical() {
+            page.vertical = vertical;
         }
     }
+    let mut paragraphs = Vec::new();
+    parse_paragraphs(body, href, css, notes, &page, &mut paragraphs);
+    blanks_to_scene_breaks(paragraphs).into_iter()
+}
 
-    Paragraph {
-        text: inner,
-        kind: PType::BodyText,
+/// Collects the linked and inline stylesheets of a document, in the cascade order
+pub fn get_stylesheet(
+    href: &str,
+    source: &str,
+    stylesheets: &HashMap<&str, Stylesheet>,
+) -> Stylesheet {
+    let dom = Dom::parse(href, source);
+    let mut css = Stylesheet::default();
+    for style in dom.select("head link[rel~=stylesheet], head style") {
+        if style.name() == "style" {
+            css.extend(&Stylesheet::parse(style.inner()));
+            continue;
+        }
+        let css_href = style
+            .get_attr("href")
+            .or_(死!(at: style, "unschematic: link without href"));
+        match stylesheets.get(resolve_href(href, css_href).as_str()) {
+            Some(linked) => css.extend(linked),
+            None => eprintln!("Stylesheet {css_href} linked from {href} not found"),
+        }
     }
+    css
 }
 
-struct PassageParser<'src> {
-    body: TagIter<'src>,
+/// The layout of an element from CSS, or from the class names in case the stylesheet is missing
+fn layout(parent: &Layout, tag: &Tag, style: &Style) -> Layout {
+    let mut layout = *parent;
+    if let Some(vertical) = style.is_vertical() {
+        layout.vertical = vertical;
+    }
+    for class in tag.classes() {
+        layout.apply_class(class);
+    }
+    let chars = |em: f32| em.round() as i32;
+    let (start, end) = style.inline_margins(layout.vertical);
+    if let Some(start) = start {
+        layout.indent = parent.indent.saturating_add_signed(chars(start));
+    }
+    if let Some(end) = end {
+        layout.end_indent = parent.end_indent.saturating_add_signed(chars(end));
+    }
+    if let Some(text_indent) = style.get_em("text-indent") {
+        layout.text_indent = chars(text_indent);
+    }
+    if let Some(align) = style.get("text-align").and_then(Align::from_css) {
+        layout.align = align;
+    }
+    layout
 }
 
-impl<'src> Iterator for PassageParser<'src> {
-    type Item = Paragraph<'src>;
+/// 傍点 from CSS, or from the class names in case the stylesheet is missing
+fn emphasis_mark(tag: &Tag, style: &Style) -> Option<Mark> {
+    match style.emphasis() {
+        Some(value) => Some(Mark::from_css(value)),
+        None => tag.classes().find_map(Mark::from_class),
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some(p) = self.body.next_by_el(&[]) {
-            let parsed = parse_paragraph(&p);
-            if let Paragraph {
-                kind: PType::Transparent,
-                ..
-            } = parsed
-            {
-                continue;
+fn is_gaiji_element(tag: &Tag) -> bool {
+    tag.has_class("gaiji") || tag.has_class("gaiji-line")
+}
+
+/// An inline image is a gaiji if it says so, has the character or a placeholder for an alt
+/// text, or is about the size of a character. Others are illustrations in the running text.
+fn is_gaiji_img(img: &Tag, style: &Style) -> bool {
+    let px = |attr| {
+        img.get_attr(attr)
+            .and_then(|px| px.trim().trim_end_matches("px").parse::<u32>().ok())
+    };
+    let is_small = match (px("width"), px("height")) {
+        (Some(width), Some(height)) => width.max(height) <= GAIJI_MAX_PX,
+        _ => style
+            .get_em("height")
+            .or(style.get_em("width"))
+            .is_some_and(|em| em <= GAIJI_MAX_EM),
+    };
+    is_gaiji_element(img) || alt_text(img).is_some_and(gaiji::is_gaiji_alt) || is_small
+}
+
+/// Whether an image on a line of its own is a gaiji, by the same signs as in the running text
+fn is_gaiji_node(img: Node, css: &Stylesheet) -> bool {
+    let tag = img.tag();
+    if is_gaiji_img(tag, &css.style_of(tag)) {
+        return true;
+    }
+    tag.local_name() == "svg"
+        && matches!(
+            parse_svg_gaiji(tag, img.end_tag()),
+            Inline::Gaiji { alt: Some(alt), .. } if gaiji::is_gaiji_alt(alt)
+        )
+}
+
+/// The text of the `alt` or `title` attribute, which gives away what the gaiji is
+fn alt_text<'src>(tag: &Tag<'src>) -> Option<&'src str> {
+    [tag.get_attr("alt"), tag.get_attr("title")]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|alt| alt.is_empty().not())
+}
+
+/// An inline svg draws a gaiji, or shows an image of it
+fn parse_svg_gaiji<'src>(svg: &Tag<'src>, end: &Tag<'src>) -> Inline<'src> {
+    let mut iter = svg.iter();
+    let mut image = None;
+    let mut title = None;
+    while let Some(child) = iter.next_by_tag(&[]) {
+        match (child.kind, child.local_name()) {
+            (TType::Closing, _) => (),
+            (_, "image") => {
+                image = image.or(child
+                    .get_attr(ns!(xlink: "href"))
+                    .or(child.get_attr("href")))
             }
-            if p.kind == TType::Opening {
-                self.body.step_out(&p);
-                return Some(parsed);
+            (TType::Opening, "title") => {
+                let (_, text) = child.get_end();
+                title = title.or(Some(text.trim()).filter(|text| text.is_empty().not()));
             }
-            return Some(parsed);
+            _ => (),
         }
-        None
+    }
+    let glyph = match image {
+        Some(href) => Glyph::Image(href),
+        None => Glyph::Svg(svg.outer(end)),
+    };
+    Inline::Gaiji {
+        glyph,
+        alt: title.or(svg.get_attr("aria-label")),
     }
 }
 
-pub fn parse_passage<'src>(
+/// Parses the inline content of a paragraph
+pub fn parse_inline<'src>(
+    root: &Tag<'src>,
     href: &'src str,
-    source: &'src str,
-) -> impl Iterator<Item = Paragraph<'src>> {
-    PHASE.set(format!("produce: {href}"));
-    let iter = Tag::get_first(source, "body")
-        .or_(死!("unschematic"))
-        .iter();
-    PassageParser { body: iter }
-}
-
-pub fn with_fmt_stripped<'b, 'src>(
-    gaiji: &mut HashMap<String, char>,
-    yomi: &mut Vec<Yomi<'src>>,
-    out: &'b mut String,
-    p: &'src str,
-) -> &'b str {
-    let root = Tag::root(p);
-    let mut iter = root.iter();
-    while let Some(tag) = iter.next_by_tag(&[]) {
-        out.push_str(tag.before_text);
-        match tag.kind {
-            TType::Closing => continue,
-            TType::SelfClosing => match tag.name {
-                "br" => out.push('\n'),
-                "img" => {
-                    let src = tag.get_attr("src").or_(死!("unknown formatting"));
-                    let gaiji_ch = match gaiji.get(src) {
-                        Some(&gaiji_ch) => gaiji_ch,
-                        None => {
-                            let replacement_ch = '�';
-                            if let Some("gaiji" | "gaiji-line") = tag.get_attr("class") {
-                                gaiji.insert(src.to_owned(), replacement_ch);
-                            } else {
-                                即死!("unknown formatting");
-                            }
-                            replacement_ch
-                        }
-                    };
-                    out.push(gaiji_ch);
+    css: &Stylesheet,
+    notes: &'src Notes,
+) -> Vec<Inline<'src>> {
+    let mut parser = InlineParser {
+        iter: root.iter(),
+        href,
+        css,
+        notes,
+    };
+    let mut content = parser.parse_children("");
+    // trimmed like the text of the paragraph
+    if let Some(Inline::Text(text)) = content.first_mut() {
+        *text = text.trim_start();
+    }
+    if let Some(Inline::Text(text)) = content.last_mut() {
+        *text = text.trim_end();
+    }
+    content.retain(|inline| matches!(inline, Inline::Text("")).not());
+    content
+}
+
+struct InlineParser<'a, 'src> {
+    iter: TagIter<'src>,
+    href: &'src str,
+    css: &'a Stylesheet,
+    notes: &'src Notes,
+}
+
+impl<'src> InlineParser<'_, 'src> {
+    /// Parses the content until the end tag of `parent`
+    fn parse_children(&mut self, parent: &str) -> Vec<Inline<'src>> {
+        let mut content = Vec::new();
+        while let Some(tag) = self.iter.next_by_tag(&[]) {
+            let is_cell = tag.kind == TType::Opening && ["td", "th"].contains(&tag.name);
+            // the indentation between the cells of a table row isn't text
+            if tag.before_text.is_empty().not()
+                && (is_cell.not() || tag.before_text.trim().is_empty().not())
+            {
+                content.extend(text_with_gaiji(tag.before_text));
+            }
+            if tag.kind == TType::Closing {
+                if tag.name == parent {
+                    break;
                 }
-                _ => {
-                    即死!("unknown formatting");
+                continue; // stray end tags in lenient mode
+            }
+            let style = self.css.style_of(&tag);
+            if style.is_hidden() {
+                self.iter.step_out(&tag);
+                continue;
+            }
+            if let Some(inline) = self.parse_element(tag, &style) {
+                content.push(inline);
+            }
+        }
+        content
+    }
+
+    fn parse_element(&mut self, tag: Tag<'src>, style: &Style) -> Option<Inline<'src>> {
+        let mark = emphasis_mark(&tag, style);
+        let emphasized = |children| match mark.clone() {
+            Some(mark) => vec![Inline::Emphasis { mark, children }],
+            None => children,
+        };
+        let inline = match (tag.kind, tag.name) {
+            (TType::SelfClosing, "br") => Inline::Break,
+            (TType::SelfClosing, "img") if is_gaiji_img(&tag, style) => {
+                let src = tag
+                    .get_attr("src")
+                    .or_(死!(at: tag, "unknown formatting: img without src"));
+                Inline::Gaiji {
+                    glyph: Glyph::Image(src),
+                    alt: alt_text(&tag),
                 }
+            }
+            (TType::Opening, _) if tag.local_name() == "svg" => {
+                let (end, _) = self
+                    .iter
+                    .step_out(&tag)
+                    .or_(死!(at: tag, "unknown formatting"));
+                parse_svg_gaiji(&tag, &end)
+            }
+            (TType::SelfClosing, _) => Inline::Element {
+                tag,
+                children: Vec::new(),
             },
-            TType::Opening => {
-                let (end_tag, inner) = iter.step_out(&tag).or_(死!("unknown formatting"));
-                match tag.name {
-                    "span" | "a" | "em" => out.push_str(inner),
-                    "ruby" => {
-                        let mut iter = tag.iter();
-                        // In case there are rb tags, use the contents of them
-                        let mut last_rb = None;
-                        // In case there are no rb tags, and the base text starts from the end of ruby
-                        let mut last_rt = out.len();
-                        while let Some(r) = iter.next_by_el(&[]) {
-                            out.push_str(r.before_text);
-                            if r.kind == TType::Closing {
-                                continue;
-                            }
-                            let (_, inner_r) = iter.step_out(&r).or_(死!("unknown formatting"));
-                            match r.name {
-                                "rb" => {
-                                    last_rb = Some(out.len()..out.len() + inner_r.len());
-                                    out.push_str(inner_r);
-                                }
-                                "rt" => {
-                                    let rb_span = last_rb.unwrap_or(last_rt..out.len());
-                                    yomi.push(Yomi {
-                                        span: rb_span,
-                                        rt: inner_r,
-                                    });
-                                    last_rt = out.len();
-                                    last_rb = None;
-                                }
-                                _ => {
-                                    即死!("unknown formatting");
-                                }
-                            }
+            (_, "ruby") => {
+                let ruby = Inline::Ruby(self.parse_ruby());
+                match mark {
+                    Some(mark) => Inline::Emphasis {
+                        mark,
+                        children: vec![ruby],
+                    },
+                    None => ruby,
+                }
+            }
+            (_, "a") => {
+                let (_, inner) = tag.get_end();
+                if let Some((marker, note)) = self.notes.resolve(self.href, &tag, inner) {
+                    // note references are removed from the running text
+                    self.iter.step_out(&tag);
+                    return Some(Inline::NoteRef { marker, note });
+                }
+                let children = emphasized(self.parse_children(tag.name));
+                match tag.get_attr("href") {
+                    Some(href) => Inline::Link { href, children },
+                    None => Inline::Element { tag, children },
+                }
+            }
+            (_, "td" | "th") => Inline::Cell(emphasized(self.parse_children(tag.name))),
+            (_, name) => {
+                let mut children = self.parse_children(name);
+                // a span of gaiji in an embedded font might have a title to go with them
+                if is_gaiji_element(&tag) {
+                    for child in &mut children {
+                        if let Inline::Gaiji {
+                            alt: alt @ None, ..
+                        } = child
+                        {
+                            *alt = alt_text(&tag);
                         }
-                        out.push_str(end_tag.before_text);
                     }
-                    _ => {
-                        即死!("unknown formatting");
+                }
+                Inline::Element {
+                    children: emphasized(children),
+                    tag,
+                }
+            }
+        };
+        Some(inline)
+    }
+
+    /// Parses ruby into groups of base text, each with its annotations
+    fn parse_ruby(&mut self) -> Vec<RubyGroup<'src>> {
+        let mut ruby = RubyParts::default();
+        while let Some(child) = self.iter.next_by_tag(&[]) {
+            // whitespace between the parts of ruby isn't text
+            if child.before_text.trim().is_empty().not() {
+                for inline in text_with_gaiji(child.before_text) {
+                    ruby.push_base_inline(inline);
+                }
+            }
+            match (child.kind, child.name) {
+                (TType::Closing, "ruby") => break,
+                (TType::Closing, _) | (TType::SelfClosing, "rb" | "rt" | "rp" | "rtc") => (),
+                // the fallback parentheses for readers without ruby support
+                (_, "rp") => {
+                    self.iter.step_out(&child);
+                }
+                (_, "rb") => {
+                    ruby.flush_if_annotated();
+                    let base = self.parse_children("rb");
+                    ruby.bases.push(base);
+                    ruby.open_base = false;
+                }
+                (_, "rt") => {
+                    let (_, rt) = self
+                        .iter
+                        .step_out(&child)
+                        .or_(死!(at: child, "unknown formatting"));
+                    ruby.rt.push(annotation(rt, child.lang()));
+                }
+                (_, "rtc") => {
+                    self.iter
+                        .step_out(&child)
+                        .or_(死!(at: child, "unknown formatting"));
+                    ruby.rtc.push(parse_rtc(&child));
+                }
+                _ => {
+                    let style = self.css.style_of(&child);
+                    if style.is_hidden() {
+                        self.iter.step_out(&child);
+                        continue;
+                    }
+                    if let Some(inline) = self.parse_element(child, &style) {
+                        ruby.push_base_inline(inline);
                     }
                 }
             }
         }
+        ruby.flush();
+        ruby.groups
+    }
+}
+
+/// An annotation tier of ruby, either from the `rt`s directly in it or from a `rtc`
+#[derive(Debug)]
+struct RubyTier<'src> {
+    rts: Vec<RubyAnnotation<'src>>,
+    spanning: bool, // a `rtc` without `rt`s annotates all the bases together
+}
+
+/// Parses the `rt`s of a `rtc`
+fn parse_rtc<'src>(rtc: &Tag<'src>) -> RubyTier<'src> {
+    let mut rts = Vec::new();
+    let mut texts = Vec::new();
+    let mut iter = rtc.iter();
+    while let Some(r) = iter.next_by_tag(&[]) {
+        if r.before_text.trim().is_empty().not() {
+            texts.push(r.before_text.trim());
+        }
+        if r.kind != TType::Opening {
+            continue;
+        }
+        let (_, inner_r) = iter.step_out(&r).or_(死!(at: r, "unknown formatting"));
+        match r.name {
+            "rt" => rts.push(annotation(inner_r, r.lang().or(rtc.lang()))),
+            "rp" => (),
+            _ => {
+                即死!(at: r, "unknown formatting <{}> in rtc", r.name);
+            }
+        }
+    }
+    if rts.is_empty() && texts.is_empty().not() {
+        let text = match texts[..] {
+            [text] => text,
+            _ => rtc.get_end().1.trim(),
+        };
+        return RubyTier {
+            rts: vec![annotation(text, rtc.lang())],
+            spanning: true,
+        };
+    }
+    RubyTier {
+        rts,
+        spanning: false,
+    }
+}
+
+/// The annotation of ruby text, a gloss if the `lang` of the `rt` isn't Japanese
+fn annotation<'src>(rt: &'src str, lang: Option<&str>) -> RubyAnnotation<'src> {
+    // 傍点 disguised as ruby, e.g. ・・・
+    if let Some(mark) = Mark::from_rt(rt) {
+        return RubyAnnotation::Emphasis(mark);
+    }
+    let primary = lang.and_then(|lang| lang.split('-').next()).unwrap_or("");
+    if primary.is_empty() || primary.eq_ignore_ascii_case("ja") {
+        RubyAnnotation::Reading(rt)
+    } else {
+        RubyAnnotation::Gloss(rt)
+    }
+}
+
+/// The bases and annotations of ruby since the last time they were paired up
+#[derive(Debug, Default)]
+struct RubyParts<'src> {
+    bases: Vec<Vec<Inline<'src>>>,
+    open_base: bool, // the last base is text outside of `rb`, and continues
+    rt: Vec<RubyAnnotation<'src>>,
+    rtc: Vec<RubyTier<'src>>,
+    groups: Vec<RubyGroup<'src>>,
+}
+
+impl<'src> RubyParts<'src> {
+    fn push_base_inline(&mut self, inline: Inline<'src>) {
+        self.flush_if_annotated();
+        match self.bases.last_mut() {
+            Some(base) if self.open_base => base.push(inline),
+            _ => self.bases.push(vec![inline]),
+        }
+        self.open_base = true;
+    }
+
+    fn flush_if_annotated(&mut self) {
+        if self.rt.is_empty().not() || self.rtc.is_empty().not() {
+            self.flush();
+        }
+    }
+
+    /// Pairs the bases with the annotations of each tier in order
+    fn flush(&mut self) {
+        let mut tiers: Vec<_> = self.rtc.drain(..).collect();
+        if self.rt.is_empty().not() {
+            let rts = self.rt.drain(..).collect();
+            tiers.insert(
+                0,
+                RubyTier {
+                    rts,
+                    spanning: false,
+                },
+            );
+        }
+        let bases: Vec<_> = self.bases.drain(..).collect();
+        self.open_base = false;
+        if bases.is_empty() && tiers.is_empty() {
+            return;
+        }
+        let pair = |bases: Vec<Vec<Inline<'src>>>, tiers: &[RubyTier<'src>]| {
+            let len = tiers
+                .iter()
+                .filter(|tier| tier.spanning.not())
+                .map(|tier| tier.rts.len())
+                .max()
+                .unwrap_or(0)
+                .max(bases.len());
+            let mut bases = bases.into_iter();
+            (0..len)
+                .map(|idx| RubyGroup {
+                    base: bases.next().unwrap_or_default(),
+                    annotations: tiers
+                        .iter()
+                        .map(|tier| {
+                            if tier.spanning {
+                                RubyAnnotation::None
+                            } else {
+                                tier.rts.get(idx).cloned().unwrap_or(RubyAnnotation::None)
+                            }
+                        })
+                        .collect(),
+                })
+                .collect::<Vec<_>>()
+        };
+        if tiers.iter().any(|tier| tier.spanning).not() {
+            let groups = pair(bases, &tiers);
+            self.groups.extend(groups);
+            return;
+        }
+        // The spanning tiers annotate a group whose base is the ruby of the other tiers
+        let inner = pair(bases, &tiers);
+        let is_annotated = |group: &RubyGroup| {
+            group
+                .annotations
+                .iter()
+                .any(|ann| *ann != RubyAnnotation::None)
+        };
+        let base = if inner.iter().any(is_annotated) {
+            vec![Inline::Ruby(inner)]
+        } else {
+            inner.into_iter().flat_map(|group| group.base).collect()
+        };
+        self.groups.push(RubyGroup {
+            base,
+            annotations: tiers
+                .iter()
+                .map(|tier| {
+                    if tier.spanning {
+                        tier.rts.first().cloned().unwrap_or(RubyAnnotation::None)
+                    } else {
+                        RubyAnnotation::None
+                    }
+                })
+                .collect(),
+        });
     }
-    out.push('\n');
-    out.as_str()
 }
 
 #[test]
-fn test_strip_formating() {
-    let mut buf = String::new();
-    let mut gaiji = HashMap::new();
-    let mut yomi = Vec::new();
+fn test_get_manifest() {
+    // an OPF without namespaces, matched by the plain names
+    let opf = Dom::parse(
+        "content.opf",
+        r#"<package><manifest><item id="c1" href="text/c1.xhtml"/><item id="ncx" href="toc.ncx"/></manifest><spine toc="ncx"><itemref idref="c1"/></spine></package>"#,
+    );
+    let manifest = get_manifest(&opf);
+    assert_eq!(manifest["c1"], "text/c1.xhtml");
+    assert_eq!(manifest.len(), 2);
+    assert_eq!(get_spine(&opf), ["c1"]);
+}
 
-    let result = with_fmt_stripped(
-        &mut gaiji,
-        &mut yomi,
-        &mut buf,
-        r#"金髪に染めた<span class="tcy">20</span>歳くらいの男<span class="tcy">!!</span>（だとか）"#,
+#[test]
+fn test_parse_inline() {
+    let css = Stylesheet::parse(".bouten { text-emphasis: open circle }");
+    let notes = Notes::default();
+    let content = parse_inline(
+        &Tag::root(
+            r#"<ruby>漢<rt>かん</rt>字<rt>じ</rt>だ</ruby><br/><span class="bouten">本<img class="gaiji" src="g.png"/></span><a href="b.xhtml">次</a>"#,
+        ),
+        "text/a.xhtml",
+        &css,
+        &notes,
+    );
+    let group = |base, annotations| RubyGroup {
+        base: vec![Inline::Text(base)],
+        annotations,
+    };
+    assert_eq!(
+        content[..2],
+        [
+            Inline::Ruby(vec![
+                group("漢", vec![RubyAnnotation::Reading("かん")]),
+                group("字", vec![RubyAnnotation::Reading("じ")]),
+                group("だ", vec![]),
+            ]),
+            Inline::Break,
+        ]
+    );
+    let Inline::Element { tag, children } = &content[2] else {
+        panic!("{:?}", content[2]);
+    };
+    assert_eq!(tag.name, "span");
+    assert_eq!(
+        children,
+        &[Inline::Emphasis {
+            mark: Mark::from_css("open circle"),
+            children: vec![
+                Inline::Text("本"),
+                Inline::Gaiji {
+                    glyph: Glyph::Image("g.png"),
+                    alt: None
+                }
+            ],
+        }]
     );
-    assert_eq!(result, "金髪に染めた20歳くらいの男!!（だとか）\n");
+    assert_eq!(
+        content[3],
+        Inline::Link {
+            href: "b.xhtml",
+            children: vec![Inline::Text("次")],
+        }
+    );
+    assert_eq!(content.len(), 4);
+}
 
-    buf.clear();
-    let result = with_fmt_stripped(
-        &mut gaiji,
-        &mut yomi,
-        &mut buf,
-        r#"<ruby><rb>山</rb><rt>やま</rt><rb>野</rb><rt>の</rt><rb>光</rb><rt>みつ</rt><rb>波</rb><rt>は</rt></ruby>、<span class="tcy">18</span>歳。"#,
+#[test]
+fn test_parse_gaiji() {
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let source = concat!(
+        r##"<img src="g1.png" alt="𩸽"/>と<svg xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="g2.png"/></svg>と<svg viewBox="0 0 10 10"><title>木＋吉</title><path d="M0 0"/></svg>と<span class="gaiji" title="髙">"##,
+        "\u{E001}</span>"
+    );
+    let content = parse_inline(&Tag::root(source), "text/a.xhtml", &css, &notes);
+    let gaiji: Vec<_> = content
+        .iter()
+        .filter_map(|inline| match inline {
+            Inline::Gaiji { glyph, alt } => Some((glyph.clone(), *alt)),
+            Inline::Element { children, .. } => match &children[..] {
+                [Inline::Gaiji { glyph, alt }] => Some((glyph.clone(), *alt)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    let svg = &source[source.find("<svg viewBox").unwrap()..source.find("と<span").unwrap()];
+    assert_eq!(
+        gaiji,
+        [
+            (Glyph::Image("g1.png"), Some("𩸽")),
+            (Glyph::Image("g2.png"), None),
+            (Glyph::Svg(svg), Some("木＋吉")),
+            (Glyph::Char('\u{E001}'), Some("髙")),
+        ]
     );
-    assert_eq!(result, "山野光波、18歳。\n");
+    assert_eq!(Glyph::Char('\u{E001}').key(), "U+E001");
+    assert!(Glyph::Svg(svg).key().starts_with("svg-"));
+    assert_eq!(plain_text(&content), "〓と〓と〓と〓");
 
-    buf.clear();
-    let result = with_fmt_stripped(
-        &mut gaiji,
-        &mut yomi,
-        &mut buf,
-        r#"<ruby><rb>漢</rb><rb>字</rb><rt>kan</rt><rt>ji</rt></ruby>"#,
+    // a line of a single gaiji isn't an illustration
+    let dom = Dom::parse("", r#"<p><img class="gaiji" src="g1.png"/></p>"#);
+    let p = dom.select_first("p").unwrap();
+    let paragraph = parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default());
+    assert_eq!(paragraph.kind, PType::BodyText);
+    let line = |source| {
+        let dom = Dom::parse("", source);
+        let p = dom.select_first("p").unwrap();
+        let paragraph = parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default());
+        (paragraph.kind, plain_text(&paragraph.content))
+    };
+    assert_eq!(
+        line(r#"<p><img src="g01.png" alt="𩸽"/></p>"#),
+        (PType::BodyText, "〓".to_owned())
+    );
+    assert_eq!(
+        line(r#"<p><svg viewBox="0 0 10 10"><title>木＋吉</title><path d="M0 0"/></svg></p>"#),
+        (PType::BodyText, "〓".to_owned())
+    );
+    assert_eq!(
+        line(r#"<p><img src="fig1.png" alt=""/></p>"#),
+        (PType::StandaloneImage, String::new())
     );
-    assert_eq!(result, "漢字\n");
 
-    buf.clear();
-    let result = with_fmt_stripped(
-        &mut gaiji,
-        &mut yomi,
-        &mut buf,
-        r#"<ruby>漢<rt>Kan</rt>字<rt>ji</rt>!</ruby>"#,
+    // only small images or those with a gaiji alt are gaiji, not illustrations in the text
+    let is_gaiji = |img| {
+        let content = parse_inline(&Tag::root(img), "text/a.xhtml", &css, &notes);
+        matches!(content[..], [Inline::Gaiji { .. }])
+    };
+    assert!(is_gaiji(r#"<img src="g.png" width="20" height="20"/>"#));
+    assert!(is_gaiji(r#"<img src="g.png" alt="外字"/>"#));
+    assert!(is_gaiji(r#"<img src="g.png" alt="" style="height: 1em"/>"#));
+    assert!(is_gaiji(r#"<img src="fig1.png" alt=""/>"#).not());
+    assert!(is_gaiji(r#"<img src="fig1.png" alt="fig1.png"/>"#).not());
+    assert!(is_gaiji(r#"<img src="map.png" width="600" height="400"/>"#).not());
+}
+
+#[test]
+fn test_parse_ruby() {
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let parse = |source| parse_inline(&Tag::root(source), "text/a.xhtml", &css, &notes);
+    let text = |text| vec![Inline::Text(text)];
+    let reading = RubyAnnotation::Reading;
+
+    // fallback parentheses, and the bases paired with the readings in order
+    assert_eq!(
+        parse("<ruby><rb>漢</rb><rb>字</rb><rp>（</rp><rt>かん</rt><rt>じ</rt><rp>）</rp></ruby>"),
+        [Inline::Ruby(vec![
+            RubyGroup {
+                base: text("漢"),
+                annotations: vec![reading("かん")],
+            },
+            RubyGroup {
+                base: text("字"),
+                annotations: vec![reading("じ")],
+            },
+        ])]
+    );
+
+    // double-sided ruby, a reading above and a gloss below
+    assert_eq!(
+        parse(
+            "<ruby><rb>東</rb><rb>京</rb><rt>とう</rt><rt>きょう</rt><rtc><rp>(</rp>Tokyo<rp>)</rp></rtc></ruby>"
+        ),
+        [Inline::Ruby(vec![RubyGroup {
+            base: vec![Inline::Ruby(vec![
+                RubyGroup {
+                    base: text("東"),
+                    annotations: vec![reading("とう"), RubyAnnotation::None],
+                },
+                RubyGroup {
+                    base: text("京"),
+                    annotations: vec![reading("きょう"), RubyAnnotation::None],
+                },
+            ])],
+            annotations: vec![RubyAnnotation::None, reading("Tokyo")],
+        }])]
+    );
+    let [Inline::Ruby(groups)] = &parse(
+        "<ruby><rb>旧</rb><rb>字</rb><rtc><rt>きゅう</rt><rt>じ</rt></rtc><rtc><rt>old</rt><rt>letter</rt></rtc></ruby>",
+    )[..] else {
+        panic!();
+    };
+    assert_eq!(groups[1].annotations, [reading("じ"), reading("letter")]);
+    // glosses in another language
+    let [Inline::Ruby(groups), Inline::Ruby(rtc)] = &parse(
+        r#"<ruby>業<rt lang="en">karma</rt>字<rt xml:lang="ja-JP">じ</rt></ruby><ruby><rb>旧</rb><rtc lang="en"><rt>old</rt></rtc></ruby>"#,
+    )[..] else {
+        panic!();
+    };
+    assert_eq!(groups[0].annotations, [RubyAnnotation::Gloss("karma")]);
+    assert_eq!(groups[1].annotations, [reading("じ")]);
+    assert_eq!(rtc[0].annotations, [RubyAnnotation::Gloss("old")]);
+
+    // markup in the base, and nested ruby
+    let content = parse(
+        r#"<ruby><rb><span>鬼</span><img class="gaiji" src="g.png"/></rb><rt>きき</rt></ruby><ruby><ruby>紅<rt>べに</rt></ruby><rt>くれない</rt></ruby>"#,
+    );
+    let Inline::Ruby(groups) = &content[0] else {
+        panic!("{:?}", content[0]);
+    };
+    assert!(matches!(
+        groups[0].base[..],
+        [
+            Inline::Element { .. },
+            Inline::Gaiji {
+                glyph: Glyph::Image("g.png"),
+                ..
+            }
+        ]
+    ));
+    assert_eq!(
+        content[1],
+        Inline::Ruby(vec![RubyGroup {
+            base: vec![Inline::Ruby(vec![RubyGroup {
+                base: text("紅"),
+                annotations: vec![reading("べに")],
+            }])],
+            annotations: vec![reading("くれない")],
+        }])
     );
-    assert_eq!(result, "漢字!\n");
 }
 
 #[test]
@@ -291,11 +1062,21 @@ fn test_parse_paragraph_1() {
       </div>
     </body></html>
 "##;
-    let mut body = Tag::get_first(source, "body").unwrap().iter();
-    let p = body.next_by_el(&[]).unwrap();
-    assert_eq!(parse_paragraph(&p).kind, PType::Transparent);
-    let p = body.next_by_el(&[]).unwrap();
-    assert_eq!(parse_paragraph(&p).kind, PType::StandaloneImage);
+    let dom = Dom::parse("", source);
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let body = dom.select_first("body").unwrap();
+    let mut body = body.descendants();
+    let p = body.next().unwrap();
+    assert_eq!(
+        parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind,
+        PType::Transparent
+    );
+    let p = body.next().unwrap();
+    assert_eq!(
+        parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind,
+        PType::StandaloneImage
+    );
 }
 
 #[test]
@@ -303,46 +1084,252 @@ fn test_parse_paragraph_2() {
     let source = r#"<body><p class="calibre3">　次から次へと、とんでもない言葉が口から衝いて出るジャティスに、フェロードも、グレンも、<ruby><rb>最</rb><rt>も</rt><rb>早</rb><rt>はや</rt></ruby>、脳内処理が追いつかない。</p>
     <p class="calibre3"><img class="fit" src="../images/00009.jpeg" alt=""/></p>
     <p class="calibre3">「ご、五億年……？」</p></body>"#;
-    let mut body = Tag::get_first(source, "body").unwrap().iter();
-    let p = body.next_by_el(&[]).unwrap();
-    assert_eq!(parse_paragraph(&p).kind, PType::BodyText);
-    body.step_out(&p).unwrap();
-    let p = body.next_by_el(&[]).unwrap();
-    assert_eq!(parse_paragraph(&p).kind, PType::StandaloneImage);
-    body.step_out(&p).unwrap();
-    let p = body.next_by_el(&[]).unwrap();
-    assert_eq!(parse_paragraph(&p).kind, PType::BodyText);
-    body.step_out(&p).unwrap();
-}
-
-pub fn get_author(source: &str) -> Cow<'_, str> {
-    let author = Tag::get_first(source, "dc:creator").or_(死!("unschematic"));
-    de_entitify(author.get_end().1)
-}
-
-pub fn get_asin(source: &str) -> Option<Cow<'_, str>> {
-    let mut metadata = Tag::get_first(source, "metadata")
-        .or_(死!("unschematic"))
-        .iter();
-    while let Some(tag) = metadata.next_by_el(&["dc:identifier"]) {
-        if let Some("MOBI-ASIN") = tag.get_attr("opf:scheme") {
-            return Some(de_entitify(tag.get_end().1));
+    let dom = Dom::parse("", source);
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let body = dom.select_first("body").unwrap();
+    let mut body = body.children();
+    let p = body.next().unwrap();
+    assert_eq!(
+        parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind,
+        PType::BodyText
+    );
+    let p = body.next().unwrap();
+    assert_eq!(
+        parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind,
+        PType::StandaloneImage
+    );
+    let p = body.next().unwrap();
+    assert_eq!(
+        parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind,
+        PType::BodyText
+    );
+}
+
+#[test]
+fn test_parse_paragraph_css() {
+    let source = r#"<html><head><style>.spoiler { display: none } .big { font-size: 150% }
+        .bouten { text-emphasis: filled sesame }</style></head>
+    <body><p class="spoiler">隠し</p><p class="big">見出し</p><p><span class="big">見出し</span></p>
+    <p>本文の<span class="bouten">強調</span>と<span class="spoiler">隠し</span>。</p></body></html>"#;
+    let css = get_stylesheet("text/a.xhtml", source, &HashMap::new());
+    let notes = Notes::default();
+    let dom = Dom::parse("", source);
+    let body = dom.select_first("body").unwrap();
+    let kinds: Vec<_> = body
+        .children()
+        .map(|p| parse_paragraph(p, "text/a.xhtml", &css, &notes, &Layout::default()).kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [PType::Hidden, PType::Header, PType::Header, PType::BodyText]
+    );
+
+    let p = parse_paragraph(
+        body.children().last().unwrap(),
+        "text/a.xhtml",
+        &css,
+        &notes,
+        &Layout::default(),
+    );
+    let mut buf = String::new();
+    let mut annotations = super::Annotations::default();
+    let result = crate::txt::with_fmt_stripped(
+        &p,
+        crate::layout::LayoutMode::Plain,
+        &mut HashMap::new(),
+        &Default::default(),
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "本文の強調と。\n");
+    let emphasis = &annotations.emphasis;
+    assert_eq!(&result[emphasis[0].span.clone()], "強調");
+    assert_eq!(emphasis[0].mark.to_string(), "filled sesame");
+}
+
+#[test]
+fn test_parse_paragraph_blocks() {
+    let source = r#"<html><body><h5>小見出し</h5>
+    <blockquote><p>引用一</p><p>引用二</p></blockquote><blockquote>短い引用</blockquote>
+    <ul><li>項目一</li><li><p>項目二</p></li></ul>
+    <dl><dt>用語</dt><dd>説明</dd></dl>
+    <table><caption>表</caption><tbody>
+        <tr>
+            <th>名前</th>
+            <td><ruby>山<rt>やま</rt></ruby></td>
+        </tr>
+    </tbody></table>
+    <figure><img src="a.jpg"/><figcaption>図一</figcaption></figure>
+    <aside><header>前書き</header></aside><pre>一行目
+二行目</pre></body></html>"#;
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let paragraphs: Vec<_> = parse_passage("text/a.xhtml", source, &css, &notes)
+        .map(|p| (p.kind, p.text))
+        .collect();
+    assert_eq!(
+        paragraphs,
+        [
+            (PType::Header, "小見出し"),
+            (PType::BodyText, "引用一"),
+            (PType::BodyText, "引用二"),
+            (PType::BodyText, "短い引用"),
+            (PType::BodyText, "項目一"),
+            (PType::BodyText, "項目二"),
+            (PType::BodyText, "用語"),
+            (PType::BodyText, "説明"),
+            (PType::BodyText, "表"),
+            (
+                PType::BodyText,
+                "<th>名前</th>\n            <td><ruby>山<rt>やま</rt></ruby></td>"
+            ),
+            (PType::StandaloneImage, ""),
+            (PType::BodyText, "図一"),
+            (PType::BodyText, "前書き"),
+            (PType::BodyText, "一行目\n二行目"),
+        ]
+    );
+
+    let row = parse_passage("text/a.xhtml", source, &css, &notes)
+        .find(|p| p.text.starts_with("<th>"))
+        .unwrap();
+    let mut buf = String::new();
+    let mut annotations = super::Annotations::default();
+    let result = crate::txt::with_fmt_stripped(
+        &row,
+        crate::layout::LayoutMode::Plain,
+        &mut HashMap::new(),
+        &Default::default(),
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "名前　山\n");
+    assert_eq!(&result[annotations.yomi[0].span.clone()], "山");
+}
+
+#[test]
+fn test_parse_layout() {
+    let source = r#"<html><head><style>html { -epub-writing-mode: vertical-rl }
+        .letter { margin-top: 2em } .sign { text-align: right; margin-bottom: 1em }</style></head>
+    <body><div class="letter"><p>拝啓</p><p class="sign">太郎</p></div>
+    <p class="jisage_3">三字下げ</p><p style="text-indent: -1em; padding-top: 1em">折り返し</p></body></html>"#;
+    let css = get_stylesheet("text/a.xhtml", source, &HashMap::new());
+    let notes = Notes::default();
+    let layouts: Vec<_> = parse_passage("text/a.xhtml", source, &css, &notes)
+        .map(|p| (p.text, p.layout))
+        .collect();
+    let layout = |indent, text_indent, end_indent, align| Layout {
+        indent,
+        text_indent,
+        end_indent,
+        align,
+        vertical: true,
+    };
+    assert_eq!(
+        layouts,
+        [
+            ("拝啓", layout(2, 0, 0, Align::Start)),
+            ("太郎", layout(2, 0, 1, Align::End)),
+            ("三字下げ", layout(3, 0, 0, Align::Start)),
+            ("折り返し", layout(1, -1, 0, Align::Start)),
+        ]
+    );
+}
+
+#[test]
+fn test_scene_breaks() {
+    use PType::*;
+
+    let source = r#"<html><body><p>一</p><hr/><p>二</p><p>◇　◇　◇</p><p>三</p>
+    <p><br/></p><p>　</p><p>四</p><p><br/></p><p>五</p>
+    <p class="center"><img src="../image/kugiri.png" alt=""/></p><p>＊</p>
+    <p>……</p><p><img src="../image/p001.jpg"/></p><p><img src="../image/scene01.jpg"/></p>
+    <p><img class="page-break" src="../image/p002.jpg"/></p></body></html>"#;
+    let css = Stylesheet::default();
+    let notes = Notes::default();
+    let kinds: Vec<_> = parse_passage("text/a.xhtml", source, &css, &notes)
+        .map(|p| p.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            BodyText,
+            SceneBreak,
+            BodyText,
+            SceneBreak,
+            BodyText,
+            SceneBreak,
+            BodyText,
+            Empty,
+            BodyText,
+            SceneBreak,
+            SceneBreak,
+            BodyText,
+            StandaloneImage,
+            StandaloneImage,
+            StandaloneImage,
+        ]
+    );
+}
+
+pub fn get_author<'src>(opf: &Dom<'src>) -> Cow<'src, str> {
+    let author = opf.select_first("dc|creator").or_(死!("unschematic"));
+    de_entitify(author.inner())
+}
+
+/// The identifiers of the book, the unique identifier of the package first
+pub fn get_identifiers<'src>(opf: &Dom<'src>) -> Vec<Cow<'src, str>> {
+    let unique_id = opf
+        .select_first("opf|package")
+        .and_then(|package| package.get_attr("unique-identifier"));
+    let mut identifiers: Vec<_> = opf.select("opf|metadata dc|identifier").collect();
+    identifiers.sort_by_key(|tag| tag.tag().id() != unique_id);
+    identifiers
+        .into_iter()
+        .map(|tag| de_entitify(tag.inner()))
+        .collect()
+}
+
+/// The obfuscated files listed in encryption.xml, with their algorithms
+pub fn get_encrypted<'src>(encryption: &Dom<'src>) -> Vec<(&'src str, &'src str)> {
+    let mut encrypted = Vec::new();
+    for data in encryption.select("EncryptedData") {
+        let algorithm = data
+            .select_first("EncryptionMethod")
+            .and_then(|method| method.get_attr("Algorithm"));
+        let uri = data
+            .select_first("CipherReference")
+            .and_then(|reference| reference.get_attr("URI"));
+        if let (Some(algorithm), Some(uri)) = (algorithm, uri) {
+            encrypted.push((uri, algorithm));
+        }
+    }
+    encrypted
+}
+
+pub fn get_asin<'src>(opf: &Dom<'src>) -> Option<Cow<'src, str>> {
+    for tag in opf.select("opf|metadata dc|identifier") {
+        // some publishers leave the scheme attribute unprefixed
+        let scheme = tag.get_attr(ns!(opf: "scheme")).or(tag.get_attr("scheme"));
+        if let Some("MOBI-ASIN") = scheme {
+            return Some(de_entitify(tag.inner()));
         }
     }
     None
 }
 
-pub fn get_title(source: &str) -> Cow<'_, str> {
-    let title = Tag::get_first(source, "dc:title").or_(死!("unschematic"));
-    de_entitify(title.get_end().1)
+pub fn get_title<'src>(opf: &Dom<'src>) -> Cow<'src, str> {
+    let title = opf.select_first("dc|title").or_(死!("unschematic"));
+    de_entitify(title.inner())
 }
 
-pub fn get_publisher(source: &str) -> Cow<'_, str> {
-    let publisher = Tag::get_first(source, "dc:publisher").or_(死!("unschematic"));
-    de_entitify(publisher.get_end().1)
+pub fn get_publisher<'src>(opf: &Dom<'src>) -> Cow<'src, str> {
+    let publisher = opf.select_first("dc|publisher").or_(死!("unschematic"));
+    de_entitify(publisher.inner())
 }
 
-pub fn get_date(source: &str) -> Cow<'_, str> {
-    let date = Tag::get_first(source, "dc:date").or_(死!("unschematic"));
-    de_entitify(date.get_end().1)
+pub fn get_date<'src>(opf: &Dom<'src>) -> Cow<'src, str> {
+    let date = opf.select_first("dc|date").or_(死!("unschematic"));
+    de_entitify(date.inner())
 }
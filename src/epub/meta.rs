@@ -2,7 +2,7 @@ use std::{fs::File, io::Write, path::Path};
 
 use crate::{
     SEP,
-    epub::{Epub, doc},
+    epub::{Epub, doc, xhtml::dom::Dom},
     error::{OrDie, 死},
     heuristics,
 };
@@ -18,11 +18,12 @@ pub struct Meta {
 
 impl Meta {
     pub fn new(epub: &Epub) -> Meta {
-        let asin = doc::get_asin(&epub.content).map(|cow| cow.into_owned());
-        let title = doc::get_title(&epub.content).to_string();
-        let author = doc::get_author(&epub.content).to_string();
-        let publisher = doc::get_publisher(&epub.content).to_string();
-        let pub_date = doc::get_date(&epub.content).to_string();
+        let opf = Dom::parse("content.opf", &epub.content);
+        let asin = doc::get_asin(&opf).map(|cow| cow.into_owned());
+        let title = doc::get_title(&opf).to_string();
+        let author = doc::get_author(&opf).to_string();
+        let publisher = doc::get_publisher(&opf).to_string();
+        let pub_date = doc::get_date(&opf).to_string();
 
         let (book_name, label) = heuristics::parse_book_title(&title);
 
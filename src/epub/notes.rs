@@ -0,0 +1,355 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    ops::Not,
+};
+
+use crate::{
+    PHASE,
+    error::{OrDie, 死},
+};
+
+use super::{
+    resolve_href,
+    xhtml::{TType, Tag, dom::Dom, dom::Node},
+};
+
+/// `epub:type`s of the elements that are notes themselves
+const NOTE_TYPES: &[&str] = &["footnote", "endnote", "rearnote", "note"];
+
+/// `epub:type`s of the sections that collect notes
+const NOTES_TYPES: &[&str] = &["footnotes", "endnotes", "rearnotes"];
+
+/// Elements that can hold the text of a note
+const NOTE_BLOCKS: &[&str] = &["p", "li", "dd", "aside", "div", "section", "blockquote"];
+
+/// Prefixes of note markers like ※ or 注1
+const MARKER_PREFIXES: &[&str] = &["原注", "訳注", "注", "※", "＊", "*", "†"];
+
+/// The notes of a book, resolved from the note references
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Notes {
+    notes: HashMap<String, String>,   // "file#id" -> the text of the note
+    blocks: HashSet<(String, usize)>, // the file and the offset of the elements that hold notes
+    docs: HashSet<String>,            // the files that consist of notes
+}
+
+/// A reference to a note from the running text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteRef<'src> {
+    pub offset: usize,
+    pub marker: String,
+    pub text: &'src str,
+}
+
+/// Recognizes note markers like 1, *2, ※, 注三, （4） or [5]
+fn is_marker(text: &str) -> bool {
+    let text = text
+        .trim()
+        .trim_start_matches(['(', '（', '[', '［', '〔', '<', '＜'])
+        .trim_end_matches([')', '）', ']', '］', '〕', '>', '＞']);
+    let (prefix, number) = MARKER_PREFIXES
+        .iter()
+        .find_map(|prefix| Some((*prefix, text.strip_prefix(prefix)?)))
+        .unwrap_or(("", text));
+    let is_numeral = |ch: char| {
+        ch.is_ascii_digit() || ('０'..='９').contains(&ch) || "〇一二三四五六七八九十".contains(ch)
+    };
+    number.chars().count() <= 4
+        && number.chars().all(is_numeral)
+        && (prefix.is_empty().not() || number.is_empty().not())
+}
+
+/// The source without the tags, for checking the text of short elements
+fn strip_tags(source: &str) -> String {
+    let mut text = String::new();
+    let mut rest = source;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = rest[start..].split_once('>').map(|(_, r)| r).unwrap_or("");
+    }
+    text.push_str(rest);
+    text
+}
+
+fn is_note_element(tag: &Tag) -> bool {
+    NOTE_TYPES.iter().any(|&t| tag.has_epub_type(t))
+}
+
+fn is_back_link(tag: &Tag) -> bool {
+    tag.has_epub_type("backlink") || tag.has_epub_type("referrer")
+}
+
+/// The text of a note, without readings and back links
+fn note_text(block: Node) -> String {
+    let back_links: HashSet<_> = block
+        .select("a[href]")
+        .filter(|link| is_back_link(link.tag()) || is_marker(&strip_tags(link.inner())))
+        .map(|link| link.tag().before())
+        .collect();
+    let mut text = String::new();
+    let mut iter = block.tag().iter();
+    while let Some(tag) = iter.next_by_tag(&[]) {
+        text.push_str(tag.before_text);
+        let is_skipped =
+            ["rt", "rp", "rtc"].contains(&tag.name) || back_links.contains(&tag.before());
+        if tag.kind == TType::Opening && is_skipped {
+            iter.step_out(&tag);
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The element that holds the note text, given the target of a reference
+fn note_block<'dom, 'src>(target: Node<'dom, 'src>) -> Node<'dom, 'src> {
+    if NOTE_BLOCKS.contains(&target.name()) {
+        return target;
+    }
+    // the note follows a term like <dt id="n1">（1）</dt>, or an empty anchor like <a id="n1"/>
+    let is_label = target.name() == "dt" || target.inner().trim().is_empty();
+    if let Some(next) = target.next_sibling()
+        && is_label
+        && NOTE_BLOCKS.contains(&next.name())
+    {
+        return next;
+    }
+    target
+        .ancestors()
+        .find(|node| NOTE_BLOCKS.contains(&node.name()))
+        .unwrap_or(target)
+}
+
+/// Splits a link into the file and the fragment, resolving the file relative to the base
+fn link_target(base: &str, href: &str) -> Option<String> {
+    let (file, fragment) = href.split_once('#')?;
+    if file.contains(':') {
+        return None; // external link
+    }
+    let file = match file {
+        "" => base.to_owned(),
+        file => resolve_href(base, file),
+    };
+    Some(format!("{file}#{fragment}"))
+}
+
+impl Notes {
+    /// Finds the notes from the body files, in the spine order
+    pub fn find(body: &[(String, String)]) -> Self {
+        PHASE.set("find_notes");
+        let doms: Vec<_> = body
+            .iter()
+            .map(|(href, text)| (href.as_str(), Dom::parse(href, text)))
+            .collect();
+
+        let mut ids = HashMap::new();
+        for (href, dom) in &doms {
+            for node in dom.root().descendants() {
+                if let Some(id) = node.tag().id() {
+                    ids.insert(format!("{href}#{id}"), node);
+                }
+            }
+        }
+
+        let mut notes = Notes::default();
+        for (href, dom) in &doms {
+            for link in dom.select("a[href]") {
+                let Some(target) = link_target(href, link.get_attr("href").or_(死!())) else {
+                    continue;
+                };
+                let Some(&target_node) = ids.get(&target) else {
+                    continue;
+                };
+                let target_file = target.split_once('#').or_(死!()).0;
+                if is_note_ref(href, link, target_file, target_node).not() {
+                    continue;
+                }
+                let block = note_block(target_node);
+                notes
+                    .blocks
+                    .insert((target_file.to_owned(), block.tag().before()));
+                // the term of a note in a list goes with it
+                if let Some(term) = block.prev_sibling().filter(|node| node.name() == "dt") {
+                    notes
+                        .blocks
+                        .insert((target_file.to_owned(), term.tag().before()));
+                }
+                notes.notes.insert(target, note_text(block));
+            }
+            // notes without references are still notes
+            for note in dom
+                .root()
+                .descendants()
+                .filter(|n| is_note_element(n.tag()))
+            {
+                notes.blocks.insert((href.to_string(), note.tag().before()));
+            }
+        }
+
+        for (href, dom) in &doms {
+            let is_notes_section = dom
+                .root()
+                .descendants()
+                .any(|node| NOTES_TYPES.iter().any(|&t| node.tag().has_epub_type(t)));
+            let blocks: Vec<_> = dom
+                .select("p, li, dd, aside")
+                .filter(|node| node.inner().trim().is_empty().not())
+                .map(|node| notes.is_note_block(href, node.tag()))
+                .collect();
+            let n_notes = blocks.iter().filter(|&&is_note| is_note).count();
+            if is_notes_section || n_notes > 0 && n_notes * 2 >= blocks.len() {
+                notes.docs.insert(href.to_string());
+            }
+        }
+        notes
+    }
+
+    /// Resolves a link in the running text to the marker and the text of the note it refers to
+    pub fn resolve(&self, base: &str, link: &Tag, inner: &str) -> Option<(String, &str)> {
+        let target = link_target(base, link.get_attr("href")?)?;
+        let text = self.notes.get(&target)?;
+        let marker = strip_tags(inner).trim().to_owned();
+        let is_ref = link.has_epub_type("noteref") || is_marker(&marker);
+        is_ref.then_some((marker, text))
+    }
+
+    pub fn is_note_block(&self, href: &str, tag: &Tag) -> bool {
+        is_note_element(tag) || self.blocks.contains(&(href.to_owned(), tag.before()))
+    }
+
+    pub fn is_note_doc(&self, href: &str) -> bool {
+        self.docs.contains(href)
+    }
+}
+
+/// Tells references to notes from back links and ordinary links
+fn is_note_ref(href: &str, link: Node, target_file: &str, target: Node) -> bool {
+    let tag = link.tag();
+    if tag.has_epub_type("noteref") {
+        return true;
+    }
+    let in_note = link.ancestors().any(|node| is_note_element(node.tag()));
+    if in_note || is_back_link(tag) {
+        return false;
+    }
+    if is_note_element(target.tag()) {
+        return true;
+    }
+    if is_marker(&strip_tags(link.inner())).not() {
+        return false;
+    }
+    // A reference and the back link of its note point to each other.
+    // The back link is the one that starts the note.
+    let target_link = match target.name() {
+        "a" => Some(target),
+        _ => target.select_first("a[href]"),
+    };
+    let links_back = target_link
+        .and_then(|target_link| target_link.get_attr("href"))
+        .and_then(|target_href| link_target(target_file, target_href))
+        .is_some_and(|back| back.starts_with(&format!("{href}#")));
+    let block = note_block(link);
+    let starts_block = block.tag().before() == tag.before()
+        || strip_tags(block.tag().span_with(tag)).trim().is_empty();
+    (links_back && starts_block).not()
+}
+
+pub fn write_notes(notes: &[NoteRef], mut file: impl Write) {
+    for NoteRef {
+        offset,
+        marker,
+        text,
+    } in notes
+    {
+        writeln!(file, "{offset}:{marker}:{text}").or_(死!());
+    }
+}
+
+#[test]
+fn test_find_notes() {
+    let body = [
+        (
+            "text/ch1.xhtml".to_owned(),
+            r##"<html><body><p>本文<a id="r1" href="notes.xhtml#n1">（1）</a>と<sup><a href="#f2" epub:type="noteref">*</a></sup>。<a href="ch2.xhtml#top">次へ</a></p>
+            <aside id="f2" epub:type="footnote"><p>脚注の<ruby>本<rt>ほん</rt></ruby>文</p></aside></body></html>"##
+                .to_owned(),
+        ),
+        (
+            "text/notes.xhtml".to_owned(),
+            r##"<html><body><h1>注</h1>
+            <p id="n1"><a href="ch1.xhtml#r1">（1）</a>　後注の本文</p></body></html>"##
+                .to_owned(),
+        ),
+    ];
+    let notes = Notes::find(&body);
+    assert_eq!(notes.notes["text/notes.xhtml#n1"], "後注の本文");
+    assert_eq!(notes.notes["text/ch1.xhtml#f2"], "脚注の本文");
+    assert_eq!(notes.notes.len(), 2);
+    assert!(notes.is_note_doc("text/notes.xhtml"));
+    assert!(notes.is_note_doc("text/ch1.xhtml").not());
+
+    // notes in a list, and after an empty anchor
+    let listed = [(
+        "text/a.xhtml".to_owned(),
+        r##"<html><body><p>本文<a href="#n1">※1</a>と<a href="#n2">※2</a></p>
+        <dl><dt id="n1">※1</dt><dd>一つ目の注</dd></dl><a id="n2"></a><p>二つ目の注</p></body></html>"##
+            .to_owned(),
+    )];
+    let listed_notes = Notes::find(&listed);
+    assert_eq!(listed_notes.notes["text/a.xhtml#n1"], "一つ目の注");
+    assert_eq!(listed_notes.notes["text/a.xhtml#n2"], "二つ目の注");
+    let dom = Dom::parse("", &listed[0].1);
+    let blocks = [dom.select_first("dt"), dom.select_first("dd"), dom.select("p").nth(1)];
+    for node in blocks.map(Option::unwrap) {
+        assert!(listed_notes.is_note_block("text/a.xhtml", node.tag()));
+    }
+    let text = dom.select_first("p").unwrap();
+    assert!(listed_notes.is_note_block("text/a.xhtml", text.tag()).not());
+
+    let dom = Dom::parse("", &body[0].1);
+    let link = dom.select_first("a").unwrap();
+    let note_ref = notes
+        .resolve("text/ch1.xhtml", link.tag(), link.inner())
+        .unwrap();
+    assert_eq!(note_ref, ("（1）".to_owned(), "後注の本文"));
+    let next = dom.select("a").last().unwrap();
+    assert_eq!(
+        notes.resolve("text/ch1.xhtml", next.tag(), next.inner()),
+        None
+    );
+
+    // the references are removed from the running text
+    let css = Default::default();
+    let p = super::Paragraph::body_text(
+        dom.select_first("p").unwrap().inner(),
+        "text/ch1.xhtml",
+        &css,
+        &notes,
+    );
+    let mut annotations = super::Annotations::default();
+    let mut buf = String::new();
+    let result = crate::txt::with_fmt_stripped(
+        &p,
+        crate::layout::LayoutMode::Plain,
+        &mut HashMap::new(),
+        &Default::default(),
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "本文と。次へ\n");
+    let offsets: Vec<_> = annotations
+        .notes
+        .iter()
+        .map(|note| (note.offset, note.text))
+        .collect();
+    assert_eq!(offsets, [(6, "後注の本文"), (9, "脚注の本文")]);
+}
+
+#[test]
+fn test_is_marker() {
+    for marker in ["1", "（２）", "[3]", "*", "※4", "注五", "〔原注6〕", "†"] {
+        assert!(is_marker(marker), "{marker}");
+    }
+    for text in ["", "次へ", "12345", "注意"] {
+        assert!(is_marker(text).not(), "{text}");
+    }
+}
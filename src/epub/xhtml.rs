@@ -1,10 +1,74 @@
-use std::{borrow::Cow, ops::Range};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::Write,
+    ops::{Not, Range},
+    path::Path,
+    sync::{Mutex, atomic::Ordering},
+};
 
+use crate::{
+    LENIENT, PHASE, SEP,
+    error::{Locate, Location, OrDie, locate, 死},
+};
+
+pub mod dom;
 pub mod iter;
+mod namespace;
+mod selector;
 mod tag_parser;
 
 use iter::TagIter;
-use tag_parser::parse_attr;
+pub(crate) use namespace::ns;
+use namespace::{Scope, split_clark, split_qname};
+use tag_parser::{parse_attr, parse_attrs};
+
+/// A fix-up applied to malformed markup in lenient mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    pub phase: String,
+    pub location: String, // entry:line:col, or the byte offset if the source isn't an archive entry
+    pub msg: String,
+}
+
+static REPAIRS: Mutex<Vec<Repair>> = Mutex::new(Vec::new());
+
+/// Records a repair, unless it was made before: the files are parsed again in later phases
+pub fn report_repair(root: &Tag, pos: usize, msg: impl Into<String>) {
+    let location = match (root, pos).location() {
+        Some(location) => location.to_string(),
+        None => pos.to_string(),
+    };
+    let msg = msg.into();
+    let mut repairs = REPAIRS.lock().expect("shouldn't be poisoned");
+    if repairs
+        .iter()
+        .any(|repair| repair.location == location && repair.msg == msg)
+    {
+        return;
+    }
+    repairs.push(Repair {
+        phase: PHASE.to_string(),
+        location,
+        msg,
+    });
+}
+
+pub fn take_repairs() -> Vec<Repair> {
+    std::mem::take(&mut *REPAIRS.lock().expect("shouldn't be poisoned"))
+}
+
+pub fn write_repairs(repairs: &[Repair], fname: &Path) {
+    let mut file = File::create(fname).or_(死!());
+    for Repair {
+        phase,
+        location,
+        msg,
+    } in repairs
+    {
+        writeln!(file, "{phase}{SEP}{location}{SEP}{msg}").or_(死!());
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TType {
@@ -17,28 +81,93 @@ pub enum TType {
 pub struct Tag<'src> {
     pub name: &'src str, // TODO: refactor to usize + accessor method?
     source: &'src str,
+    entry: &'src str, // the archive entry of the source, empty if it isn't one
     span: Range<usize>,
     pub before_text: &'src str, // refactor to usize + accessor method
     pub kind: TType,
+    lenient: bool, // HTML-style error recovery instead of dying on malformed markup
+    scope: Scope<'src>,
 }
 
 impl<'src> Tag<'src> {
-    pub fn get_first(source: &'src str, tag: &str) -> Option<Self> {
-        Tag::root(source).iter().next_by_el(&[tag])
-    }
-
-    pub fn get_first_child(&self, tag: &str) -> Option<Self> {
-        self.iter().next_by_el(&[tag])
-    }
-
     pub fn get_end(&self) -> (Tag<'src>, &'src str) {
         self.iter()
             .step_out(self)
             .unwrap_or_else(|| (self.to_owned(), ""))
     }
 
-    pub fn get_attr(&self, target_attr: &'src str) -> Option<&'src str> {
-        parse_attr(self.repr(), target_attr)
+    /// Gets an attribute by its raw name, or by `{namespace URI}local name`.
+    pub fn get_attr(&self, target_attr: &str) -> Option<&'src str> {
+        let Some((target_uri, target_local)) = split_clark(target_attr) else {
+            return parse_attr(self.repr(), target_attr);
+        };
+        self.attrs()
+            .find(|&(attr, _)| {
+                // unprefixed attributes are in no namespace
+                let (prefix, local) = split_qname(attr);
+                local == target_local
+                    && prefix.is_empty().not()
+                    && self.scope.resolve(prefix) == Some(target_uri)
+            })
+            .map(|(_, val)| val)
+    }
+
+    /// The attributes by their raw names, in the order they are written
+    pub fn attrs(&self) -> impl Iterator<Item = (&'src str, &'src str)> + use<'src> {
+        parse_attrs(self.repr())
+    }
+
+    pub fn classes(&self) -> impl Iterator<Item = &'src str> + use<'src> {
+        self.get_attr("class")
+            .unwrap_or("")
+            .split_ascii_whitespace()
+    }
+
+    pub fn has_class(&self, class: &str) -> bool {
+        self.classes().any(|c| c == class)
+    }
+
+    pub fn id(&self) -> Option<&'src str> {
+        self.get_attr("id")
+    }
+
+    pub fn style(&self) -> Option<&'src str> {
+        self.get_attr("style")
+    }
+
+    /// The language of the content, like `ja` or `en-US`
+    pub fn lang(&self) -> Option<&'src str> {
+        self.get_attr(ns!(xml: "lang"))
+            .or_else(|| self.get_attr("lang"))
+    }
+
+    /// The space-separated semantics of `epub:type`, e.g. `noteref`, `toc`
+    pub fn epub_types(&self) -> impl Iterator<Item = &'src str> + use<'src> {
+        // HTML-ish files might use the prefix without declaring it
+        self.get_attr(ns!(epub: "type"))
+            .or_else(|| self.get_attr("epub:type"))
+            .unwrap_or("")
+            .split_ascii_whitespace()
+    }
+
+    pub fn has_epub_type(&self, epub_type: &str) -> bool {
+        self.epub_types().any(|t| t == epub_type)
+    }
+
+    pub fn local_name(&self) -> &'src str {
+        split_qname(self.name).1
+    }
+
+    pub fn namespace(&self) -> Option<&'src str> {
+        self.scope.resolve(split_qname(self.name).0)
+    }
+
+    /// Matches the element by its raw name, or by `{namespace URI}local name`.
+    pub fn is(&self, target: &str) -> bool {
+        match split_clark(target) {
+            Some((uri, local)) => self.local_name() == local && self.namespace() == Some(uri),
+            None => self.name == target,
+        }
     }
 
     pub fn span_with(&self, tag: &Tag) -> &str {
@@ -49,13 +178,27 @@ impl<'src> Tag<'src> {
         }
     }
 
+    /// The source of the element, from this start tag to its end tag
+    pub fn outer(&self, end: &Tag) -> &'src str {
+        &self.source[self.before()..end.after()]
+    }
+
+    #[cfg(test)]
     pub fn root(source: &'src str) -> Self {
+        Tag::entry_root("", source)
+    }
+
+    /// The root of an archive entry, for locating errors in it
+    pub fn entry_root(entry: &'src str, source: &'src str) -> Self {
         Tag {
             name: "",
             source,
+            entry,
             span: 0..0,
             before_text: "",
             kind: TType::Opening,
+            lenient: LENIENT.load(Ordering::Relaxed),
+            scope: Scope::default(),
         }
     }
 
@@ -63,6 +206,18 @@ impl<'src> Tag<'src> {
         TagIter::new(self)
     }
 
+    /// A root that spans the content up to `end_tag`, for parsing it on its own.
+    /// The positions stay those of the whole source.
+    pub fn inner_root(&self, end_tag: &Tag<'src>) -> Tag<'src> {
+        Tag {
+            name: "",
+            source: &self.source[..end_tag.before().max(self.after())],
+            span: self.after()..self.after(),
+            before_text: "",
+            ..self.clone()
+        }
+    }
+
     pub fn before(&self) -> usize {
         self.span.start
     }
@@ -76,16 +231,109 @@ impl<'src> Tag<'src> {
     }
 }
 
+impl Locate for Tag<'_> {
+    fn location(&self) -> Option<Location> {
+        (self, self.span.start).location()
+    }
+}
+
+/// A position in the source of a tag
+impl Locate for (&Tag<'_>, usize) {
+    fn location(&self) -> Option<Location> {
+        let (tag, pos) = *self;
+        locate(tag.entry, tag.source, pos)
+    }
+}
+
+#[test]
+fn test_namespaces() {
+    let source = r#"<opf:package xmlns:opf="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <opf:metadata>
+        <creator xmlns="http://purl.org/dc/elements/1.1/">著者</creator>
+        <dc:identifier opf:scheme="MOBI-ASIN">B00XXXXXXX</dc:identifier>
+        </opf:metadata>
+        <svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+        <image xlink:href="cover.jpg"/></svg></opf:package>"#;
+    let metadata = Tag::root(source)
+        .iter()
+        .next_by_el(&[ns!(opf: "metadata")])
+        .unwrap();
+    assert_eq!(metadata.name, "opf:metadata");
+    let creator = metadata.iter().next_by_el(&[ns!(dc: "creator")]).unwrap();
+    assert_eq!(creator.get_end().1, "著者");
+    let id = Tag::root(source)
+        .iter()
+        .next_by_el(&[ns!(dc: "identifier")])
+        .unwrap();
+    assert_eq!(id.get_attr(ns!(opf: "scheme")), Some("MOBI-ASIN"));
+    assert_eq!(id.get_attr("opf:scheme"), Some("MOBI-ASIN"));
+    assert_eq!(id.get_attr(ns!(opf: "hoge")), None);
+    let image = Tag::root(source)
+        .iter()
+        .next_by_el(&[ns!(svg: "image")])
+        .unwrap();
+    assert_eq!(image.get_attr(ns!(xlink: "href")), Some("cover.jpg"));
+    assert_eq!(
+        Tag::root(source).iter().next_by_el(&[ns!(opf: "creator")]),
+        None
+    );
+}
+
+#[test]
+fn test_report_repair() {
+    let root = Tag::entry_root("text/repaired.xhtml", "<p>a<br>b</p>");
+    report_repair(&root, 4, "void element <br> without /");
+    report_repair(&root, 4, "void element <br> without /");
+    report_repair(&root, 4, "stray < in text");
+    let repairs: Vec<_> = take_repairs()
+        .into_iter()
+        .filter(|repair| repair.location.starts_with("text/repaired.xhtml:"))
+        .map(|repair| repair.msg)
+        .collect();
+    assert_eq!(repairs, ["void element <br> without /", "stray < in text"]);
+}
+
+#[test]
+fn test_attrs() {
+    let source = r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><img class=" gaiji  fit" id="g1"
+        src="../images/gaiji.png" xml:lang="ja" epub:type="noteref glossref" style="width:1em"/>"#;
+    let img = Tag::root(source).iter().next_by_el(&["img"]).unwrap();
+    assert_eq!(
+        img.attrs().map(|(name, _)| name).collect::<Vec<_>>(),
+        ["class", "id", "src", "xml:lang", "epub:type", "style"]
+    );
+    assert_eq!(img.attrs().nth(2), Some(("src", "../images/gaiji.png")));
+    assert_eq!(img.classes().collect::<Vec<_>>(), ["gaiji", "fit"]);
+    assert!(img.has_class("gaiji"));
+    assert!(img.has_class("fit"));
+    assert!(img.has_class("gaiji-line").not());
+    assert_eq!(img.id(), Some("g1"));
+    assert_eq!(img.lang(), Some("ja"));
+    assert_eq!(img.style(), Some("width:1em"));
+    assert!(img.has_epub_type("noteref"));
+    assert!(img.has_epub_type("glossref"));
+    assert!(img.has_epub_type("note").not());
+
+    let p = Tag::root("<p epub:type='footnote' lang='en'>")
+        .iter()
+        .next_by_el(&["p"])
+        .unwrap();
+    assert!(p.has_epub_type("footnote"));
+    assert_eq!(p.lang(), Some("en"));
+    assert_eq!(p.classes().next(), None);
+}
+
 #[test]
 fn test_find_first() {
-    let hoge = Tag::get_first("<hoge>after hoge", "hoge").unwrap();
+    let find_first = |source, name| Tag::root(source).iter().next_by_el(&[name]);
+    let hoge = find_first("<hoge>after hoge", "hoge").unwrap();
     assert_eq!(hoge.name, "hoge");
     assert_eq!(hoge.span, 0..6);
-    let hoge = Tag::get_first("before hoge<hoge>after hoge", "hoge").unwrap();
+    let hoge = find_first("before hoge<hoge>after hoge", "hoge").unwrap();
     assert_eq!(hoge.span, 11..17);
     assert_eq!(hoge.before_text, "before hoge");
-    assert_eq!(
-        Tag::get_first(
+    assert!(
+        find_first(
             r#"<?xml version="1.0" encoding="UTF-8"?>
             <package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uuid_id">
             <metadata
@@ -95,10 +343,10 @@ fn test_find_first() {
             xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"
             xmlns:calibre="http://calibre.kovidgoyal.net/2009/metadata"
             >
-            <dc:title>やっほう</dc:title></metadata></package></?xml>"#,
+            <dc:title>やっほう</dc:title></metadata></package>"#,
             "manifest"
-        ),
-        None
+        )
+        .is_none()
     );
 }
 
@@ -116,7 +364,7 @@ fn test_get_end_1() {
 fn test_get_end_2() {
     let source = "aa<span>bb</span>cc<span>dd</span>ee";
 
-    let span_1 = Tag::get_first(source, "span").unwrap();
+    let span_1 = Tag::root(source).iter().next_by_el(&["span"]).unwrap();
     assert_eq!(span_1.get_end().1, "bb");
 }
 
@@ -124,14 +372,18 @@ fn test_get_end_2() {
 fn test_get_end_3() {
     let source = "aa<hr/>bb";
 
-    let hr = Tag::get_first(source, "hr").unwrap();
+    let hr = Tag::root(source).iter().next_by_el(&["hr"]).unwrap();
     assert_eq!(hr.get_end().1, "");
 }
 
 #[test]
 fn test_find_incremental() {
     let source = "<body><div><p>a</p><p>b</p></div></body>";
-    let mut div = Tag::get_first(source, "div").unwrap().iter();
+    let mut div = Tag::root(source)
+        .iter()
+        .next_by_el(&["div"])
+        .unwrap()
+        .iter();
     assert_eq!(div.next_by_tag(&[]).unwrap().name, "p"); // first p with a
     assert_eq!(div.next_by_tag(&[]).unwrap().name, "p"); // closig
     assert_eq!(div.next_by_tag(&[]).unwrap().name, "p"); // second p with b
@@ -0,0 +1,234 @@
+use crate::error::{Locate, Location, OrDie, 死};
+
+use super::{TType, Tag, selector::Selector};
+
+type NodeId = usize;
+
+#[derive(Debug)]
+struct NodeData<'src> {
+    tag: Tag<'src>,
+    end_tag: Tag<'src>, // the tag itself, if self-closing
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    child_idx: usize, // index in the children of the parent
+}
+
+/// A tree of the elements of a document, built in one pass.
+/// The nodes borrow their names and contents from the source.
+#[derive(Debug)]
+pub struct Dom<'src> {
+    nodes: Vec<NodeData<'src>>,
+}
+
+/// A reference to an element in a `Dom`
+#[derive(Debug, Clone, Copy)]
+pub struct Node<'dom, 'src> {
+    dom: &'dom Dom<'src>,
+    id: NodeId,
+}
+
+impl<'src> Dom<'src> {
+    /// Parses the source of an archive entry; errors are located by the entry name
+    pub fn parse(entry: &'src str, source: &'src str) -> Self {
+        let root = Tag::entry_root(entry, source);
+        let mut nodes = vec![NodeData {
+            tag: root.clone(),
+            end_tag: root.clone(),
+            parent: None,
+            children: Vec::new(),
+            child_idx: 0,
+        }];
+        let mut stack = vec![0];
+        let mut iter = root.iter();
+        while let Some(tag) = iter.next_by_tag(&[]) {
+            let &parent = stack.last().or_(死!(at: tag, "unexpected end of document"));
+            match tag.kind {
+                TType::Closing => {
+                    // in lenient mode, stray end tags might occur
+                    if nodes[parent].tag.name == tag.name {
+                        nodes[parent].end_tag = tag;
+                        stack.pop();
+                    }
+                }
+                kind => {
+                    let id = nodes.len();
+                    let child_idx = nodes[parent].children.len();
+                    nodes[parent].children.push(id);
+                    nodes.push(NodeData {
+                        end_tag: tag.clone(),
+                        tag,
+                        parent: Some(parent),
+                        children: Vec::new(),
+                        child_idx,
+                    });
+                    if kind == TType::Opening {
+                        stack.push(id);
+                    }
+                }
+            }
+        }
+        Dom { nodes }
+    }
+
+    /// The document root; not an element itself, but the parent of the top-level elements
+    pub fn root(&self) -> Node<'_, 'src> {
+        Node { dom: self, id: 0 }
+    }
+
+    pub fn select<'dom>(&'dom self, selector: &str) -> impl Iterator<Item = Node<'dom, 'src>> {
+        self.root().select(selector)
+    }
+
+    pub fn select_first(&self, selector: &str) -> Option<Node<'_, 'src>> {
+        self.select(selector).next()
+    }
+}
+
+impl<'dom, 'src> Node<'dom, 'src> {
+    fn node(self, id: NodeId) -> Self {
+        Node { dom: self.dom, id }
+    }
+
+    fn data(&self) -> &'dom NodeData<'src> {
+        &self.dom.nodes[self.id]
+    }
+
+    pub fn tag(&self) -> &'dom Tag<'src> {
+        &self.data().tag
+    }
+
+    pub fn end_tag(&self) -> &'dom Tag<'src> {
+        &self.data().end_tag
+    }
+
+    pub fn name(&self) -> &'src str {
+        self.tag().name
+    }
+
+    pub fn get_attr(&self, attr: &str) -> Option<&'src str> {
+        self.tag().get_attr(attr)
+    }
+
+    /// The source between the start and end tags
+    pub fn inner(&self) -> &'src str {
+        let Tag { source, kind, .. } = self.tag();
+        if *kind == TType::SelfClosing {
+            return "";
+        }
+        &source[self.tag().after()..self.end_tag().before()]
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        self.data().parent.map(|id| self.node(id))
+    }
+
+    pub fn children(self) -> impl DoubleEndedIterator<Item = Self> + Clone {
+        self.data().children.iter().map(move |&id| self.node(id))
+    }
+
+    pub fn next_sibling(&self) -> Option<Self> {
+        let parent = self.parent()?;
+        let &id = parent.data().children.get(self.data().child_idx + 1)?;
+        Some(self.node(id))
+    }
+
+    pub fn prev_sibling(&self) -> Option<Self> {
+        let parent = self.parent()?;
+        let idx = self.data().child_idx.checked_sub(1)?;
+        Some(self.node(parent.data().children[idx]))
+    }
+
+    pub fn ancestors(self) -> impl Iterator<Item = Self> {
+        std::iter::successors(self.parent(), |node| node.parent())
+    }
+
+    /// All the descendant elements in document order
+    pub fn descendants(self) -> impl Iterator<Item = Self> {
+        // the nodes are allocated in document order, and a subtree is a contiguous range
+        let mut end = self;
+        while let Some(last) = end.children().next_back() {
+            end = last;
+        }
+        (self.id + 1..end.id + 1).map(move |id| self.node(id))
+    }
+
+    pub fn matches(&self, selector: &Selector) -> bool {
+        let ancestors: Vec<_> = self.ancestors().map(|node| node.tag()).collect();
+        selector.matches(self.tag(), &ancestors)
+    }
+
+    /// The descendants that match the selector. The ancestors of the node
+    /// are considered for matching too, like in `querySelectorAll`.
+    pub fn select(self, selector: &str) -> impl Iterator<Item = Self> {
+        let selector = Selector::parse(selector);
+        self.descendants()
+            .filter(move |node| node.matches(&selector))
+    }
+
+    pub fn select_first(self, selector: &str) -> Option<Self> {
+        self.select(selector).next()
+    }
+}
+
+impl Locate for Node<'_, '_> {
+    fn location(&self) -> Option<Location> {
+        self.tag().location()
+    }
+}
+
+#[test]
+fn test_dom_navigation() {
+    let source = r#"<?xml version="1.0"?><html><body><p>a</p><div><p>b<br/></p></div><p>c</p></body></html>"#;
+    let dom = Dom::parse("", source);
+    let body = dom.select_first("body").unwrap();
+    let names: Vec<_> = body.children().map(|node| node.name()).collect();
+    assert_eq!(names, ["p", "div", "p"]);
+    let div = body.children().nth(1).unwrap();
+    assert_eq!(div.prev_sibling().unwrap().inner(), "a");
+    assert_eq!(div.next_sibling().unwrap().inner(), "c");
+    let first = body.children().next().unwrap();
+    assert!(first.prev_sibling().is_none());
+    assert_eq!(div.inner(), "<p>b<br/></p>");
+    assert_eq!(div.parent().unwrap().name(), "body");
+    let names: Vec<_> = body.descendants().map(|node| node.name()).collect();
+    assert_eq!(names, ["p", "div", "p", "br", "p"]);
+    let br = dom.select_first("br").unwrap();
+    assert_eq!(br.inner(), "");
+    assert_eq!(br.next_sibling().map(|node| node.name()), None);
+    assert!(dom.root().next_sibling().is_none());
+    let names: Vec<_> = br.ancestors().map(|node| node.name()).collect();
+    assert_eq!(names, ["p", "div", "body", "html", ""]);
+}
+
+#[test]
+fn test_dom_select() {
+    let source = r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><body>
+        <p class="calibre3">a</p><div><p class="calibre3 x">b</p></div>
+        <nav epub:type="landmarks"><a href="1">x</a></nav>
+        <nav epub:type="toc"><ol><li><a href="2">y</a></li></ol></nav></body></html>"#;
+    let dom = Dom::parse("", source);
+    let texts: Vec<_> = dom.select("body > p.calibre3").map(|n| n.inner()).collect();
+    assert_eq!(texts, ["a"]);
+    let texts: Vec<_> = dom.select("body p.calibre3").map(|n| n.inner()).collect();
+    assert_eq!(texts, ["a", "b"]);
+    let texts: Vec<_> = dom.select("html div > .x").map(|n| n.inner()).collect();
+    assert_eq!(texts, ["b"]);
+    let hrefs: Vec<_> = dom
+        .select("nav[epub|type=toc] a")
+        .map(|n| n.get_attr("href").unwrap())
+        .collect();
+    assert_eq!(hrefs, ["2"]);
+    let nav = dom.select_first("nav").unwrap();
+    assert_eq!(nav.select("a").count(), 1);
+    assert_eq!(dom.select("body > a").count(), 0);
+}
+
+#[test]
+fn test_dom_location() {
+    let source = "<html>\n<body>\n  <p>テキスト<blink>x</blink></p>\n</body></html>";
+    let dom = Dom::parse("OEBPS/text/a.xhtml", source);
+    let blink = dom.select_first("blink").unwrap();
+    let location = blink.location().unwrap();
+    assert_eq!(location.to_string(), "OEBPS/text/a.xhtml:3:10");
+    assert_eq!(Dom::parse("", source).root().location(), None);
+}
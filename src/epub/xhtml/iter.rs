@@ -2,11 +2,60 @@ use std::ops::Not;
 
 use crate::{error::OrDie, 即死, 死};
 
-use super::{TType, Tag, tag_parser::parse_tag};
+use super::{TType, Tag, namespace::Scope, report_repair, tag_parser::parse_tag};
+
+/// HTML elements that never have contents or end tags
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Start tags that close an open p element in HTML
+const P_CLOSERS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "dl",
+    "fieldset",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+
+/// Whether opening `next` implies the end of the currently open `open` element
+fn implies_end(open: &str, next: &str) -> bool {
+    match open {
+        "p" => P_CLOSERS.contains(&next),
+        "li" => next == "li",
+        "dt" | "dd" => ["dt", "dd"].contains(&next),
+        "rb" | "rt" | "rp" => ["rb", "rt", "rp", "rtc"].contains(&next),
+        _ => false,
+    }
+}
+
+fn is_heading(name: &str) -> bool {
+    ["h1", "h2", "h3", "h4", "h5", "h6"].contains(&name)
+}
 
 #[derive(Debug)]
 pub struct TagIter<'src> {
-    stack: Vec<(usize, &'src str)>,
+    stack: Vec<(usize, &'src str, Scope<'src>)>,
     pos: usize,
     root: Tag<'src>,
 }
@@ -14,7 +63,7 @@ pub struct TagIter<'src> {
 impl<'src> TagIter<'src> {
     pub fn new(tag: &Tag<'src>) -> Self {
         TagIter {
-            stack: vec![(tag.after(), tag.name)],
+            stack: vec![(tag.after(), tag.name, tag.scope.clone())],
             pos: tag.span.end,
             root: tag.clone(),
         }
@@ -34,42 +83,128 @@ impl<'src> TagIter<'src> {
             return None;
         }
         while self.stack.is_empty().not() {
-            let tag = match parse_tag(self.root.source, self.pos) {
+            let mut tag = match parse_tag(&self.root, self.pos) {
+                Some(tag) if self.root.lenient => self.repair(tag),
                 Some(tag) => tag,
                 None => {
                     // no literal tags left in source, trying for root tag
-                    if let Some((_, "")) = self.stack.last() {
+                    if let Some((_, "", _)) = self.stack.last() {
                         Tag {
                             name: "",
                             source: self.root.source,
+                            entry: self.root.entry,
                             span: self.root.source.len()..self.root.source.len(),
                             before_text: &self.root.source[self.pos..],
                             kind: TType::Closing,
+                            lenient: self.root.lenient,
+                            scope: self.scope(),
                         }
+                    } else if self.root.lenient {
+                        self.implied_end(self.root.source.len(), "unclosed element at EOF")
                     } else {
                         // source endeded, but there were still unpopped tags in stack?
-                        即死!("unexpected EOF");
+                        let &(open, name, _) = self.stack.last().or_(死!());
+                        即死!(at: (&self.root, open), "unexpected EOF: <{name}> is never closed");
                     }
                 }
             };
             self.pos = tag.after();
+            tag.scope = match tag.kind {
+                TType::Closing => self.scope(),
+                TType::Opening | TType::SelfClosing => self.scope().declare(tag.repr()),
+            };
             if tag.kind == TType::Opening {
-                self.stack.push((self.pos, tag.name));
+                self.stack.push((self.pos, tag.name, tag.scope.clone()));
             }
             if tag.kind == TType::Closing {
-                match self.stack.pop() {
+                match self.stack.last() {
                     // closing tag in source matches the tag on stack
-                    Some((_, expected)) if expected == tag.name => (),
-                    _ => 即死!("closing tag mismatch"),
+                    Some(&(_, expected, _)) if expected == tag.name => {
+                        self.stack.pop();
+                    }
+                    // stray end tags are passed through as-is, leaving the stack untouched
+                    _ if self.root.lenient => (),
+                    Some(&(_, expected, _)) => {
+                        即死!(at: tag, "closing tag mismatch: expected </{expected}>")
+                    }
+                    None => 即死!(at: tag, "closing tag mismatch: nothing to close"),
                 }
             };
-            if target_tags.is_empty() || target_tags.contains(&tag.name) {
+            if target_tags.is_empty() || target_tags.iter().any(|target| tag.is(target)) {
                 return Some(tag);
             }
         }
         None
     }
 
+    /// Applies HTML-style recovery rules to a freshly parsed tag.
+    /// Might return a zero-width synthesized end tag instead,
+    /// in which case the original tag is re-parsed on the next round.
+    fn repair(&mut self, mut tag: Tag<'src>) -> Tag<'src> {
+        let open = self.stack.last().map(|&(_, name, _)| name).unwrap_or("");
+        match tag.kind {
+            TType::Opening if implies_end(open, tag.name) => {
+                return self.implied_end(tag.before(), format!("<{}> implies end", tag.name));
+            }
+            TType::Opening if VOID_ELEMENTS.contains(&tag.name) => {
+                let end_tag = format!("</{}", tag.name);
+                if self.root.source[tag.after()..].starts_with(&end_tag).not() {
+                    report_repair(
+                        &self.root,
+                        tag.before(),
+                        format!("void element <{}> without /", tag.name),
+                    );
+                    tag.kind = TType::SelfClosing;
+                }
+            }
+            TType::Closing if open == tag.name => (),
+            TType::Closing if self.stack.iter().any(|&(_, name, _)| name == tag.name) => {
+                return self.implied_end(tag.before(), format!("</{}> implies end", tag.name));
+            }
+            // as in HTML, the end tag of any heading ends the open one
+            TType::Closing if is_heading(open) && is_heading(tag.name) => {
+                report_repair(
+                    &self.root,
+                    tag.before(),
+                    format!("</{}> ends <{open}>", tag.name),
+                );
+                tag.name = open;
+            }
+            TType::Closing => {
+                report_repair(&self.root, tag.before(), format!("stray </{}>", tag.name))
+            }
+            _ => (),
+        }
+        tag
+    }
+
+    /// Synthesizes a zero-width end tag at `pos` for the innermost open element
+    fn implied_end(&self, pos: usize, reason: impl Into<String>) -> Tag<'src> {
+        let &(_, name, _) = self.stack.last().or_(死!("unexpected empty stack"));
+        report_repair(
+            &self.root,
+            pos,
+            format!("implied </{name}>: {}", reason.into()),
+        );
+        Tag {
+            name,
+            source: self.root.source,
+            entry: self.root.entry,
+            span: pos..pos,
+            before_text: &self.root.source[self.pos..pos],
+            kind: TType::Closing,
+            lenient: self.root.lenient,
+            scope: self.scope(),
+        }
+    }
+
+    fn scope(&self) -> Scope<'src> {
+        match self.stack.last() {
+            Some((_, _, scope)) => scope.clone(),
+            None => self.root.scope.clone(),
+        }
+    }
+
     pub fn step_out(&mut self, tag: &Tag<'src>) -> Option<(Tag<'src>, &'src str)> {
         if tag.kind == TType::SelfClosing && self.pos == tag.after() {
             return None;
@@ -77,13 +212,15 @@ impl<'src> TagIter<'src> {
         let Some(tag_depth) = self
             .stack
             .iter()
-            .position(|&(pos, name)| pos == tag.after() && name == tag.name)
+            .position(|&(pos, name, _)| pos == tag.after() && name == tag.name)
         else {
-            即死!("unexpected not found");
+            即死!(at: tag, "unexpected not found");
         };
 
         let end_tag = loop {
-            let end_tag = self.next_by_tag(&[tag.name]).or_(死!("unexpected EOF"));
+            let end_tag = self
+                .next_by_tag(&[tag.name])
+                .or_(死!(at: tag, "unexpected EOF: <{}> is never closed", tag.name));
             if self.stack.len() == tag_depth {
                 break end_tag;
             }
@@ -117,3 +254,68 @@ fn test_tag_iter_just_next() {
     assert_eq!(iter.next_by_el(&[]).unwrap().name, "span");
     assert_eq!(iter.next_by_el(&[]), None);
 }
+
+#[test]
+fn test_tag_iter_lenient() {
+    let source = r#"<p>a<br>b<img src="x.png"><p>c</span>d</div>e"#;
+    let root = Tag {
+        lenient: true,
+        ..Tag::root(source)
+    };
+
+    let mut iter = root.iter();
+    let p = iter.next_by_el(&[]).unwrap();
+    let (end_p, inner) = iter.step_out(&p).unwrap();
+    assert_eq!(inner, r#"a<br>b<img src="x.png">"#);
+    assert_eq!(end_p.span, end_p.before()..end_p.before());
+    let p = iter.next_by_el(&[]).unwrap();
+    let (_, inner) = iter.step_out(&p).unwrap();
+    assert_eq!(inner, "c</span>d</div>e");
+    assert_eq!(iter.next_by_el(&[]), None);
+
+    let mut iter = root.iter();
+    let mut tags = Vec::new();
+    while let Some(tag) = iter.next_by_tag(&[]) {
+        tags.push((tag.name, tag.kind, tag.before_text));
+    }
+    assert_eq!(
+        tags,
+        [
+            ("p", TType::Opening, ""),
+            ("br", TType::SelfClosing, "a"),
+            ("img", TType::SelfClosing, "b"),
+            ("p", TType::Closing, ""),
+            ("p", TType::Opening, ""),
+            ("span", TType::Closing, "c"),
+            ("div", TType::Closing, "d"),
+            ("p", TType::Closing, "e"),
+            ("", TType::Closing, ""),
+        ]
+    );
+}
+
+#[test]
+fn test_tag_iter_lenient_heading() {
+    let source = "<h2>第二章</h3><p>本文</p>";
+    let root = Tag {
+        lenient: true,
+        ..Tag::root(source)
+    };
+    let mut iter = root.iter();
+    let h2 = iter.next_by_el(&[]).unwrap();
+    assert_eq!(iter.step_out(&h2).unwrap().1, "第二章");
+    assert_eq!(iter.next_by_el(&[]).unwrap().name, "p");
+}
+
+#[test]
+fn test_tag_iter_lenient_xhtml_void() {
+    let source = r#"<p>a<br></br>b</p>"#;
+    let root = Tag {
+        lenient: true,
+        ..Tag::root(source)
+    };
+    let mut iter = root.iter();
+    let p = iter.next_by_el(&[]).unwrap();
+    assert_eq!(iter.next_by_el(&[]).unwrap().kind, TType::Opening);
+    assert_eq!(iter.step_out(&p).unwrap().1, "a<br></br>b");
+}
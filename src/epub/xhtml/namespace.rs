@@ -0,0 +1,113 @@
+use std::{ops::Not, rc::Rc};
+
+use super::tag_parser::parse_attrs;
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Conventional prefixes of the namespaces EPUBs use (keep in sync with `ns!`)
+const KNOWN_PREFIXES: &[(&str, &str)] = &[
+    ("opf", "http://www.idpf.org/2007/opf"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("ncx", "http://www.daisy.org/z3986/2005/ncx/"),
+    ("xhtml", "http://www.w3.org/1999/xhtml"),
+    ("epub", "http://www.idpf.org/2007/ops"),
+    ("svg", "http://www.w3.org/2000/svg"),
+    ("xlink", "http://www.w3.org/1999/xlink"),
+    ("xml", XML_NS),
+];
+
+pub fn known_uri(prefix: &str) -> Option<&'static str> {
+    KNOWN_PREFIXES
+        .iter()
+        .find(|&&(known, _)| known == prefix)
+        .map(|&(_, uri)| uri)
+}
+
+/// Builds a namespace-qualified name in Clark notation: `{namespace URI}local name`
+macro_rules! ns {
+    (opf: $local:literal) => {
+        concat!("{http://www.idpf.org/2007/opf}", $local)
+    };
+    (dc: $local:literal) => {
+        concat!("{http://purl.org/dc/elements/1.1/}", $local)
+    };
+    (ncx: $local:literal) => {
+        concat!("{http://www.daisy.org/z3986/2005/ncx/}", $local)
+    };
+    (xhtml: $local:literal) => {
+        concat!("{http://www.w3.org/1999/xhtml}", $local)
+    };
+    (epub: $local:literal) => {
+        concat!("{http://www.idpf.org/2007/ops}", $local)
+    };
+    (svg: $local:literal) => {
+        concat!("{http://www.w3.org/2000/svg}", $local)
+    };
+    (xlink: $local:literal) => {
+        concat!("{http://www.w3.org/1999/xlink}", $local)
+    };
+    (xml: $local:literal) => {
+        concat!("{http://www.w3.org/XML/1998/namespace}", $local)
+    };
+}
+pub(crate) use ns;
+
+/// The namespace declarations in scope at some tag, as (prefix, URI) pairs.
+/// The default namespace has the empty prefix. Innermost declarations come last.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scope<'src>(Option<Rc<Vec<(&'src str, &'src str)>>>);
+
+impl<'src> Scope<'src> {
+    /// Returns the scope extended with the xmlns attributes of `tag_repr`
+    pub fn declare(&self, tag_repr: &'src str) -> Self {
+        if tag_repr.contains("xmlns").not() {
+            return self.clone();
+        }
+        let mut decls = self.0.as_deref().cloned().unwrap_or_default();
+        for (attr, uri) in parse_attrs(tag_repr) {
+            if attr == "xmlns" {
+                decls.push(("", uri));
+            } else if let Some(prefix) = attr.strip_prefix("xmlns:") {
+                decls.push((prefix, uri));
+            }
+        }
+        Scope(Some(Rc::new(decls)))
+    }
+
+    pub fn resolve(&self, prefix: &str) -> Option<&'src str> {
+        if prefix == "xml" {
+            return Some(XML_NS);
+        }
+        let decls = self.0.as_deref()?;
+        let &(_, uri) = decls.iter().rev().find(|&&(p, _)| p == prefix)?;
+        // xmlns="" undeclares the default namespace
+        Some(uri).filter(|uri| uri.is_empty().not())
+    }
+}
+
+pub fn split_qname(qname: &str) -> (&str, &str) {
+    qname.split_once(':').unwrap_or(("", qname))
+}
+
+/// Splits a name in Clark notation into URI and local name.
+/// Returns None for plain names.
+pub fn split_clark(name: &str) -> Option<(&str, &str)> {
+    name.strip_prefix('{')?.split_once('}')
+}
+
+#[test]
+fn test_scope() {
+    let scope = Scope::default()
+        .declare(r#"<package xmlns="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/">"#);
+    assert_eq!(scope.resolve(""), Some("http://www.idpf.org/2007/opf"));
+    assert_eq!(
+        scope.resolve("dc"),
+        Some("http://purl.org/dc/elements/1.1/")
+    );
+    assert_eq!(scope.resolve("opf"), None);
+    let inner = scope.declare(r#"<metadata xmlns:opf="http://www.idpf.org/2007/opf" xmlns="">"#);
+    assert_eq!(inner.resolve("opf"), Some("http://www.idpf.org/2007/opf"));
+    assert_eq!(inner.resolve(""), None);
+    assert_eq!(scope.resolve("opf"), None);
+    assert_eq!(inner.resolve("xml"), Some(XML_NS));
+}
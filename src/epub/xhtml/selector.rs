@@ -0,0 +1,330 @@
+use std::ops::Not;
+
+use crate::error::{OrDie, 即死, 死};
+
+use super::{Tag, namespace::known_uri, split_qname};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant, // `a b`
+    Child,      // `a > b`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttrOp {
+    Exists,   // `[a]`
+    Equals,   // `[a=b]`
+    Includes, // `[a~=b]`, a space-separated list that contains b
+}
+
+/// A possibly namespaced name: `local`, or `prefix|local` with a known prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Name<'s> {
+    prefix: Option<&'s str>,
+    local: &'s str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AttrSel<'s> {
+    name: Name<'s>,
+    op: AttrOp,
+    value: &'s str,
+}
+
+/// A sequence of simple selectors that all apply to the same element, e.g. `p.calibre3`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Compound<'s> {
+    name: Option<Name<'s>>, // None is the universal selector
+    id: Option<&'s str>,
+    classes: Vec<&'s str>,
+    attrs: Vec<AttrSel<'s>>,
+}
+
+type Steps<'s> = Vec<(Combinator, Compound<'s>)>; // the combinator joins the step to the previous one
+
+/// A minimal subset of CSS selectors:
+/// type, `*`, `.class`, `#id`, `[attr]`, `[attr=val]`, `[attr~=val]`,
+/// namespaces as `prefix|name`, the descendant and child combinators,
+/// and comma-separated lists of alternatives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector<'s> {
+    alternatives: Vec<Steps<'s>>,
+}
+
+fn is_ident_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ['-', '_'].contains(&ch) || ch.is_ascii().not()
+}
+
+struct Parser<'s> {
+    source: &'s str,
+    pos: usize,
+}
+
+impl<'s> Parser<'s> {
+    fn rest(&self) -> &'s str {
+        &self.source[self.pos..]
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let eaten = self.rest().starts_with(prefix);
+        if eaten {
+            self.pos += prefix.len();
+        }
+        eaten
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let len = self.rest().len() - self.rest().trim_start().len();
+        self.pos += len;
+        len > 0
+    }
+
+    fn ident(&mut self) -> &'s str {
+        let len = self
+            .rest()
+            .find(|ch| is_ident_char(ch).not())
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            即死!(
+                "invalid selector {}: expected a name at {}",
+                self.source,
+                self.pos
+            );
+        }
+        let ident = &self.rest()[..len];
+        self.pos += len;
+        ident
+    }
+
+    fn name(&mut self) -> Name<'s> {
+        let first = self.ident();
+        if self.eat("|") {
+            known_uri(first).or_(死!("unknown namespace prefix {first} in selector"));
+            Name {
+                prefix: Some(first),
+                local: self.ident(),
+            }
+        } else {
+            Name {
+                prefix: None,
+                local: first,
+            }
+        }
+    }
+
+    fn attr(&mut self) -> AttrSel<'s> {
+        self.skip_whitespace();
+        let name = self.name();
+        self.skip_whitespace();
+        let op = if self.eat("~=") {
+            AttrOp::Includes
+        } else if self.eat("=") {
+            AttrOp::Equals
+        } else {
+            AttrOp::Exists
+        };
+        self.skip_whitespace();
+        let value = match op {
+            AttrOp::Exists => "",
+            _ => match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let len = self.rest()[1..]
+                        .find(quote)
+                        .or_(死!("invalid selector {}: unclosed quote", self.source));
+                    let value = &self.rest()[1..1 + len];
+                    self.pos += len + 2;
+                    value
+                }
+                _ => self.ident(),
+            },
+        };
+        self.skip_whitespace();
+        if self.eat("]").not() {
+            即死!(
+                "invalid selector {}: expected ] at {}",
+                self.source,
+                self.pos
+            );
+        }
+        AttrSel { name, op, value }
+    }
+
+    fn compound(&mut self) -> Compound<'s> {
+        let mut compound = Compound::default();
+        if self.eat("*").not() && self.rest().starts_with(is_ident_char) {
+            compound.name = Some(self.name());
+        }
+        loop {
+            if self.eat(".") {
+                compound.classes.push(self.ident());
+            } else if self.eat("#") {
+                compound.id = Some(self.ident());
+            } else if self.eat("[") {
+                compound.attrs.push(self.attr());
+            } else {
+                return compound;
+            }
+        }
+    }
+}
+
+impl<'s> Selector<'s> {
+    pub fn parse(source: &'s str) -> Self {
+        let mut parser = Parser { source, pos: 0 };
+        let mut alternatives = vec![Vec::new()];
+        parser.skip_whitespace();
+        while parser.rest().is_empty().not() {
+            let combinator = if parser.eat(",") {
+                alternatives.push(Vec::new());
+                parser.skip_whitespace();
+                continue;
+            } else if parser.eat(">") {
+                parser.skip_whitespace();
+                Combinator::Child
+            } else {
+                Combinator::Descendant
+            };
+            let start = parser.pos;
+            let compound = parser.compound();
+            if parser.pos == start {
+                即死!("invalid selector {source}: unexpected character at {start}");
+            }
+            alternatives
+                .last_mut()
+                .or_(死!())
+                .push((combinator, compound));
+            parser.skip_whitespace();
+        }
+        if alternatives.iter().any(Vec::is_empty) {
+            即死!("invalid selector {source}: empty alternative");
+        }
+        Selector { alternatives }
+    }
+
+    /// Matches `tag`, whose ancestors are given innermost first
+    pub fn matches<'src>(&self, tag: &Tag<'src>, ancestors: &[&Tag<'src>]) -> bool {
+        self.alternatives.iter().any(|steps| {
+            let Some((_, last)) = steps.last() else {
+                return false;
+            };
+            last.matches(tag) && Self::matches_ancestors(steps, ancestors)
+        })
+    }
+
+    /// `steps` ends with the step that is already matched; its combinator tells
+    /// how the step before it relates to the ancestors
+    fn matches_ancestors(steps: &[(Combinator, Compound<'s>)], ancestors: &[&Tag]) -> bool {
+        let Some(((combinator, _), rest)) = steps.split_last() else {
+            return true;
+        };
+        let Some((_, prev)) = rest.last() else {
+            return true;
+        };
+        match combinator {
+            Combinator::Child => match ancestors.split_first() {
+                Some((parent, ancestors)) => {
+                    prev.matches(parent) && Self::matches_ancestors(rest, ancestors)
+                }
+                None => false,
+            },
+            Combinator::Descendant => (0..ancestors.len()).any(|i| {
+                prev.matches(ancestors[i]) && Self::matches_ancestors(rest, &ancestors[i + 1..])
+            }),
+        }
+    }
+}
+
+impl Name<'_> {
+    fn get_attr<'src>(&self, tag: &Tag<'src>) -> Option<&'src str> {
+        let Some(prefix) = self.prefix else {
+            return tag.get_attr(self.local);
+        };
+        let uri = known_uri(prefix).or_(死!());
+        // HTML-ish files might use the conventional prefix without declaring it
+        tag.get_attr(&format!("{{{uri}}}{}", self.local))
+            .or_else(|| tag.get_attr(&format!("{prefix}:{}", self.local)))
+    }
+}
+
+impl Compound<'_> {
+    fn matches(&self, tag: &Tag) -> bool {
+        if tag.name.is_empty() {
+            return false; // the document root isn't an element
+        }
+        if let Some(name) = &self.name {
+            if tag.local_name() != name.local {
+                return false;
+            }
+            // sloppy OPFs might use the conventional prefix without declaring it,
+            // or no prefix and no namespace at all
+            if let Some(prefix) = name.prefix
+                && tag.namespace() != known_uri(prefix)
+                && (tag.namespace().is_none() && ["", prefix].contains(&split_qname(tag.name).0))
+                    .not()
+            {
+                return false;
+            }
+        }
+        if let Some(id) = self.id
+            && tag.id() != Some(id)
+        {
+            return false;
+        }
+        if self.classes.iter().any(|class| tag.has_class(class).not()) {
+            return false;
+        }
+        self.attrs.iter().all(|attr| {
+            let value = attr.name.get_attr(tag);
+            match attr.op {
+                AttrOp::Exists => value.is_some(),
+                AttrOp::Equals => value == Some(attr.value),
+                AttrOp::Includes => value
+                    .unwrap_or("")
+                    .split_ascii_whitespace()
+                    .any(|v| v == attr.value),
+            }
+        })
+    }
+}
+
+#[test]
+fn test_parse_selector() {
+    let selector = Selector::parse("body > p.calibre3");
+    let steps = &selector.alternatives[0];
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[1].0, Combinator::Child);
+    assert_eq!(steps[1].1.classes, ["calibre3"]);
+
+    let selector = Selector::parse(" nav[epub|type=toc]   a ");
+    let steps = &selector.alternatives[0];
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[1].0, Combinator::Descendant);
+    let attr = &steps[0].1.attrs[0];
+    assert_eq!(attr.name.prefix, Some("epub"));
+    assert_eq!(attr.name.local, "type");
+    assert_eq!(attr.value, "toc");
+
+    let selector = Selector::parse(r#"*#id1.a.b[title~='x y']>opf|item"#);
+    let steps = &selector.alternatives[0];
+    assert_eq!(steps[0].1.name, None);
+    assert_eq!(steps[0].1.id, Some("id1"));
+    assert_eq!(steps[0].1.classes, ["a", "b"]);
+    assert_eq!(steps[0].1.attrs[0].op, AttrOp::Includes);
+    assert_eq!(steps[0].1.attrs[0].value, "x y");
+    assert_eq!(steps[1].0, Combinator::Child);
+
+    let selector = Selector::parse("img, p > svg");
+    assert_eq!(selector.alternatives.len(), 2);
+    assert_eq!(selector.alternatives[1].len(), 2);
+
+    // an undeclared conventional prefix
+    let dom = super::dom::Dom::parse("", "<metadata><dc:title>題</dc:title></metadata>");
+    assert_eq!(dom.select_first("dc|title").unwrap().inner(), "題");
+    assert!(dom.select_first("opf|title").is_none());
+    // no namespace at all
+    let dom = super::dom::Dom::parse("", "<metadata><title>題</title></metadata>");
+    assert_eq!(dom.select_first("dc|title").unwrap().inner(), "題");
+    let xhtml = r#"<html xmlns="http://www.w3.org/1999/xhtml"><title>題</title></html>"#;
+    let dom = super::dom::Dom::parse("", xhtml);
+    assert!(dom.select_first("dc|title").is_none());
+}
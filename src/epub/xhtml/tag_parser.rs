@@ -2,7 +2,7 @@ use std::ops::{Not, Range};
 
 use crate::error::{OrDie, 即死, 死};
 
-use super::{TType, Tag};
+use super::{TType, Tag, namespace::Scope, report_repair};
 
 fn is_attr_name_char(ch: u8) -> bool {
     ![b' ', b'\t', b'\n', b'\r', b'=', b'>', b'/', b'\'', b'"'].contains(&ch)
@@ -14,45 +14,85 @@ fn is_whitespace(ch: u8) -> bool {
 
 fn consume_while(source: &str, predicate: impl Fn(u8) -> bool) -> usize {
     let mut pos = 0;
-    while predicate(source.as_bytes()[pos]) {
+    while pos < source.len() && predicate(source.as_bytes()[pos]) {
         pos += 1;
     }
     pos
 }
 
-fn parse_quotes(source: &str) -> Range<usize> {
-    let start = source.find(['"', '\'']).or_(死!());
+/// The span of the first quoted string, or `None` if there is none or it is unterminated
+fn parse_quotes(source: &str) -> Option<Range<usize>> {
+    let start = source.find(['"', '\''])?;
     let quotation_mark = source.as_bytes()[start];
     let mut pos = start + 1;
     while source[pos..].is_empty().not() {
-        pos += source[pos..].find(quotation_mark as char).or_(死!());
+        pos += source[pos..].find(quotation_mark as char)?;
         if source.as_bytes()[pos - 1] != b'\\' {
-            return start..pos + 1;
+            return Some(start..pos + 1);
         } else {
             pos += 1;
         }
     }
-    即死!("unexpected EOF")
+    None
 }
 
 #[test]
 fn test_parse_quotes() {
     //assert!(parse_quotes(r#""#));
     //assert!(parse_quotes(r#"""#));
-    assert_eq!(parse_quotes(r#""""#), 0..2);
-    assert_eq!(parse_quotes(r#"a"b"c"#), 1..4);
-    assert_eq!(parse_quotes(r#"''"#), 0..2);
-    assert_eq!(parse_quotes(r#"a'b'c"#), 1..4);
+    assert_eq!(parse_quotes(r#""""#), Some(0..2));
+    assert_eq!(parse_quotes(r#"a"b"c"#), Some(1..4));
+    assert_eq!(parse_quotes(r#"''"#), Some(0..2));
+    assert_eq!(parse_quotes(r#"a'b'c"#), Some(1..4));
     //assert!(parse_quotes(r#"a'b"c"#));
     //assert!(parse_quotes(r#"a"b'c"#));
-    assert_eq!(parse_quotes(r#"a"b\""c"#), 1..6);
-    assert_eq!(parse_quotes(r#"a"あ"c"#), 1..6);
-    assert_eq!(parse_quotes(r#""fuga">noniin"#), 0..6);
+    assert_eq!(parse_quotes(r#"a"b\""c"#), Some(1..6));
+    assert_eq!(parse_quotes(r#"a"あ"c"#), Some(1..6));
+    assert_eq!(parse_quotes(r#""fuga">noniin"#), Some(0..6));
+}
+
+fn is_tag_start(source: &str) -> bool {
+    match source.as_bytes() {
+        [b'/', ch, ..] => ch.is_ascii_alphabetic() || *ch >= 0x80,
+        [b'!' | b'?', ..] => true,
+        [ch, ..] => ch.is_ascii_alphabetic() || *ch >= 0x80,
+        [] => false,
+    }
 }
 
-pub fn parse_tag(source: &str, offset: usize) -> Option<Tag> {
+/// Parses the next tag after `offset` in the source of `root`
+pub fn parse_tag<'src>(root: &Tag<'src>, offset: usize) -> Option<Tag<'src>> {
+    let Tag {
+        source,
+        entry,
+        lenient,
+        ..
+    } = *root;
     // find starting <
-    let start = source[offset..].find('<').map(|s| offset + s)?;
+    let mut start = source[offset..].find('<').map(|s| offset + s)?;
+    if lenient {
+        // in HTML, a < that can't start a tag is just text
+        while is_tag_start(&source[start + 1..]).not() {
+            report_repair(root, start, "stray < in text");
+            start = source[start + 1..].find('<').map(|s| start + 1 + s)?;
+        }
+    }
+    if source[start..].starts_with("<!--") {
+        let end = source[start..]
+            .find("-->")
+            .or_(死!(at: (root, start), "unterminated comment"))
+            + start;
+        return Some(Tag {
+            name: "!--",
+            source,
+            entry,
+            span: start..end + "-->".len(),
+            before_text: &source[offset..start],
+            kind: TType::SelfClosing,
+            lenient,
+            scope: Scope::default(),
+        });
+    }
     let mut pos = start + 1;
 
     // check for closing tag marker
@@ -64,7 +104,7 @@ pub fn parse_tag(source: &str, offset: usize) -> Option<Tag> {
     // parse tag name
     let tag_name_end = source[pos..]
         .find([' ', '/', '\t', '\n', '\r', '>'])
-        .or_(死!("malformed tag name"));
+        .or_(死!(at: (root, start), "malformed tag name"));
     let tag_name = &source[pos..pos + tag_name_end];
     pos += tag_name_end;
 
@@ -72,92 +112,168 @@ pub fn parse_tag(source: &str, offset: usize) -> Option<Tag> {
     loop {
         pos += source[pos..]
             .find(['>', '"', '\''])
-            .or_(死!("cannot find tag end"));
+            .or_(死!(at: (root, start), "cannot find tag end"));
         if source.as_bytes()[pos] == b'>' {
             break;
         }
-        let quote = parse_quotes(&source[pos..]);
+        let quote = parse_quotes(&source[pos..]).or_(死!(at: (root, pos), "unterminated quotes"));
         pos += quote.end;
     }
 
     // check for self-closing tag marker
-    let self_closing_tag = source.as_bytes()[pos - 1] == b'/';
+    let mut self_closing_tag = source.as_bytes()[pos - 1] == b'/';
     pos += 1;
 
+    // doctypes and processing instructions have no end tags
+    if tag_name.starts_with(['!', '?']) {
+        self_closing_tag = true;
+    }
+
     Some(Tag {
         name: tag_name,
         source,
+        entry,
         span: start..pos,
         before_text: &source[offset..start],
         kind: match (closing_tag, self_closing_tag) {
             (false, false) => TType::Opening,
             (true, false) => TType::Closing,
             (false, true) => TType::SelfClosing,
-            (true, true) => 即死!("mixed closing marks"),
+            (true, true) if lenient => {
+                report_repair(root, start, "mixed closing marks");
+                TType::Closing
+            }
+            (true, true) => 即死!(at: (root, start), "mixed closing marks"),
         },
+        lenient,
+        scope: Scope::default(), // filled in by the iterator that knows the context
     })
 }
 
 #[test]
 fn test_parse_tag() {
-    assert_eq!(parse_tag("<hoge>", 0).unwrap().span, 0..6);
-    assert_eq!(parse_tag("<hoge/>", 0).unwrap().span, 0..7);
-    assert_eq!(parse_tag("<hoge />", 0).unwrap().span, 0..8);
-    assert_eq!(parse_tag("<hoge>", 0).unwrap().name, "hoge");
-    assert_eq!(parse_tag("<hoge/>", 0).unwrap().name, "hoge");
-    assert_eq!(parse_tag("<hoge />", 0).unwrap().name, "hoge");
-    assert_eq!(parse_tag("<hoge>after hoge", 0).unwrap().span, 0..6);
+    assert_eq!(parse_tag(&Tag::root("<hoge>"), 0).unwrap().span, 0..6);
+    assert_eq!(parse_tag(&Tag::root("<hoge/>"), 0).unwrap().span, 0..7);
+    assert_eq!(parse_tag(&Tag::root("<hoge />"), 0).unwrap().span, 0..8);
+    assert_eq!(parse_tag(&Tag::root("<hoge>"), 0).unwrap().name, "hoge");
+    assert_eq!(parse_tag(&Tag::root("<hoge/>"), 0).unwrap().name, "hoge");
+    assert_eq!(parse_tag(&Tag::root("<hoge />"), 0).unwrap().name, "hoge");
+    assert_eq!(
+        parse_tag(&Tag::root("<hoge>after hoge"), 0).unwrap().span,
+        0..6
+    );
     assert_eq!(
-        parse_tag(r#"<hoge param="fuga">noniin"#, 0).unwrap().span,
+        parse_tag(&Tag::root(r#"<hoge param="fuga">noniin"#), 0)
+            .unwrap()
+            .span,
         0..19
     );
     assert_eq!(
-        parse_tag(r#"<hoge param="fu>ga">noniin"#, 0).unwrap().span,
+        parse_tag(&Tag::root(r#"<hoge param="fu>ga">noniin"#), 0)
+            .unwrap()
+            .span,
         0..20
     );
     assert_eq!(
-        parse_tag(r#"<hoge param="fu\"ga">juu"#, 0).unwrap().span,
+        parse_tag(&Tag::root(r#"<hoge param="fu\"ga">juu"#), 0)
+            .unwrap()
+            .span,
         0..21
     );
-    assert_eq!(parse_tag(r#"<hoge param="あ">juu"#, 0).unwrap().span, 0..18);
+    assert_eq!(
+        parse_tag(&Tag::root(r#"<hoge param="あ">juu"#), 0)
+            .unwrap()
+            .span,
+        0..18
+    );
 
-    let self_closing = parse_tag(r#"<hoge param="fuga" />jooh"#, 0).unwrap();
+    let self_closing = parse_tag(&Tag::root(r#"<hoge param="fuga" />jooh"#), 0).unwrap();
     assert_eq!(self_closing.span, 0..21);
     assert_eq!(self_closing.kind, TType::SelfClosing);
 
-    let closing = parse_tag("</hoge>juuh", 0).unwrap();
+    let closing = parse_tag(&Tag::root("</hoge>juuh"), 0).unwrap();
     assert_eq!(closing.span, 0..7);
     assert_eq!(closing.kind, TType::Closing);
 
-    let あ = parse_tag(r#"<あ>juu"#, 0).unwrap();
+    let あ = parse_tag(&Tag::root(r#"<あ>juu"#), 0).unwrap();
     assert_eq!(あ.span, 0..5);
+
+    let xml_decl = parse_tag(&Tag::root("<?xml version='1.0'?>"), 0).unwrap();
+    assert_eq!(xml_decl.kind, TType::SelfClosing);
+    let doctype = parse_tag(&Tag::root("<!DOCTYPE html>"), 0).unwrap();
+    assert_eq!(doctype.kind, TType::SelfClosing);
+    let comment = parse_tag(&Tag::root("<!-- <hoge> --><fuga>"), 0).unwrap();
+    assert_eq!(comment.name, "!--");
+    assert_eq!(comment.span, 0..15);
 }
 
-pub fn parse_attr<'src>(source: &'src str, target_attr: &str) -> Option<&'src str> {
-    let source = &source[1..source.len() - 1]; // remove < and >
-    let mut pos = 0;
-    pos += consume_while(&source[pos..], is_attr_name_char);
-    while source[pos..].is_empty().not() {
-        pos += consume_while(&source[pos..], is_whitespace);
-        let attr_name_end = consume_while(&source[pos..], is_attr_name_char);
-        let attr_name = &source[pos..pos + attr_name_end];
-        pos += attr_name_end;
-        pos += consume_while(&source[pos..], is_whitespace);
-        let attr_val = if source.as_bytes()[pos] == b'=' {
-            pos += 1;
-            let mut span = parse_quotes(&source[pos..]);
-            span.start += pos;
-            span.end += pos;
-            pos = span.end;
-            &source[span.start + 1..span.end - 1]
-        } else {
-            attr_name
-        };
-        if attr_name == target_attr {
-            return Some(attr_val);
+#[test]
+fn test_parse_tag_lenient() {
+    let lenient = |source| Tag {
+        lenient: true,
+        ..Tag::root(source)
+    };
+    let tag = parse_tag(&lenient("a < b <hoge>"), 0).unwrap();
+    assert_eq!(tag.name, "hoge");
+    assert_eq!(tag.before_text, "a < b ");
+    assert_eq!(parse_tag(&lenient("1 < 2"), 0), None);
+}
+
+/// Iterates the (name, value) pairs of the attributes of a tag
+pub struct AttrIter<'src> {
+    source: &'src str,
+    pos: usize,
+}
+
+impl<'src> Iterator for AttrIter<'src> {
+    type Item = (&'src str, &'src str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let source = self.source;
+        let mut pos = self.pos;
+        loop {
+            pos += consume_while(&source[pos..], |ch| is_whitespace(ch) || ch == b'/');
+            if source[pos..].is_empty() {
+                self.pos = pos;
+                return None;
+            }
+            let attr_name_end = consume_while(&source[pos..], is_attr_name_char);
+            if attr_name_end == 0 {
+                pos += 1; // skip over garbage
+                continue;
+            }
+            let attr_name = &source[pos..pos + attr_name_end];
+            pos += attr_name_end;
+            pos += consume_while(&source[pos..], is_whitespace);
+            let attr_val = if source.as_bytes().get(pos) == Some(&b'=') {
+                pos += 1;
+                let mut span = parse_quotes(&source[pos..]).or_(死!("unterminated quotes"));
+                span.start += pos;
+                span.end += pos;
+                pos = span.end;
+                &source[span.start + 1..span.end - 1]
+            } else {
+                attr_name
+            };
+            self.pos = pos;
+            return Some((attr_name, attr_val));
         }
     }
-    None
+}
+
+pub fn parse_attrs(source: &str) -> AttrIter<'_> {
+    let Some(source) = source.strip_prefix('<').and_then(|s| s.strip_suffix('>')) else {
+        return AttrIter { source: "", pos: 0 }; // the root or a synthesized tag
+    };
+    let source = source.strip_prefix('/').unwrap_or(source);
+    let pos = consume_while(source, is_attr_name_char); // skip the tag name
+    AttrIter { source, pos }
+}
+
+pub fn parse_attr<'src>(source: &'src str, target_attr: &str) -> Option<&'src str> {
+    parse_attrs(source)
+        .find(|&(attr_name, _)| attr_name == target_attr)
+        .map(|(_, attr_val)| attr_val)
 }
 
 #[test]
@@ -170,4 +286,10 @@ fn test_parse_attr() {
     assert_eq!(parse_attr(source, "ee"), None);
     assert_eq!(parse_attr(source, "ff"), Some("ff"));
     assert_eq!(parse_attr(source, "gg"), Some("hh"));
+    let source = r#"<img src="a.png" alt=""/>"#;
+    assert_eq!(parse_attr(source, "alt"), Some(""));
+    assert_eq!(parse_attr(source, "class"), None);
+    assert_eq!(parse_attr("<hoge ff>", "ff"), Some("ff"));
+    assert_eq!(parse_attr("</hoge>", "hoge"), None);
+    assert_eq!(parse_attr("", "hoge"), None);
 }
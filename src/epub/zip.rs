@@ -66,10 +66,14 @@ impl<'a> FileIter<'a> {
         let uncompressed_size = u32::from_le_bytes(header.uncompressed_size) as usize;
         self.file.seek_relative(compressed_size as i64).or_(死!());
 
+        // images are often stored as they are, as they don't compress any further
+        let stored = u16::from_le_bytes(header.compression_method) == 0;
+
         Some(DeflatedFile {
             name: filename,
             range,
             size: uncompressed_size,
+            stored,
         })
     }
 }
@@ -87,15 +91,23 @@ pub struct DeflatedFile {
     pub name: String,
     range: Range<u64>,
     size: usize,
+    stored: bool, // not compressed
 }
 
 impl DeflatedFile {
-    pub fn extract_string(&self, file: &mut File) -> String {
+    pub fn extract_bytes(&self, file: &File) -> Vec<u8> {
         let len = (self.range.end - self.range.start) as usize;
         let mut deflate_bytes = vec![0; len];
         file.read_exact_at(&mut deflate_bytes, self.range.start)
             .or_(死!());
-        let contents = decompress_to_vec_with_limit(&deflate_bytes, self.size).or_(死!());
+        if self.stored {
+            return deflate_bytes;
+        }
+        decompress_to_vec_with_limit(&deflate_bytes, self.size).or_(死!())
+    }
+
+    pub fn extract_string(&self, file: &mut File) -> String {
+        let contents = self.extract_bytes(file);
         String::from_utf8(contents).or_(死!())
     }
 }
@@ -1,15 +1,87 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::Not};
+
+/// Snippets of markup in error messages show this many bytes around the position
+const SNIPPET_CONTEXT: usize = 40;
+
+/// A position in an archive entry, with a snippet of the surrounding markup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub entry: String,
+    pub line: usize, // 1-based
+    pub col: usize,  // 1-based, in chars
+    snippet: String, // the line around the position
+    caret: usize,    // the position in the snippet, in terminal columns
+}
+
+/// Japanese text is mostly full-width, so the caret under the snippet has to account for that
+fn display_width(text: &str) -> usize {
+    let is_wide = |ch: char| {
+        matches!(ch as u32,
+            0x1100..=0x115F | 0x2E80..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6 | 0x20000..=0x3FFFD)
+    };
+    text.chars().map(|ch| if is_wide(ch) { 2 } else { 1 }).sum()
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.entry, self.line, self.col)
+    }
+}
+
+/// Locates a byte position in the text of an archive entry. `None` if the text isn't one.
+pub fn locate(entry: &str, text: &str, pos: usize) -> Option<Location> {
+    if entry.is_empty() || text.is_char_boundary(pos).not() {
+        return None;
+    }
+    let line_start = text[..pos].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    let line_end = text[pos..]
+        .find('\n')
+        .map(|idx| pos + idx)
+        .unwrap_or(text.len());
+    let line = text[..pos].matches('\n').count() + 1;
+    let col = text[line_start..pos].chars().count() + 1;
+
+    let mut snippet_start = pos.saturating_sub(SNIPPET_CONTEXT).max(line_start);
+    while text.is_char_boundary(snippet_start).not() {
+        snippet_start += 1;
+    }
+    let mut snippet_end = (pos + SNIPPET_CONTEXT).min(line_end);
+    while text.is_char_boundary(snippet_end).not() {
+        snippet_end -= 1;
+    }
+    Some(Location {
+        entry: entry.to_owned(),
+        line,
+        col,
+        snippet: text[snippet_start..snippet_end].to_owned(),
+        caret: display_width(&text[snippet_start..pos]),
+    })
+}
+
+/// Things in parsed sources that errors can point to
+pub trait Locate {
+    fn location(&self) -> Option<Location>;
+}
+
+impl<T: Locate> Locate for &T {
+    fn location(&self) -> Option<Location> {
+        (*self).location()
+    }
+}
 
 #[must_use]
-pub struct EndMsg<M> {
+pub struct EndMsg<M, L = fn() -> Option<Location>> {
     pub callback: M,
     pub file: &'static str,
     pub line: u32,
+    pub locator: L, // lazy, as locating is only needed when dying
 }
 
-impl<M> EndMsg<M>
+impl<M, L> EndMsg<M, L>
 where
     M: FnOnce() -> String,
+    L: FnOnce() -> Option<Location>,
 {
     pub fn end_with(self, causing_err: impl Display) -> ! {
         eprintln!(
@@ -21,14 +93,38 @@ where
             (self.callback)(),
             causing_err
         );
+        if let Some(location) = (self.locator)() {
+            eprintln!("  --> {location}");
+            eprintln!("   | {}", location.snippet);
+            eprintln!("   | {}^", " ".repeat(location.caret));
+        }
         std::process::exit(2)
     }
 
+    /// Points the message to a spot in the parsed source
+    pub fn at<L2>(self, locator: L2) -> EndMsg<M, L2>
+    where
+        L2: FnOnce() -> Option<Location>,
+    {
+        EndMsg {
+            callback: self.callback,
+            file: self.file,
+            line: self.line,
+            locator,
+        }
+    }
+}
+
+impl<M> EndMsg<M>
+where
+    M: FnOnce() -> String,
+{
     pub fn new(callback: M, file: &'static str, line: u32) -> Self {
         Self {
             file,
             line,
             callback,
+            locator: || None,
         }
     }
 }
@@ -40,6 +136,9 @@ macro_rules! 即死 {
     ($fmt:literal $(, $args:expr)*) => {
         crate::error::EndMsg::new(|| format!($fmt, $($args),*), file!(), line!()).end_with("")
     };
+    (at: $spot:expr, $fmt:literal $(, $args:expr)*) => {
+        crate::error::死!(at: $spot, $fmt $(, $args)*).end_with("")
+    };
 }
 macro_rules! 死 {
     () => {
@@ -48,23 +147,29 @@ macro_rules! 死 {
     ($fmt:literal $(, $args:expr)*) => {
         crate::error::EndMsg::new(|| format!($fmt, $($args),*), file!(), line!())
     };
+    (at: $spot:expr, $fmt:literal $(, $args:expr)*) => {
+        crate::error::EndMsg::new(|| format!($fmt, $($args),*), file!(), line!())
+            .at(|| crate::error::Locate::location(&$spot))
+    };
 }
 pub(crate) use 即死;
 pub(crate) use 死;
 
 pub trait OrDie<T> {
-    fn or_<M>(self, end_msg: EndMsg<M>) -> T
+    fn or_<M, L>(self, end_msg: EndMsg<M, L>) -> T
     where
-        M: FnOnce() -> String;
+        M: FnOnce() -> String,
+        L: FnOnce() -> Option<Location>;
 }
 
 impl<T, E> OrDie<T> for Result<T, E>
 where
     E: Display,
 {
-    fn or_<M>(self, end_msg: EndMsg<M>) -> T
+    fn or_<M, L>(self, end_msg: EndMsg<M, L>) -> T
     where
         M: FnOnce() -> String,
+        L: FnOnce() -> Option<Location>,
     {
         match self {
             Ok(t) => t,
@@ -74,9 +179,10 @@ where
 }
 
 impl<T> OrDie<T> for Option<T> {
-    fn or_<M>(self, end_msg: EndMsg<M>) -> T
+    fn or_<M, L>(self, end_msg: EndMsg<M, L>) -> T
     where
         M: FnOnce() -> String,
+        L: FnOnce() -> Option<Location>,
     {
         match self {
             Some(t) => t,
@@ -84,3 +190,16 @@ impl<T> OrDie<T> for Option<T> {
         }
     }
 }
+
+#[test]
+fn test_locate() {
+    let entry = "OEBPS/text/test_locate.xhtml";
+    let text = "<html>\n<body>\n  <p>テキスト<blink>x</blink></p>\n</body>";
+    let pos = text.find("<blink>").unwrap();
+    let location = locate(entry, text, pos).unwrap();
+    assert_eq!(location.to_string(), "OEBPS/text/test_locate.xhtml:3:10");
+    assert_eq!(location.snippet, "  <p>テキスト<blink>x</blink></p>");
+    assert_eq!(location.caret, 13);
+
+    assert_eq!(locate("", "<p>not an entry</p>", 0), None);
+}
@@ -0,0 +1,893 @@
+use std::{collections::HashMap, fs::File, io::Write, ops::Not, path::Path};
+
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+use crate::SEP;
+use crate::epub::is_private_use;
+use crate::error::{OrDie, 死};
+use crate::glyph::{self, Bitmap};
+
+/// How many bytes at the start of the font file the obfuscation covers
+const IDPF_OBFUSCATED: usize = 1040;
+const ADOBE_OBFUSCATED: usize = 1024;
+
+/// The side of the bitmap outlines are drawn on for the perceptual hash
+const RASTER_SIZE: usize = 64;
+
+/// How many line segments a quadratic curve is flattened into for drawing
+const CURVE_STEPS: usize = 8;
+
+/// Composite glyphs nested deeper than this are broken
+const MAX_COMPONENT_DEPTH: usize = 8;
+
+/// The font obfuscation of EPUB: the start of the font file is XORed with a key
+/// derived from an identifier of the book
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Obfuscation {
+    Idpf([u8; 20]),  // the SHA-1 of the unique identifier
+    Adobe([u8; 16]), // the UUID of the urn:uuid: identifier
+}
+
+impl Obfuscation {
+    /// From the algorithm of an entry of encryption.xml and the identifiers of the book,
+    /// the unique identifier first. Other algorithms than the two obfuscations are DRM.
+    pub fn new(algorithm: &str, identifiers: &[&str]) -> Option<Self> {
+        match algorithm {
+            "http://www.idpf.org/2008/embedding" => {
+                let unique_identifier: String = identifiers
+                    .first()?
+                    .chars()
+                    .filter(|ch| matches!(ch, ' ' | '\t' | '\r' | '\n').not())
+                    .collect();
+                let key = sha1(unique_identifier.as_bytes());
+                Some(Obfuscation::Idpf(key))
+            }
+            "http://ns.adobe.com/pdf/enc#RC" => {
+                let uuid = identifiers
+                    .iter()
+                    .find_map(|identifier| identifier.trim().strip_prefix("urn:uuid:"))?;
+                let hex: String = uuid.chars().filter(|&ch| ch != '-').collect();
+                let mut key = [0; 16];
+                for (idx, byte) in key.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(hex.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+                }
+                Some(Obfuscation::Adobe(key))
+            }
+            _ => None,
+        }
+    }
+
+    /// Obfuscating twice is the same as de-obfuscating
+    pub fn apply(&self, bytes: &mut [u8]) {
+        let (key, len): (&[u8], _) = match self {
+            Obfuscation::Idpf(key) => (key, IDPF_OBFUSCATED),
+            Obfuscation::Adobe(key) => (key, ADOBE_OBFUSCATED),
+        };
+        for (byte, key) in bytes.iter_mut().take(len).zip(key.iter().cycle()) {
+            *byte ^= key;
+        }
+    }
+}
+
+/// The SHA-1 digest, which the IDPF obfuscation takes its key from
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    // padded with a 1 bit, zeros, and the length in bits, to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks_exact(64) {
+        let mut words = [0u32; 80];
+        for (idx, word) in block.chunks_exact(4).enumerate() {
+            words[idx] = u32::from_be_bytes(word.try_into().expect("4 bytes"));
+        }
+        for idx in 16..80 {
+            words[idx] = (words[idx - 3] ^ words[idx - 8] ^ words[idx - 14] ^ words[idx - 16])
+                .rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (idx, &word) in words.iter().enumerate() {
+            let (f, k) = match idx {
+                0..20 => ((b & c) | (b.not() & d), 0x5A827999),
+                20..40 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6u32),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn i16_at(data: &[u8], pos: usize) -> Option<i16> {
+    u16_at(data, pos).map(|n| n as i16)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// The tables of an OpenType or TrueType font, from a plain, a collection (the first font)
+/// or a WOFF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Font {
+    tables: HashMap<[u8; 4], Vec<u8>>,
+}
+
+/// A point of a TrueType outline; the off-curve points are the controls of quadratic curves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub on_curve: bool,
+}
+
+/// The outline of a glyph, in font units with y upwards
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outline {
+    pub contours: Vec<Vec<Point>>,
+    pub units_per_em: f32,
+    pub top: f32, // of the em box, from the ascender and descender of the font
+}
+
+/// A private use character of the book that an embedded font has a glyph for
+#[derive(Debug, Clone, PartialEq)]
+pub struct FontGlyph {
+    pub ch: char,
+    pub font: String, // the path in the archive
+    pub family: Option<String>,
+    pub glyph_id: u16,
+    pub name: Option<String>,     // from the post table
+    pub outline: Option<Outline>, // of TrueType glyphs; CFF outlines aren't read
+}
+
+impl Font {
+    pub fn parse(bytes: &[u8]) -> Option<Font> {
+        match bytes.get(..4)? {
+            b"wOFF" => parse_woff(bytes),
+            b"ttcf" => parse_sfnt(bytes, u32_at(bytes, 12)? as usize),
+            b"\0\x01\0\0" | b"OTTO" | b"true" => parse_sfnt(bytes, 0),
+            _ => None,
+        }
+    }
+
+    fn table(&self, tag: &[u8; 4]) -> Option<&[u8]> {
+        self.tables.get(tag).map(Vec::as_slice)
+    }
+
+    /// The full name of the font, or the family name
+    pub fn family(&self) -> Option<String> {
+        let name = self.table(b"name")?;
+        let count = u16_at(name, 2)? as usize;
+        let storage = u16_at(name, 4)? as usize;
+        let mut names = HashMap::new();
+        for record in (0..count).map(|idx| 6 + idx * 12) {
+            let platform = u16_at(name, record)?;
+            let name_id = u16_at(name, record + 6)?;
+            let len = u16_at(name, record + 8)? as usize;
+            let start = storage + u16_at(name, record + 10)? as usize;
+            let Some(bytes) = name.get(start..start + len) else {
+                continue;
+            };
+            let text = match platform {
+                0 | 3 => {
+                    let units: Vec<_> = bytes
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                        .collect();
+                    String::from_utf16(&units).ok()
+                }
+                _ => bytes
+                    .is_ascii()
+                    .then(|| String::from_utf8_lossy(bytes).into_owned()),
+            };
+            if let Some(text) = text {
+                names.entry(name_id).or_insert(text);
+            }
+        }
+        names.remove(&4).or_else(|| names.remove(&1))
+    }
+
+    /// The glyphs of the characters, from the Unicode subtable of the cmap table
+    pub fn char_map(&self) -> HashMap<char, u16> {
+        let mut chars = HashMap::new();
+        let Some(cmap) = self.table(b"cmap") else {
+            return chars;
+        };
+        let subtables = (0..u16_at(cmap, 2).unwrap_or(0) as usize).filter_map(|idx| {
+            let record = 4 + idx * 8;
+            let platform = u16_at(cmap, record)?;
+            let encoding = u16_at(cmap, record + 2)?;
+            let offset = u32_at(cmap, record + 4)? as usize;
+            let format = u16_at(cmap, offset)?;
+            // full Unicode first, then the BMP
+            let rank = match (platform, encoding, format) {
+                (3, 10, 12) | (0, _, 12) => 0,
+                (3, 1, 4) | (0, _, 4) => 1,
+                _ => return None,
+            };
+            Some((rank, offset, format))
+        });
+        let Some((_, offset, format)) = subtables.min_by_key(|&(rank, ..)| rank) else {
+            return chars;
+        };
+        let subtable = &cmap[offset..];
+        match format {
+            12 => read_cmap_12(subtable, &mut chars),
+            _ => read_cmap_4(subtable, &mut chars),
+        };
+        chars
+    }
+
+    /// The name of a glyph, from a version 2 post table; the standard Macintosh names
+    /// (of the Latin letters etc.) aren't of interest for gaiji
+    pub fn glyph_name(&self, glyph_id: u16) -> Option<String> {
+        let post = self.table(b"post")?;
+        if u32_at(post, 0)? != 0x0002_0000 {
+            return None;
+        }
+        let n_glyphs = u16_at(post, 32)?;
+        if glyph_id >= n_glyphs {
+            return None;
+        }
+        let index = (u16_at(post, 34 + glyph_id as usize * 2)? as usize).checked_sub(258)?;
+        let mut pos = 34 + n_glyphs as usize * 2;
+        for _ in 0..index {
+            pos += 1 + *post.get(pos)? as usize;
+        }
+        let len = *post.get(pos)? as usize;
+        let name = post.get(pos + 1..pos + 1 + len)?;
+        Some(String::from_utf8_lossy(name).into_owned())
+    }
+
+    /// The outline of a TrueType glyph, with the components of composite glyphs resolved
+    pub fn outline(&self, glyph_id: u16) -> Option<Outline> {
+        let head = self.table(b"head")?;
+        let units_per_em = u16_at(head, 18)? as f32;
+        let (ascender, descender) = self
+            .table(b"hhea")
+            .and_then(|hhea| Some((i16_at(hhea, 4)? as f32, i16_at(hhea, 6)? as f32)))
+            .filter(|(ascender, descender)| ascender > descender)
+            .unwrap_or((units_per_em * 0.88, units_per_em * -0.12));
+        let mut contours = Vec::new();
+        self.read_glyph(glyph_id, [1.0, 0.0, 0.0, 1.0, 0.0, 0.0], 0, &mut contours)?;
+        contours.is_empty().not().then_some(Outline {
+            contours,
+            units_per_em,
+            top: units_per_em * ascender / (ascender - descender),
+        })
+    }
+
+    /// Adds the contours of a glyph, transformed by the 2x2 matrix and offset of a component
+    fn read_glyph(
+        &self,
+        glyph_id: u16,
+        transform: [f32; 6],
+        depth: usize,
+        contours: &mut Vec<Vec<Point>>,
+    ) -> Option<()> {
+        if depth > MAX_COMPONENT_DEPTH {
+            return None;
+        }
+        let head = self.table(b"head")?;
+        let loca = self.table(b"loca")?;
+        let glyf = self.table(b"glyf")?;
+        let idx = glyph_id as usize;
+        let (start, end) = match i16_at(head, 50)? {
+            0 => (
+                u16_at(loca, idx * 2)? as usize * 2,
+                u16_at(loca, idx * 2 + 2)? as usize * 2,
+            ),
+            _ => (
+                u32_at(loca, idx * 4)? as usize,
+                u32_at(loca, idx * 4 + 4)? as usize,
+            ),
+        };
+        let data = glyf.get(start..end)?;
+        if data.is_empty() {
+            return Some(()); // e.g. a space
+        }
+        let [a, b, c, d, dx, dy] = transform;
+        let transformed = |x: f32, y: f32, on_curve| Point {
+            x: a * x + c * y + dx,
+            y: b * x + d * y + dy,
+            on_curve,
+        };
+        let n_contours = i16_at(data, 0)?;
+        if n_contours >= 0 {
+            for contour in read_simple_glyph(data, n_contours as usize)? {
+                let contour = contour
+                    .into_iter()
+                    .map(|point| transformed(point.x, point.y, point.on_curve))
+                    .collect();
+                contours.push(contour);
+            }
+            return Some(());
+        }
+
+        const ARGS_ARE_WORDS: u16 = 0x0001;
+        const ARGS_ARE_XY: u16 = 0x0002;
+        const SCALE: u16 = 0x0008;
+        const MORE_COMPONENTS: u16 = 0x0020;
+        const XY_SCALE: u16 = 0x0040;
+        const TWO_BY_TWO: u16 = 0x0080;
+        let f2dot14 = |pos| i16_at(data, pos).map(|n| n as f32 / 16384.0);
+        let mut pos = 10;
+        loop {
+            let flags = u16_at(data, pos)?;
+            let component = u16_at(data, pos + 2)?;
+            pos += 4;
+            let (arg1, arg2) = if flags & ARGS_ARE_WORDS != 0 {
+                pos += 4;
+                (i16_at(data, pos - 4)? as f32, i16_at(data, pos - 2)? as f32)
+            } else {
+                pos += 2;
+                (
+                    *data.get(pos - 2)? as i8 as f32,
+                    *data.get(pos - 1)? as i8 as f32,
+                )
+            };
+            // components aligned by matching points are rare enough to be drawn in place
+            let (x, y) = if flags & ARGS_ARE_XY != 0 {
+                (arg1, arg2)
+            } else {
+                (0.0, 0.0)
+            };
+            let scale = if flags & SCALE != 0 {
+                pos += 2;
+                let scale = f2dot14(pos - 2)?;
+                [scale, 0.0, 0.0, scale]
+            } else if flags & XY_SCALE != 0 {
+                pos += 4;
+                [f2dot14(pos - 4)?, 0.0, 0.0, f2dot14(pos - 2)?]
+            } else if flags & TWO_BY_TWO != 0 {
+                pos += 8;
+                [f2dot14(pos - 8)?, f2dot14(pos - 6)?, f2dot14(pos - 4)?, f2dot14(pos - 2)?]
+            } else {
+                [1.0, 0.0, 0.0, 1.0]
+            };
+            let [sa, sb, sc, sd] = scale;
+            let offset = transformed(x, y, true);
+            let component_transform = [
+                a * sa + c * sb,
+                b * sa + d * sb,
+                a * sc + c * sd,
+                b * sc + d * sd,
+                offset.x,
+                offset.y,
+            ];
+            self.read_glyph(component, component_transform, depth + 1, contours)?;
+            if flags & MORE_COMPONENTS == 0 {
+                return Some(());
+            }
+        }
+    }
+}
+
+fn parse_sfnt(bytes: &[u8], offset: usize) -> Option<Font> {
+    let n_tables = u16_at(bytes, offset + 4)? as usize;
+    let mut tables = HashMap::new();
+    for record in (0..n_tables).map(|idx| offset + 12 + idx * 16) {
+        let tag = bytes.get(record..record + 4)?.try_into().ok()?;
+        let start = u32_at(bytes, record + 8)? as usize;
+        let len = u32_at(bytes, record + 12)? as usize;
+        tables.insert(tag, bytes.get(start..start + len)?.to_vec());
+    }
+    Some(Font { tables })
+}
+
+fn parse_woff(bytes: &[u8]) -> Option<Font> {
+    let n_tables = u16_at(bytes, 12)? as usize;
+    let mut tables = HashMap::new();
+    for record in (0..n_tables).map(|idx| 44 + idx * 20) {
+        let tag = bytes.get(record..record + 4)?.try_into().ok()?;
+        let start = u32_at(bytes, record + 4)? as usize;
+        let compressed_len = u32_at(bytes, record + 8)? as usize;
+        let len = u32_at(bytes, record + 12)? as usize;
+        let data = bytes.get(start..start + compressed_len)?;
+        let data = if compressed_len < len {
+            decompress_to_vec_zlib(data).ok()?
+        } else {
+            data.to_vec()
+        };
+        tables.insert(tag, data);
+    }
+    Some(Font { tables })
+}
+
+/// Segmented coverage of the full Unicode range
+fn read_cmap_12(subtable: &[u8], chars: &mut HashMap<char, u16>) -> Option<()> {
+    let n_groups = u32_at(subtable, 12)? as usize;
+    for group in (0..n_groups).map(|idx| 16 + idx * 12) {
+        let start = u32_at(subtable, group)?;
+        let end = u32_at(subtable, group + 4)?;
+        let start_glyph = u32_at(subtable, group + 8)?;
+        for (code, glyph_id) in (start..=end).zip(start_glyph..) {
+            if let Some(ch) = char::from_u32(code) {
+                chars.insert(ch, glyph_id as u16);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Segment mapping to delta values, of the BMP
+fn read_cmap_4(subtable: &[u8], chars: &mut HashMap<char, u16>) -> Option<()> {
+    let n_segments = u16_at(subtable, 6)? as usize / 2;
+    let ends = 14;
+    let starts = ends + n_segments * 2 + 2;
+    let deltas = starts + n_segments * 2;
+    let range_offsets = deltas + n_segments * 2;
+    for segment in 0..n_segments {
+        let start = u16_at(subtable, starts + segment * 2)?;
+        let end = u16_at(subtable, ends + segment * 2)?;
+        let delta = u16_at(subtable, deltas + segment * 2)?;
+        let range_offset_pos = range_offsets + segment * 2;
+        let range_offset = u16_at(subtable, range_offset_pos)? as usize;
+        for code in start..=end.min(0xFFFE) {
+            let glyph_id = if range_offset == 0 {
+                code.wrapping_add(delta)
+            } else {
+                let pos = range_offset_pos + range_offset + (code - start) as usize * 2;
+                match u16_at(subtable, pos)? {
+                    0 => 0,
+                    glyph_id => glyph_id.wrapping_add(delta),
+                }
+            };
+            if let Some(ch) = char::from_u32(code as u32)
+                && glyph_id != 0
+            {
+                chars.insert(ch, glyph_id);
+            }
+        }
+    }
+    Some(())
+}
+
+fn read_simple_glyph(data: &[u8], n_contours: usize) -> Option<Vec<Vec<Point>>> {
+    const ON_CURVE: u8 = 0x01;
+    const X_SHORT: u8 = 0x02;
+    const Y_SHORT: u8 = 0x04;
+    const REPEAT: u8 = 0x08;
+    const X_SAME_OR_POSITIVE: u8 = 0x10;
+    const Y_SAME_OR_POSITIVE: u8 = 0x20;
+
+    let ends: Vec<_> = (0..n_contours)
+        .map(|idx| u16_at(data, 10 + idx * 2).map(|end| end as usize))
+        .collect::<Option<_>>()?;
+    let n_points = ends.last().map_or(0, |end| end + 1);
+    let instructions_len = u16_at(data, 10 + n_contours * 2)? as usize;
+    let mut pos = 12 + n_contours * 2 + instructions_len;
+
+    let mut flags = Vec::with_capacity(n_points);
+    while flags.len() < n_points {
+        let flag = *data.get(pos)?;
+        pos += 1;
+        flags.push(flag);
+        if flag & REPEAT != 0 {
+            let repeat = *data.get(pos)?;
+            pos += 1;
+            flags.extend(std::iter::repeat_n(flag, repeat as usize));
+        }
+    }
+    flags.truncate(n_points);
+
+    let mut read_coordinates = |short, same_or_positive| {
+        let mut value = 0i32;
+        let mut coordinates = Vec::with_capacity(n_points);
+        for &flag in &flags {
+            if flag & short != 0 {
+                let delta = *data.get(pos)? as i32;
+                pos += 1;
+                value += if flag & same_or_positive != 0 {
+                    delta
+                } else {
+                    -delta
+                };
+            } else if flag & same_or_positive == 0 {
+                value += i16_at(data, pos)? as i32;
+                pos += 2;
+            }
+            coordinates.push(value as f32);
+        }
+        Some(coordinates)
+    };
+    let xs = read_coordinates(X_SHORT, X_SAME_OR_POSITIVE)?;
+    let ys = read_coordinates(Y_SHORT, Y_SAME_OR_POSITIVE)?;
+
+    let mut contours = Vec::with_capacity(n_contours);
+    let mut start = 0;
+    for end in ends {
+        let contour = (start..=end)
+            .map(|idx| {
+                Some(Point {
+                    x: *xs.get(idx)?,
+                    y: *ys.get(idx)?,
+                    on_curve: flags.get(idx)? & ON_CURVE != 0,
+                })
+            })
+            .collect::<Option<_>>()?;
+        contours.push(contour);
+        start = end + 1;
+    }
+    Some(contours)
+}
+
+/// A closed contour as a start point and quadratic segments, `(control, end)`; two
+/// off-curve points in a row imply an on-curve point halfway between them
+type Segments = ((f32, f32), Vec<(Option<(f32, f32)>, (f32, f32))>);
+
+fn segments(contour: &[Point]) -> Option<Segments> {
+    let midpoint = |a: &Point, b: &Point| ((a.x + b.x) / 2.0, (a.y + b.y) / 2.0);
+    let first = contour.first()?;
+    // start on a point on the curve
+    let (start, rotation) = match contour.iter().position(|point| point.on_curve) {
+        Some(idx) => ((contour[idx].x, contour[idx].y), idx + 1),
+        None => (midpoint(first, contour.last()?), 0),
+    };
+    let mut segments = Vec::new();
+    let mut control: Option<&Point> = None;
+    for point in contour[rotation..].iter().chain(&contour[..rotation]) {
+        match (control, point.on_curve) {
+            (None, true) => segments.push((None, (point.x, point.y))),
+            (None, false) => control = Some(point),
+            (Some(ctrl), true) => {
+                segments.push((Some((ctrl.x, ctrl.y)), (point.x, point.y)));
+                control = None;
+            }
+            (Some(ctrl), false) => {
+                segments.push((Some((ctrl.x, ctrl.y)), midpoint(ctrl, point)));
+                control = Some(point);
+            }
+        }
+    }
+    if let Some(ctrl) = control {
+        segments.push((Some((ctrl.x, ctrl.y)), start));
+    }
+    Some((start, segments))
+}
+
+impl Outline {
+    /// A standalone SVG of the glyph in its em box
+    pub fn to_svg(&self) -> String {
+        let mut path = String::new();
+        for (start, segments) in self.contours.iter().filter_map(|contour| segments(contour)) {
+            path.push_str(&format!("M{} {}", start.0, -start.1));
+            for (control, end) in segments {
+                match control {
+                    Some(control) => path.push_str(&format!(
+                        "Q{} {} {} {}",
+                        control.0, -control.1, end.0, -end.1
+                    )),
+                    None => path.push_str(&format!("L{} {}", end.0, -end.1)),
+                }
+            }
+            path.push('Z');
+        }
+        let (em, top) = (self.units_per_em, self.top);
+        format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 {} {em} {em}"><path d="{path}"/></svg>"#,
+            -top
+        )
+    }
+
+    /// Draws the glyph in its em box, for the perceptual hash
+    pub fn rasterize(&self) -> Bitmap {
+        let to_unit =
+            |(x, y): (f32, f32)| (x / self.units_per_em, (self.top - y) / self.units_per_em);
+        let polygons: Vec<_> = self
+            .contours
+            .iter()
+            .filter_map(|contour| segments(contour))
+            .map(|(start, segments)| {
+                let mut polygon = vec![to_unit(start)];
+                let mut from = start;
+                for (control, end) in segments {
+                    if let Some(control) = control {
+                        for step in 1..CURVE_STEPS {
+                            let t = step as f32 / CURVE_STEPS as f32;
+                            let along = |p0: f32, p1: f32, p2: f32| {
+                                (1.0 - t) * (1.0 - t) * p0 + 2.0 * (1.0 - t) * t * p1 + t * t * p2
+                            };
+                            polygon.push(to_unit((
+                                along(from.0, control.0, end.0),
+                                along(from.1, control.1, end.1),
+                            )));
+                        }
+                    }
+                    polygon.push(to_unit(end));
+                    from = end;
+                }
+                polygon
+            })
+            .collect();
+        glyph::rasterize(&polygons, RASTER_SIZE)
+    }
+}
+
+impl FontGlyph {
+    /// What the glyph name says the character is: `uni6F22`, `u2000B`, or several
+    /// characters joined by `_`, after the suffix of a variant like `.vert`
+    pub fn hint(&self) -> Option<String> {
+        let name = self.name.as_deref()?;
+        let base = name.split('.').next()?;
+        let mut hint = String::new();
+        for component in base.split('_') {
+            let codepoints = if let Some(hex) = component.strip_prefix("uni") {
+                if hex.is_empty() || hex.len() % 4 != 0 {
+                    return None;
+                }
+                (0..hex.len() / 4)
+                    .map(|idx| &hex[idx * 4..idx * 4 + 4])
+                    .collect()
+            } else if let Some(hex) = component.strip_prefix('u')
+                && (4..=6).contains(&hex.len())
+            {
+                vec![hex]
+            } else {
+                return None;
+            };
+            for hex in codepoints {
+                let ch = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)?;
+                if is_private_use(ch) {
+                    return None;
+                }
+                hint.push(ch);
+            }
+        }
+        Some(hint)
+    }
+}
+
+/// Finds the glyphs of the private use characters in the embedded fonts, from the first
+/// font that has each one
+pub fn find_glyphs(fonts: &[(String, Font)], chars: &[char]) -> Vec<FontGlyph> {
+    let char_maps: Vec<_> = fonts.iter().map(|(_, font)| font.char_map()).collect();
+    chars
+        .iter()
+        .filter_map(|&ch| {
+            let (idx, glyph_id) = char_maps
+                .iter()
+                .enumerate()
+                .find_map(|(idx, char_map)| Some((idx, *char_map.get(&ch)?)))?;
+            let (path, font) = &fonts[idx];
+            Some(FontGlyph {
+                ch,
+                font: path.clone(),
+                family: font.family(),
+                glyph_id,
+                name: font.glyph_name(glyph_id),
+                outline: font.outline(glyph_id),
+            })
+        })
+        .collect()
+}
+
+pub fn write_font_glyphs(glyphs: &[FontGlyph], fname: &Path) {
+    let mut file = File::create(fname).or_(死!());
+    for glyph in glyphs {
+        writeln!(
+            file,
+            "U+{:04X}{SEP}{}{SEP}{}{SEP}{}{SEP}{}{SEP}{}",
+            glyph.ch as u32,
+            glyph.font,
+            glyph.family.as_deref().unwrap_or(""),
+            glyph.glyph_id,
+            glyph.name.as_deref().unwrap_or(""),
+            glyph.hint().unwrap_or_default(),
+        )
+        .or_(死!());
+    }
+}
+
+#[cfg(test)]
+fn build_sfnt(tables: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut font = vec![0, 1, 0, 0];
+    font.extend((tables.len() as u16).to_be_bytes());
+    font.extend([0; 6]);
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, data) in tables {
+        font.extend(*tag);
+        font.extend([0; 4]);
+        font.extend((offset as u32).to_be_bytes());
+        font.extend((data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+    for (_, data) in tables {
+        font.extend(data);
+    }
+    font
+}
+
+#[test]
+fn test_font() {
+    let be16 = |values: &[i32]| -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|&value| (value as u16).to_be_bytes())
+            .collect()
+    };
+    let mut head = vec![0; 54];
+    head[18..20].copy_from_slice(&1000u16.to_be_bytes());
+    let mut hhea = vec![0; 36];
+    hhea[4..8].copy_from_slice(&be16(&[880, -120]));
+    // glyph 1 is a square, glyph 0 is empty
+    let mut glyf = be16(&[1, 100, 100, 900, 900, 3, 0]);
+    glyf.extend([1; 4]);
+    glyf.extend(be16(&[100, 800, 0, -800, 100, 0, 800, 0]));
+    let loca = be16(&[0, 0, glyf.len() as i32 / 2]);
+    let mut cmap = be16(&[0, 1, 3, 1, 0, 12]);
+    cmap.extend(be16(&[4, 32, 0, 4, 0, 0, 0]));
+    cmap.extend(be16(&[
+        0xE001,
+        0xFFFF,
+        0,
+        0xE001,
+        0xFFFF,
+        1 - 0xE001,
+        1,
+        0,
+        0,
+    ]));
+    let mut post = vec![0, 2, 0, 0];
+    post.extend([0; 28]);
+    post.extend(be16(&[2, 0, 258]));
+    post.extend(b"\x07uni5409");
+    let mut name = be16(&[0, 1, 18, 3, 1, 0x409, 4, 10, 0]);
+    name.extend(be16(
+        &"Gaiji".encode_utf16().map(i32::from).collect::<Vec<_>>(),
+    ));
+    let bytes = build_sfnt(&[
+        (b"cmap", cmap),
+        (b"glyf", glyf),
+        (b"head", head),
+        (b"hhea", hhea),
+        (b"loca", loca),
+        (b"name", name),
+        (b"post", post),
+    ]);
+
+    let font = Font::parse(&bytes).unwrap();
+    assert_eq!(font.family().as_deref(), Some("Gaiji"));
+    assert_eq!(font.char_map(), HashMap::from([('\u{E001}', 1)]));
+    let fonts = [("fonts/gaiji.ttf".to_owned(), font)];
+    let glyphs = find_glyphs(&fonts, &['\u{E001}', '\u{E002}']);
+    assert_eq!(glyphs.len(), 1);
+    let glyph = &glyphs[0];
+    assert_eq!(glyph.glyph_id, 1);
+    assert_eq!(glyph.name.as_deref(), Some("uni5409"));
+    assert_eq!(glyph.hint().as_deref(), Some("吉"));
+
+    let outline = glyph.outline.as_ref().unwrap();
+    assert_eq!(outline.top, 880.0);
+    assert!(outline.to_svg().contains(
+        r#"viewBox="0 -880 1000 1000"><path d="M100 -100L900 -100L900 -900L100 -900L100 -100Z"/>"#
+    ));
+    let bitmap = outline.rasterize();
+    let luma = |x: usize, y: usize| bitmap.luma[y * bitmap.width + x];
+    assert_eq!((luma(32, 32), luma(2, 32), luma(32, 60)), (0.0, 1.0, 1.0));
+
+    // the same tables in a WOFF file
+    let mut woff = b"wOFF".to_vec();
+    woff.extend([0; 8]);
+    woff.extend(be16(&[fonts[0].1.tables.len() as i32]));
+    woff.extend([0; 30]);
+    let mut tables: Vec<_> = fonts[0].1.tables.iter().collect();
+    tables.sort();
+    let mut offset = woff.len() + tables.len() * 20;
+    for (tag, data) in &tables {
+        woff.extend(*tag);
+        woff.extend((offset as u32).to_be_bytes());
+        woff.extend((data.len() as u32).to_be_bytes());
+        woff.extend((data.len() as u32).to_be_bytes());
+        woff.extend([0; 4]);
+        offset += data.len();
+    }
+    for (_, data) in &tables {
+        woff.extend(*data);
+    }
+    assert_eq!(Font::parse(&woff).as_ref(), Some(&fonts[0].1));
+}
+
+#[test]
+fn test_glyph_names() {
+    let hint = |name: &str| {
+        FontGlyph {
+            ch: '\u{E001}',
+            font: String::new(),
+            family: None,
+            glyph_id: 1,
+            name: Some(name.to_owned()),
+            outline: None,
+        }
+        .hint()
+    };
+    assert_eq!(hint("uni5409.vert").as_deref(), Some("吉"));
+    assert_eq!(hint("u2000B").as_deref(), Some("𠀋"));
+    assert_eq!(hint("uni845BDB40DD00"), None); // surrogates aren't characters
+    assert_eq!(hint("uni845B_u E0100"), None);
+    assert_eq!(hint("uni845B_uE0100").as_deref(), Some("葛\u{E0100}"));
+    assert_eq!(hint("uniE001"), None);
+    assert_eq!(hint("gid12"), None);
+
+    // the quadratic curves of an outline, with an implied point between the controls
+    let point = |x, y, on_curve| Point { x, y, on_curve };
+    let contour = [point(0.0, 0.0, true), point(10.0, 0.0, false), point(10.0, 10.0, false)];
+    assert_eq!(
+        segments(&contour),
+        Some((
+            (0.0, 0.0),
+            vec![(Some((10.0, 0.0)), (10.0, 5.0)), (Some((10.0, 10.0)), (0.0, 0.0))]
+        ))
+    );
+}
+
+#[test]
+fn test_sha1() {
+    let hex = |digest: [u8; 20]| digest.map(|byte| format!("{byte:02x}")).concat();
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+        hex(sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(
+        hex(sha1(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+}
+
+#[test]
+fn test_obfuscation() {
+    const IDPF: &str = "http://www.idpf.org/2008/embedding";
+    const ADOBE: &str = "http://ns.adobe.com/pdf/enc#RC";
+    let uuid = "urn:uuid:01234567-89ab-cdef-0123-456789abcdef";
+    assert_eq!(
+        Obfuscation::new(IDPF, &[" urn:uuid:0123\n"]),
+        Obfuscation::new(IDPF, &["urn:uuid:0123"])
+    );
+    let adobe = Obfuscation::new(ADOBE, &["isbn", uuid]).unwrap();
+    assert_eq!(
+        adobe,
+        Obfuscation::Adobe([
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef
+        ])
+    );
+    assert_eq!(Obfuscation::new(ADOBE, &["isbn"]), None);
+    assert_eq!(
+        Obfuscation::new("http://www.w3.org/2001/04/xmlenc#aes128-cbc", &[uuid]),
+        None
+    );
+
+    let font = vec![0xAA; 2000];
+    for obfuscation in [adobe, Obfuscation::new(IDPF, &[uuid]).unwrap()] {
+        let mut bytes = font.clone();
+        obfuscation.apply(&mut bytes);
+        assert_ne!(bytes[..1024], font[..1024]);
+        assert_eq!(bytes[1040..], font[1040..]);
+        obfuscation.apply(&mut bytes);
+        assert_eq!(bytes, font);
+    }
+}
@@ -1,29 +1,719 @@
-use std::{collections::HashMap, fs::File, io::Write, path::Path};
+use std::{collections::HashMap, fmt::Display, fs::File, io::Write, ops::Not, path::Path};
 
-use crate::error::{OrDie, 死};
+use crate::epub::{Epub, GaijiRef, Glyph};
+use crate::error::{OrDie, 即死, 死};
+use crate::font::FontGlyph;
+use crate::glyph::{self, Bitmap};
 
-pub fn read(fname: &Path) -> Option<HashMap<String, char>> {
+/// The replacement of gaiji that haven't been annotated yet
+pub const UNKNOWN: &str = "�";
+
+/// How many places of each gaiji the review sheet shows, and how many characters around them
+const REVIEW_PLACES: usize = 3;
+const REVIEW_CONTEXT: usize = 12;
+
+/// Suggestions from glyphs further than this (of 63 bits) are more likely wrong than right
+const MAX_SUGGESTION_DISTANCE: u32 = 10;
+
+/// Alt texts that only say that there's a gaiji
+const ALT_PLACEHOLDERS: &[&str] = &["外字", "gaiji", "〓", "■", "□", "*", "＊", "?", "？"];
+
+/// Alt texts longer than this are descriptions rather than the character
+const MAX_ALT_CHARS: usize = 8;
+
+/// Where a suggested replacement comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suggestion {
+    Alt,          // the alt or title text of the gaiji
+    GlyphName,    // the name of the glyph in an embedded font, like uni5409
+    Similar(u32), // an annotated glyph, at this distance
+}
+
+/// A gaiji of a book that has an image, with the hashes of its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub key: String,
+    pub hash: u64,          // of the decoded image (or the file), for exact matches
+    pub phash: Option<u64>, // of the glyph, for similar ones; if the image could be decoded
+}
+
+/// A gaiji in the database, by the hash of its image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbEntry {
+    pub replacement: String,
+    pub phash: Option<u64>,
+}
+
+/// A gaiji exported for review
+struct Unresolved<'a> {
+    fname: Option<String>, // gaiji in fonts have no image
+    key: &'a str,
+    replacement: &'a str,
+    suggestion: Option<Suggestion>,
+    contexts: &'a [(String, String)],
+}
+
+/// A 64-bit FNV-1a hash, which stays the same across books and runs
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The hash of the decoded pixels, so that the same glyph re-encoded by another publisher,
+/// e.g. with other compression or metadata, still matches
+fn bitmap_hash(bitmap: &Bitmap) -> u64 {
+    let mut bytes = Vec::with_capacity(8 + bitmap.luma.len());
+    bytes.extend_from_slice(&(bitmap.width as u32).to_le_bytes());
+    bytes.extend_from_slice(&(bitmap.height as u32).to_le_bytes());
+    bytes.extend(bitmap.luma.iter().map(|&luma| (luma * 255.0).round() as u8));
+    content_hash(&bytes)
+}
+
+/// Codepoints that can't be seen in gaiji.txt, like variation selectors (IVS) or ZWJ
+fn is_invisible(ch: char) -> bool {
+    ch.is_control()
+        || matches!(ch,
+            '\u{180B}'..='\u{180F}' | '\u{200B}'..='\u{200F}' | '\u{2060}'..='\u{2064}'
+            | '\u{FE00}'..='\u{FE0F}' | '\u{FEFF}' | '\u{E0100}'..='\u{E01EF}')
+}
+
+/// Escapes a replacement for gaiji.txt: `\\`, `\:` and `\u{E0100}` for invisible codepoints
+fn escape(replacement: &str) -> String {
+    let mut escaped = String::new();
+    for ch in replacement.chars() {
+        match ch {
+            '\\' | ':' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            ch if is_invisible(ch) => escaped.push_str(&format!("\\u{{{:X}}}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Reads an escaped replacement up to the next unescaped `:`, which starts another field.
+/// A lone `:` or `\\` is itself, as in the files from before the escapes.
+fn unescape<'a>(field: &'a str, error: &str) -> (String, Option<&'a str>) {
+    if field == "\\" {
+        return (field.to_owned(), None);
+    }
+    let mut replacement = String::new();
+    let mut rest = field;
+    if field == ":" || field.starts_with("::") {
+        replacement.push(':');
+        rest = &field[1..];
+    }
+    let mut chars = rest.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            ':' => {
+                let field = chars.as_str();
+                return (replacement, Some(field));
+            }
+            '\\' => match chars.next() {
+                Some('u') => {
+                    let (hex, rest) = chars
+                        .as_str()
+                        .strip_prefix('{')
+                        .and_then(|rest| rest.split_once('}'))
+                        .or_(死!("{error}: \\u should be followed by {{hex}}"));
+                    let ch = u32::from_str_radix(hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .or_(死!("{error}: invalid codepoint \\u{{{hex}}}"));
+                    replacement.push(ch);
+                    chars = rest.chars();
+                }
+                Some(ch) => replacement.push(ch),
+                None => 即死!("{error}: \\ at the end of the line"),
+            },
+            ch => replacement.push(ch),
+        }
+    }
+    if replacement.is_empty() {
+        即死!("{error}");
+    }
+    (replacement, None)
+}
+
+impl Suggestion {
+    fn parse(field: &str) -> Option<Self> {
+        match field {
+            "alt" => Some(Suggestion::Alt),
+            "name" => Some(Suggestion::GlyphName),
+            distance => distance.parse().ok().map(Suggestion::Similar),
+        }
+    }
+}
+
+impl Display for Suggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Suggestion::Alt => f.write_str("alt"),
+            Suggestion::GlyphName => f.write_str("name"),
+            Suggestion::Similar(distance) => write!(f, "{distance}"),
+        }
+    }
+}
+
+/// Reads the gaiji of a book, and which of them are suggestions.
+/// The replacements can be several characters, e.g. a kanji with a variation selector
+/// or an annotation like ［＃「木＋吉」］. Suggestions look like `key:replacement:distance`,
+/// or `key:replacement:alt` and `key:replacement:name` if they come from the alt text or
+/// the glyph name in a font; deleting the last field confirms them.
+pub fn read(fname: &Path) -> Option<(HashMap<String, String>, HashMap<String, Suggestion>)> {
     let Ok(file) = std::fs::read_to_string(fname) else {
         return None;
     };
     let mut gaiji = HashMap::new();
+    let mut suggested = HashMap::new();
     for line in file.lines() {
-        let (src, gaiji_ch) = line
+        let (key, replacement) = line
             .split_once(':')
             .or_(死!("Invalid gaiji file: should have : on every line"));
 
-        let gaiji_ch = gaiji_ch.parse().or_(死!(
-            "Invalid gaiji file: should have a single character (codepoint) after :"
-        ));
+        let (replacement, suggestion) = unescape(
+            replacement,
+            "Invalid gaiji file: should have a replacement after :",
+        );
+        if let Some(suggestion) = suggestion {
+            let suggestion = Suggestion::parse(suggestion).or_(死!(
+                "Invalid gaiji file: should have a distance or alt after the suggested replacement"
+            ));
+            suggested.insert(key.to_owned(), suggestion);
+        }
 
-        gaiji.insert(src.to_owned(), gaiji_ch);
+        gaiji.insert(key.to_owned(), replacement);
     }
-    Some(gaiji)
+    Some((gaiji, suggested))
 }
 
-pub fn write_gaiji(gaiji: &HashMap<String, char>, fname: &Path) {
+pub fn write_gaiji(
+    gaiji: &HashMap<String, String>,
+    suggested: &HashMap<String, Suggestion>,
+    fname: &Path,
+) {
     let mut file = File::create(fname).or_(死!());
-    for (src, &gaiji_ch) in gaiji {
-        writeln!(file, "{src}:{gaiji_ch}").or_(死!())
+    for (key, replacement) in gaiji {
+        let replacement = escape(replacement);
+        match suggested.get(key) {
+            Some(suggestion) => writeln!(file, "{key}:{replacement}:{suggestion}").or_(死!()),
+            None => writeln!(file, "{key}:{replacement}").or_(死!()),
+        }
+    }
+}
+
+/// Reads the library-wide gaiji database, which maps the hashes of the images to replacements,
+/// and keeps the perceptual hashes of the glyphs for suggesting similar ones
+pub fn read_db(fname: &Path) -> HashMap<u64, DbEntry> {
+    let Ok(file) = std::fs::read_to_string(fname) else {
+        return HashMap::new();
+    };
+    let mut db = HashMap::new();
+    for line in file.lines() {
+        let (hash, replacement) = line
+            .split_once(':')
+            .or_(死!("Invalid gaiji database: should have : on every line"));
+        let hash = u64::from_str_radix(hash, 16).or_(死!(
+            "Invalid gaiji database: should have a hexadecimal hash before :"
+        ));
+        let (replacement, phash) = unescape(
+            replacement,
+            "Invalid gaiji database: should have a replacement after :",
+        );
+        let phash = phash.map(|phash| {
+            u64::from_str_radix(phash, 16).or_(死!(
+                "Invalid gaiji database: should have a hexadecimal perceptual hash after the replacement"
+            ))
+        });
+        db.insert(hash, DbEntry { replacement, phash });
+    }
+    db
+}
+
+pub fn write_db(db: &HashMap<u64, DbEntry>, fname: &Path) {
+    let mut entries: Vec<_> = db.iter().collect();
+    entries.sort_by_key(|(hash, _)| **hash);
+    let mut file = File::create(fname).or_(死!());
+    for (hash, DbEntry { replacement, phash }) in entries {
+        let replacement = escape(replacement);
+        match phash {
+            Some(phash) => writeln!(file, "{hash:016x}:{replacement}:{phash:016x}").or_(死!()),
+            None => writeln!(file, "{hash:016x}:{replacement}").or_(死!()),
+        }
+    }
+}
+
+/// The image file of a gaiji, the markup of an svg drawing, or the outline of
+/// a private use character in an embedded font as svg
+fn glyph_file(epub: &Epub, gaiji: &GaijiRef, font_glyphs: &[FontGlyph]) -> Option<Vec<u8>> {
+    match gaiji.glyph {
+        Glyph::Image(_) => epub.extract(gaiji.path.as_deref()?),
+        Glyph::Svg(svg) => Some(svg.as_bytes().to_vec()),
+        Glyph::Char(ch) => {
+            let font_glyph = font_glyphs.iter().find(|font_glyph| font_glyph.ch == ch)?;
+            Some(font_glyph.outline.as_ref()?.to_svg().into_bytes())
+        }
     }
 }
+
+/// The gaiji of a book that have images (or outlines), with the hashes of their contents
+pub fn image_hashes(epub: &Epub, book_gaiji: &[GaijiRef], font_glyphs: &[FontGlyph]) -> Vec<Image> {
+    book_gaiji
+        .iter()
+        .filter_map(|gaiji| {
+            let bytes = glyph_file(epub, gaiji, font_glyphs)?;
+            let bitmap = match gaiji.glyph {
+                Glyph::Char(ch) => font_glyphs
+                    .iter()
+                    .find(|font_glyph| font_glyph.ch == ch)
+                    .and_then(|font_glyph| Some(font_glyph.outline.as_ref()?.rasterize())),
+                _ => glyph::decode(&bytes),
+            };
+            // images that aren't decoded, SVG, JPEG or GIF, are hashed as files
+            Some(Image {
+                key: gaiji.key.clone(),
+                hash: bitmap
+                    .as_ref()
+                    .map_or_else(|| content_hash(&bytes), bitmap_hash),
+                phash: bitmap.map(|bitmap| glyph::perceptual_hash(&bitmap)),
+            })
+        })
+        .collect()
+}
+
+/// Fills in the gaiji of a book that are known from the database.
+/// Known gaiji replace suggestions.
+pub fn inherit(
+    gaiji: &mut HashMap<String, String>,
+    suggested: &mut HashMap<String, Suggestion>,
+    db: &HashMap<u64, DbEntry>,
+    images: &[Image],
+) {
+    for Image { key, hash, .. } in images {
+        let Some(entry) = db.get(hash) else {
+            continue;
+        };
+        let replacement = gaiji
+            .entry(key.clone())
+            .or_insert_with(|| entry.replacement.clone());
+        if replacement == UNKNOWN || suggested.remove(key).is_some() {
+            replacement.clone_from(&entry.replacement);
+        }
+    }
+}
+
+/// Suggests what the alt or title texts of the unannotated gaiji say they are.
+/// Returns how many were suggested.
+pub fn suggest_alt(
+    gaiji: &mut HashMap<String, String>,
+    suggested: &mut HashMap<String, Suggestion>,
+    book_gaiji: &[GaijiRef],
+) -> usize {
+    let mut n_suggested = 0;
+    for GaijiRef { key, alt, .. } in book_gaiji {
+        if let Some(alt) = alt.as_deref().filter(|&alt| is_telling(alt)) {
+            n_suggested += suggest_text(gaiji, suggested, key, alt, Suggestion::Alt) as usize;
+        }
+    }
+    n_suggested
+}
+
+/// Suggests what the names of the glyphs of private use characters in the embedded fonts
+/// say they are. Returns how many were suggested.
+pub fn suggest_glyph_names(
+    gaiji: &mut HashMap<String, String>,
+    suggested: &mut HashMap<String, Suggestion>,
+    font_glyphs: &[FontGlyph],
+) -> usize {
+    let mut n_suggested = 0;
+    for font_glyph in font_glyphs {
+        if let Some(hint) = font_glyph.hint() {
+            let key = format!("U+{:04X}", font_glyph.ch as u32);
+            n_suggested +=
+                suggest_text(gaiji, suggested, &key, &hint, Suggestion::GlyphName) as usize;
+        }
+    }
+    n_suggested
+}
+
+/// Suggests a replacement for a gaiji that isn't annotated yet
+fn suggest_text(
+    gaiji: &mut HashMap<String, String>,
+    suggested: &mut HashMap<String, Suggestion>,
+    key: &str,
+    text: &str,
+    suggestion: Suggestion,
+) -> bool {
+    let is_unknown = gaiji
+        .get(key)
+        .is_none_or(|replacement| replacement == UNKNOWN);
+    if is_unknown {
+        gaiji.insert(key.to_owned(), text.to_owned());
+        suggested.insert(key.to_owned(), suggestion);
+    }
+    is_unknown
+}
+
+/// Whether an alt text is that of a gaiji: the character itself or a placeholder,
+/// rather than a description or a file name
+pub fn is_gaiji_alt(alt: &str) -> bool {
+    let n_chars = alt.chars().count();
+    let is_name = n_chars > 1
+        && alt
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "_-.".contains(ch));
+    (1..=MAX_ALT_CHARS).contains(&n_chars) && is_name.not()
+}
+
+/// Whether an alt text is likely the character itself, rather than a placeholder,
+/// a description or a file name
+fn is_telling(alt: &str) -> bool {
+    let lowercase = alt.to_lowercase();
+    let is_placeholder = ALT_PLACEHOLDERS
+        .iter()
+        .any(|placeholder| lowercase.contains(placeholder));
+    is_gaiji_alt(alt) && is_placeholder.not()
+}
+
+/// Suggests the annotated glyph that looks the most like each unannotated one, from
+/// the database and the book itself. Returns how many were suggested.
+pub fn suggest(
+    gaiji: &mut HashMap<String, String>,
+    suggested: &mut HashMap<String, Suggestion>,
+    db: &HashMap<u64, DbEntry>,
+    images: &[Image],
+) -> usize {
+    let confirmed = |key: &String| {
+        let replacement = gaiji.get(key)?;
+        (replacement != UNKNOWN && suggested.contains_key(key).not()).then_some(replacement)
+    };
+    let annotated: Vec<_> = db
+        .values()
+        .filter_map(|entry| Some((entry.phash?, &entry.replacement)))
+        .chain(
+            images
+                .iter()
+                .filter_map(|image| Some((image.phash?, confirmed(&image.key)?))),
+        )
+        .collect();
+    let mut suggestions = Vec::new();
+    for Image { key, phash, .. } in images {
+        let is_unknown = gaiji
+            .get(key)
+            .is_none_or(|replacement| replacement == UNKNOWN);
+        let Some(phash) = phash.filter(|_| is_unknown) else {
+            continue;
+        };
+        let nearest = annotated
+            .iter()
+            .map(|&(annotated, replacement)| (glyph::distance(phash, annotated), replacement))
+            .min_by_key(|&(distance, _)| distance);
+        if let Some((distance, replacement)) = nearest
+            && distance <= MAX_SUGGESTION_DISTANCE
+        {
+            suggestions.push((key, replacement.clone(), distance));
+        }
+    }
+    for (key, replacement, distance) in &suggestions {
+        gaiji.insert(key.to_string(), replacement.clone());
+        suggested.insert(key.to_string(), Suggestion::Similar(*distance));
+    }
+    suggestions.len()
+}
+
+/// Adds the gaiji annotated for a book to the database, returning how many entries changed.
+/// The database wins in case of conflicts, as it has been checked against more books.
+/// Suggestions aren't added before they are confirmed.
+pub fn promote(
+    gaiji: &HashMap<String, String>,
+    suggested: &HashMap<String, Suggestion>,
+    db: &mut HashMap<u64, DbEntry>,
+    images: &[Image],
+) -> usize {
+    let mut promoted = 0;
+    for Image { key, hash, phash } in images {
+        let Some(replacement) = gaiji
+            .get(key)
+            .filter(|&replacement| replacement != UNKNOWN && suggested.contains_key(key).not())
+        else {
+            continue;
+        };
+        match db.get_mut(hash) {
+            None => {
+                db.insert(
+                    *hash,
+                    DbEntry {
+                        replacement: replacement.clone(),
+                        phash: *phash,
+                    },
+                );
+                promoted += 1;
+            }
+            Some(entry) if entry.replacement != *replacement => {
+                let db_replacement = &entry.replacement;
+                eprintln!(
+                    "Gaiji {key} is {replacement} in this book, but {db_replacement} in the database"
+                );
+            }
+            // entries from before the perceptual hashes
+            Some(entry) if entry.phash.is_none() && phash.is_some() => {
+                entry.phash = *phash;
+                promoted += 1;
+            }
+            Some(_) => (),
+        }
+    }
+    promoted
+}
+
+/// Writes the images of the gaiji that aren't annotated yet to the output directory as
+/// `gaiji_001.png` etc., and a review sheet, `gaiji.html`, that shows them in context.
+/// The numbers follow all the gaiji of the book, so they stay the same across runs.
+/// Suggested gaiji are unresolved until they are confirmed.
+pub fn export_unresolved(
+    epub: &Epub,
+    book_gaiji: &[GaijiRef],
+    font_glyphs: &[FontGlyph],
+    gaiji: &HashMap<String, String>,
+    suggested: &HashMap<String, Suggestion>,
+    output_path: &Path,
+) -> usize {
+    let contexts = epub.gaiji_contexts(REVIEW_CONTEXT);
+    let mut unresolved = Vec::new();
+    for (idx, found) in book_gaiji.iter().enumerate() {
+        let key = found.key.as_str();
+        let replacement = gaiji.get(key).map_or(UNKNOWN, String::as_str);
+        let suggestion = suggested.get(key).copied();
+        if replacement != UNKNOWN && suggestion.is_none() {
+            continue;
+        }
+        let ext = match found.glyph {
+            Glyph::Image(_) => found
+                .path
+                .as_deref()
+                .and_then(|path| Path::new(path).extension())
+                .and_then(|ext| ext.to_str())
+                .unwrap_or("img"),
+            Glyph::Svg(_) | Glyph::Char(_) => "svg",
+        };
+        let fname = match glyph_file(epub, found, font_glyphs) {
+            Some(bytes) => {
+                let fname = format!("gaiji_{:03}.{ext}", idx + 1);
+                std::fs::write(output_path.join(&fname), bytes).or_(死!());
+                Some(fname)
+            }
+            None if matches!(found.glyph, Glyph::Char(_)) => None,
+            None => {
+                eprintln!("Gaiji image {:?} isn't in the EPUB", found.path);
+                continue;
+            }
+        };
+        unresolved.push(Unresolved {
+            fname,
+            key,
+            replacement,
+            suggestion,
+            contexts: contexts.get(key).map_or(&[], Vec::as_slice),
+        });
+    }
+    if unresolved.is_empty().not() {
+        let file = File::create(output_path.join("gaiji.html")).or_(死!());
+        write_review(&unresolved, file);
+    }
+    unresolved.len()
+}
+
+/// A standalone HTML page. The keys and the text come from the XHTML as is, so they are
+/// already escaped.
+fn write_review(unresolved: &[Unresolved], mut file: impl Write) {
+    writeln!(
+        file,
+        r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+<meta charset="utf-8">
+<title>Gaiji review</title>
+<style>
+td {{ border-bottom: 1px solid #ccc; padding: 0.3em 1em; }}
+td.image img {{ height: 3em; }}
+td.context img {{ height: 1em; }}
+</style>
+</head>
+<body>
+<p>Annotate the gaiji by replacing {UNKNOWN} with the character (or text) after the key in gaiji.txt.
+Suggestions are confirmed by deleting the distance (or alt or name) after them.</p>
+<table>
+<tr><th>Image</th><th>Key</th><th>Replacement</th><th>Context</th></tr>"#
+    )
+    .or_(死!());
+    for Unresolved {
+        fname,
+        key,
+        replacement,
+        suggestion,
+        contexts,
+    } in unresolved
+    {
+        // as in gaiji.txt, so that invisible codepoints show
+        let replacement = escape(replacement)
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        let replacement = match suggestion {
+            Some(Suggestion::Alt) => {
+                format!("{replacement} <small>(suggested by the alt text)</small>")
+            }
+            Some(Suggestion::GlyphName) => {
+                format!("{replacement} <small>(suggested by the glyph name)</small>")
+            }
+            Some(Suggestion::Similar(distance)) => {
+                format!("{replacement} <small>(suggested, distance {distance})</small>")
+            }
+            None => replacement,
+        };
+        // private use characters without outlines show as whatever the fonts of the browser
+        // have for them
+        let glyph = match fname {
+            Some(fname) => format!(r#"<img src="{fname}">"#),
+            None => key
+                .strip_prefix("U+")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32)
+                .map_or_else(|| key.to_string(), String::from),
+        };
+        let contexts: Vec<_> = contexts
+            .iter()
+            .take(REVIEW_PLACES)
+            .map(|(before, after)| format!("{before}<mark>{glyph}</mark>{after}"))
+            .collect();
+        writeln!(
+            file,
+            r#"<tr><td class="image">{glyph}</td><td><code>{key}</code></td><td>{replacement}</td><td class="context">{}</td></tr>"#,
+            contexts.join("<br>")
+        )
+        .or_(死!());
+    }
+    writeln!(file, "</table>\n</body>\n</html>").or_(死!());
+}
+
+#[test]
+fn test_gaiji_db() {
+    assert_eq!(content_hash(b""), 0xcbf29ce484222325);
+    assert_eq!(content_hash(b"a"), 0xaf63dc4c8601ec8c);
+    // the same pixels, filtered differently
+    let unfiltered = crate::png::encode_row(2, 8, 0, &[], &[0, 10, 30]);
+    let filtered = crate::png::encode_row(2, 8, 0, &[], &[1, 10, 20]);
+    assert_ne!(content_hash(&unfiltered), content_hash(&filtered));
+    let decoded = |png| bitmap_hash(&glyph::decode(png).unwrap());
+    assert_eq!(decoded(&unfiltered), decoded(&filtered));
+
+    let image = |n, phash| Image {
+        key: format!("../image/g0{n}.png"),
+        hash: n,
+        phash,
+    };
+    let images = [
+        image(1, None),
+        image(2, Some(0xff00)),
+        image(3, None),
+        image(4, Some(0xff01)), // looks like 2
+        image(5, Some(0x00ff)),
+    ];
+    let entry = |replacement: &str| DbEntry {
+        replacement: replacement.to_owned(),
+        phash: None,
+    };
+    let mut db = HashMap::from([(1, entry("𠮷")), (3, entry("髙"))]);
+    let mut gaiji = HashMap::from([
+        ("../image/g02.png".to_owned(), "𩸽".to_owned()),
+        ("../image/g03.png".to_owned(), UNKNOWN.to_owned()),
+    ]);
+    let mut suggested = HashMap::new();
+    inherit(&mut gaiji, &mut suggested, &db, &images);
+    assert_eq!(gaiji["../image/g01.png"], "𠮷");
+    assert_eq!(gaiji["../image/g03.png"], "髙");
+
+    assert_eq!(suggest(&mut gaiji, &mut suggested, &db, &images), 1);
+    assert_eq!(gaiji["../image/g04.png"], "𩸽");
+    assert_eq!(suggested["../image/g04.png"], Suggestion::Similar(1));
+    assert!(gaiji.contains_key("../image/g05.png").not());
+
+    assert_eq!(promote(&gaiji, &suggested, &mut db, &images), 1);
+    assert_eq!(db[&2].replacement, "𩸽");
+    assert_eq!(db[&2].phash, Some(0xff00));
+    assert!(db.contains_key(&4).not());
+}
+
+#[test]
+fn test_gaiji_file() {
+    let svg = Glyph::Svg("<svg><path d=\"M0 0\"/></svg>")
+        .key()
+        .into_owned();
+    let gaiji = HashMap::from([
+        (svg.clone(), UNKNOWN.to_owned()),
+        ("U+E001".to_owned(), "𠮷".to_owned()),
+        ("../image/g01.png".to_owned(), "a:b".to_owned()),
+    ]);
+    let suggested = HashMap::from([("U+E001".to_owned(), Suggestion::Alt)]);
+    let fname = std::env::temp_dir().join(format!("gaiji-{}.txt", std::process::id()));
+    write_gaiji(&gaiji, &suggested, &fname);
+    let read_back = read(&fname);
+    std::fs::remove_file(&fname).unwrap();
+    assert_eq!(read_back, Some((gaiji, suggested)));
+}
+
+#[test]
+fn test_escapes() {
+    let field = |field: &'static str| unescape(field, "");
+    assert_eq!(field("𠮷"), ("𠮷".to_owned(), None));
+    assert_eq!(field("葛\\u{E0100}"), ("葛\u{E0100}".to_owned(), None));
+    assert_eq!(
+        field("［＃「木＋吉」］:3"),
+        ("［＃「木＋吉」］".to_owned(), Some("3"))
+    );
+    assert_eq!(field("a\\:b\\\\"), ("a:b\\".to_owned(), None));
+    // single characters from before the escapes
+    assert_eq!(field(":"), (":".to_owned(), None));
+    assert_eq!(field("::2"), (":".to_owned(), Some("2")));
+    assert_eq!(field("\\"), ("\\".to_owned(), None));
+
+    for replacement in ["葛\u{E0100}", "a:b\\c", "①\u{200D}②"] {
+        let escaped = escape(replacement);
+        assert_eq!(unescape(&escaped, ""), (replacement.to_owned(), None));
+    }
+    assert_eq!(escape("葛\u{E0100}"), "葛\\u{E0100}");
+}
+
+#[test]
+fn test_suggest_alt() {
+    let found = |key: &str, alt: Option<&str>| GaijiRef {
+        key: key.to_owned(),
+        glyph: Glyph::Char('\u{E001}'),
+        path: None,
+        alt: alt.map(str::to_owned),
+    };
+    let book_gaiji = [
+        found("U+E001", Some("𠮷")),
+        found("U+E002", Some("外字")),
+        found("U+E003", Some("gaiji_003")),
+        found("U+E004", Some("「木＋吉」、第3水準1-85-XX")),
+        found("U+E005", None),
+        found("U+E006", Some("髙")),
+    ];
+    let mut gaiji = HashMap::from([("U+E006".to_owned(), "高".to_owned())]);
+    let mut suggested = HashMap::new();
+    assert_eq!(suggest_alt(&mut gaiji, &mut suggested, &book_gaiji), 1);
+    assert_eq!(gaiji["U+E001"], "𠮷");
+    assert_eq!(suggested["U+E001"], Suggestion::Alt);
+    assert_eq!(gaiji["U+E006"], "高");
+    assert_eq!(gaiji.len(), 2);
+
+    assert_eq!(Suggestion::parse("alt"), Some(Suggestion::Alt));
+    assert_eq!(Suggestion::parse("name"), Some(Suggestion::GlyphName));
+    assert_eq!(Suggestion::parse("3"), Some(Suggestion::Similar(3)));
+    assert_eq!(Suggestion::parse("x"), None);
+}
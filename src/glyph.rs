@@ -0,0 +1,210 @@
+/// The side of the thumbnail the perceptual hash is computed from
+const THUMBNAIL: usize = 32;
+
+/// The low frequencies that make up the perceptual hash, per axis
+const FREQUENCIES: usize = 8;
+
+/// A decoded image as luminance, with transparency flattened onto white paper
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    pub luma: Vec<f32>, // 0.0 is black, 1.0 white
+}
+
+impl Bitmap {
+    /// From 8-bit samples of `channels` per pixel: gray, gray + alpha, RGB or RGBA
+    fn from_samples(width: usize, height: usize, samples: &[u8], channels: usize) -> Option<Self> {
+        if width == 0 || height == 0 || samples.len() < width * height * channels {
+            return None;
+        }
+        let luma = samples
+            .chunks_exact(channels)
+            .take(width * height)
+            .map(|px| {
+                let (value, alpha) = match *px {
+                    [l] => (l as f32, 255.0),
+                    [l, a] => (l as f32, a as f32),
+                    [r, g, b] => (
+                        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32,
+                        255.0,
+                    ),
+                    [r, g, b, a] => (
+                        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32,
+                        a as f32,
+                    ),
+                    _ => unreachable!("1 to 4 channels"),
+                };
+                let alpha = alpha / 255.0;
+                (value / 255.0) * alpha + (1.0 - alpha)
+            })
+            .collect();
+        Some(Bitmap {
+            width,
+            height,
+            luma,
+        })
+    }
+}
+
+/// Decodes a PNG gaiji; other image formats aren't hashed
+pub fn decode(bytes: &[u8]) -> Option<Bitmap> {
+    let png = crate::png::decode(bytes)?;
+    Bitmap::from_samples(png.width, png.height, &png.samples, png.channels)
+}
+
+/// Fills polygons, e.g. flattened font outlines, with the nonzero winding rule.
+/// The points are in the unit square with y downwards; 4x4 samples per pixel smooth the edges.
+pub fn rasterize(polygons: &[Vec<(f32, f32)>], size: usize) -> Bitmap {
+    const SAMPLES: usize = 4;
+    let n = (size * SAMPLES) as f32;
+    let mut coverage = vec![0u32; size * size];
+    let mut crossings = Vec::new();
+    for row in 0..size * SAMPLES {
+        let y = (row as f32 + 0.5) / n;
+        crossings.clear();
+        for polygon in polygons {
+            for (idx, &(x0, y0)) in polygon.iter().enumerate() {
+                let (x1, y1) = polygon[(idx + 1) % polygon.len()];
+                if (y0 <= y) != (y1 <= y) {
+                    let x = x0 + (y - y0) / (y1 - y0) * (x1 - x0);
+                    crossings.push((x, if y1 > y0 { 1 } else { -1 }));
+                }
+            }
+        }
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut winding = 0;
+        for pair in crossings.windows(2) {
+            winding += pair[0].1;
+            if winding == 0 {
+                continue;
+            }
+            // the sample columns whose centers are between the crossings
+            let start = (pair[0].0 * n - 0.5).ceil().max(0.0) as usize;
+            let end = ((pair[1].0 * n - 0.5).floor() + 1.0).clamp(0.0, n) as usize;
+            for col in start..end {
+                coverage[row / SAMPLES * size + col / SAMPLES] += 1;
+            }
+        }
+    }
+    let full = (SAMPLES * SAMPLES) as f32;
+    Bitmap {
+        width: size,
+        height: size,
+        luma: coverage.iter().map(|&n| 1.0 - n as f32 / full).collect(),
+    }
+}
+
+/// Shrinks the bitmap to a square thumbnail, averaging the pixels each thumbnail pixel covers
+fn thumbnail(bitmap: &Bitmap) -> Vec<f32> {
+    let span = |idx: usize, len: usize| {
+        let start = idx * len / THUMBNAIL;
+        let end = ((idx + 1) * len / THUMBNAIL).max(start + 1);
+        start..end
+    };
+    let mut thumbnail = Vec::with_capacity(THUMBNAIL * THUMBNAIL);
+    for y in 0..THUMBNAIL {
+        for x in 0..THUMBNAIL {
+            let (rows, cols) = (span(y, bitmap.height), span(x, bitmap.width));
+            let n = (rows.len() * cols.len()) as f32;
+            let sum: f32 = rows
+                .flat_map(|row| &bitmap.luma[row * bitmap.width..][cols.clone()])
+                .sum();
+            thumbnail.push(sum / n);
+        }
+    }
+    thumbnail
+}
+
+/// The lowest frequencies of the 2D DCT-II of a thumbnail, row by row
+fn low_frequencies(thumbnail: &[f32]) -> Vec<f32> {
+    let n = THUMBNAIL as f32;
+    let basis = |freq: usize, idx: usize| {
+        (std::f32::consts::PI * freq as f32 * (2 * idx + 1) as f32 / (2.0 * n)).cos()
+    };
+    // the rows first, then the columns
+    let mut rows = vec![0.0; THUMBNAIL * FREQUENCIES];
+    for y in 0..THUMBNAIL {
+        for u in 0..FREQUENCIES {
+            rows[y * FREQUENCIES + u] = (0..THUMBNAIL)
+                .map(|x| thumbnail[y * THUMBNAIL + x] * basis(u, x))
+                .sum();
+        }
+    }
+    let mut frequencies = Vec::with_capacity(FREQUENCIES * FREQUENCIES);
+    for v in 0..FREQUENCIES {
+        for u in 0..FREQUENCIES {
+            let coefficient = (0..THUMBNAIL)
+                .map(|y| rows[y * FREQUENCIES + u] * basis(v, y))
+                .sum();
+            frequencies.push(coefficient);
+        }
+    }
+    frequencies
+}
+
+/// A 64-bit perceptual hash (pHash) of the image: which of the low frequencies of its
+/// DCT are above their median. The same glyph at a different size or compression
+/// gets the same or a near hash.
+pub fn perceptual_hash(bitmap: &Bitmap) -> u64 {
+    let frequencies = low_frequencies(&thumbnail(bitmap));
+    // the DC term is just the overall brightness
+    let mut sorted = frequencies[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    frequencies[1..]
+        .iter()
+        .enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
+
+/// How many bits of the hashes differ, from 0 (the same) to 63
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+fn draw_glyph(size: usize, glyph: &[&str]) -> Bitmap {
+    let rows = glyph.len();
+    let cols = glyph[0].len();
+    let luma = (0..size * size)
+        .map(|idx| {
+            let (x, y) = (idx % size * cols / size, idx / size * rows / size);
+            if glyph[y].as_bytes()[x] == b'#' {
+                0.0
+            } else {
+                1.0
+            }
+        })
+        .collect();
+    Bitmap {
+        width: size,
+        height: size,
+        luma,
+    }
+}
+
+#[test]
+fn test_perceptual_hash() {
+    let tsuchi = [
+        "........", "...#....", ".#####..", "...#....", "...#....", "...#....", "#######.",
+        "........",
+    ];
+    let kuchi = [
+        "........", ".######.", ".#....#.", ".#....#.", ".#....#.", ".######.", "........",
+        "........",
+    ];
+    let small = perceptual_hash(&draw_glyph(24, &tsuchi));
+    let large = perceptual_hash(&draw_glyph(100, &tsuchi));
+    let other = perceptual_hash(&draw_glyph(100, &kuchi));
+    assert!(distance(small, large) <= 4, "{}", distance(small, large));
+    assert!(distance(large, other) > 16, "{}", distance(large, other));
+
+    // a PNG with alpha decodes onto white
+    let png = crate::png::encode_row(2, 8, 4, &[], &[0, 0, 255, 0, 0]);
+    let bitmap = decode(&png).unwrap();
+    assert_eq!((bitmap.width, bitmap.height), (2, 1));
+    assert_eq!(bitmap.luma, [0.0, 1.0]);
+    assert_eq!(decode(b"<svg/>"), None);
+}
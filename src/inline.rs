@@ -0,0 +1,98 @@
+use std::{collections::HashMap, io::ErrorKind, ops::Not, path::Path};
+
+use crate::{
+    SEP,
+    error::{OrDie, 即死, 死},
+};
+
+/// What to do with the text of an inline element
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    Keep,
+    Drop,
+    Wrap(String, String), // opening and closing markers
+    Annotate(String),     // an Aozora Bunko style annotation, e.g. ［＃「2」は上付き小文字］
+}
+
+/// Elements that have dedicated handling, and thus can't be configured
+const SPECIAL_ELEMENTS: &[&str] = &["ruby", "rb", "rt", "rp", "rtc", "br", "img", "td", "th"];
+
+/// Phrasing content elements whose text is kept by default
+const KEPT_ELEMENTS: &[&str] = &[
+    "span", "a", "em", "strong", "b", "i", "u", "s", "small", "big", "sup", "sub", "code", "bdo",
+    "bdi", "time", "abbr", "cite", "dfn", "kbd", "samp", "var", "mark", "data", "q", "ins", "del",
+    "label", "font", "tt", "strike", "nobr",
+];
+
+/// Phrasing content elements that are dropped by default
+const DROPPED_ELEMENTS: &[&str] = &["wbr"];
+
+/// The per-element policies of inline elements
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policies(HashMap<String, Policy>);
+
+impl Default for Policies {
+    fn default() -> Self {
+        let kept = KEPT_ELEMENTS
+            .iter()
+            .map(|&el| (el.to_owned(), Policy::Keep));
+        let dropped = DROPPED_ELEMENTS
+            .iter()
+            .map(|&el| (el.to_owned(), Policy::Drop));
+        Policies(kept.chain(dropped).collect())
+    }
+}
+
+impl Policies {
+    pub fn get(&self, element: &str) -> Option<&Policy> {
+        self.0.get(element)
+    }
+
+    /// Overrides the defaults with lines like `sup<TAB>drop` or `b<TAB>wrap<TAB>【<TAB>】`
+    pub fn parse(config: &str) -> Self {
+        let mut policies = Policies::default();
+        for line in config.lines().filter(|line| line.trim().is_empty().not()) {
+            let fields: Vec<_> = line.split(SEP).collect();
+            let policy = match fields[1..] {
+                ["keep"] => Policy::Keep,
+                ["drop"] => Policy::Drop,
+                ["wrap", open, close] => Policy::Wrap(open.to_owned(), close.to_owned()),
+                ["annotate", label] => Policy::Annotate(label.to_owned()),
+                _ => 即死!(
+                    "Invalid inline file: expected keep, drop, wrap<TAB>open<TAB>close or annotate<TAB>label after the element name, got {line:?}"
+                ),
+            };
+            let element = fields[0];
+            if SPECIAL_ELEMENTS.contains(&element) {
+                即死!("Invalid inline file: <{element}> can't be configured");
+            }
+            policies.0.insert(element.to_owned(), policy);
+        }
+        policies
+    }
+}
+
+/// Reads the policies, with the overrides from the config file if it exists
+pub fn read(fname: &Path) -> Policies {
+    match std::fs::read_to_string(fname) {
+        Ok(config) => Policies::parse(&config),
+        Err(err) if err.kind() == ErrorKind::NotFound => Policies::default(),
+        Err(err) => Err(err).or_(死!("failed to read {fname:?}")),
+    }
+}
+
+#[test]
+fn test_parse_policies() {
+    let policies = Policies::parse("sup\tdrop\nb\twrap\t【\t】\n\nblink\tannotate\t点滅\n");
+    assert_eq!(policies.get("sup"), Some(&Policy::Drop));
+    assert_eq!(policies.get("sub"), Some(&Policy::Keep));
+    assert_eq!(
+        policies.get("b"),
+        Some(&Policy::Wrap("【".to_owned(), "】".to_owned()))
+    );
+    assert_eq!(
+        policies.get("blink"),
+        Some(&Policy::Annotate("点滅".to_owned()))
+    );
+    assert_eq!(policies.get("marquee"), None);
+}
@@ -0,0 +1,180 @@
+/// Where the lines of a paragraph are placed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Align {
+    #[default]
+    Start,
+    Center,
+    End, // 地付き, e.g. signatures of letters
+}
+
+/// The indentation and alignment of a paragraph, in characters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub indent: u32,      // 字下げ of all the lines
+    pub text_indent: i32, // of the first line, relative to the others
+    pub end_indent: u32,  // 字上げ from the end of the lines
+    pub align: Align,
+    pub vertical: bool,
+}
+
+/// How the layout is rendered in the txt output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LayoutMode {
+    #[default]
+    Plain, // not at all
+    Spaces, // indentation as leading ideographic spaces
+    Aozora, // Aozora Bunko style annotations like ［＃２字下げ］
+}
+
+impl Align {
+    /// Parses the value of `text-align`; in vertical writing, right is the bottom
+    pub fn from_css(value: &str) -> Option<Self> {
+        let align = match value.trim() {
+            "start" | "left" | "justify" => Align::Start,
+            "center" => Align::Center,
+            "end" | "right" => Align::End,
+            _ => return None,
+        };
+        Some(align)
+    }
+}
+
+/// The number at the end of class names like `jisage_3` or `indent-2em`
+fn class_number(class: &str, prefix: &str) -> Option<u32> {
+    let number = class.strip_prefix(prefix)?.trim_start_matches(['_', '-']);
+    number.strip_suffix("em").unwrap_or(number).parse().ok()
+}
+
+impl Layout {
+    /// Recognizes class names like `jisage_3`, `jiage_1`, `chitsuki` or `align-end`
+    pub fn apply_class(&mut self, class: &str) {
+        let class = class.to_ascii_lowercase();
+        if let Some(n) = class_number(&class, "jisage").or(class_number(&class, "indent")) {
+            self.indent += n;
+        } else if let Some(n) = class_number(&class, "jiage").or(class_number(&class, "chiage")) {
+            self.align = Align::End;
+            self.end_indent += n;
+        } else {
+            self.align = match class.as_str() {
+                "chitsuki" | "chituki" | "align-end" | "align-right" | "text-right" | "right" => {
+                    Align::End
+                }
+                "align-center" | "text-center" | "center" | "centering" => Align::Center,
+                "align-start" | "align-left" | "text-left" => Align::Start,
+                _ => return,
+            };
+        }
+    }
+
+    /// The indentation of the first line
+    pub fn first_line_indent(&self) -> u32 {
+        self.indent.saturating_add_signed(self.text_indent)
+    }
+
+    /// The annotations that open and close the paragraph in Aozora Bunko style.
+    /// Paragraphs of several lines use the block form, ［＃ここから…］ … ［＃ここで…終わり］.
+    /// Centering has no Aozora Bunko notation, and an ordinary paragraph indent is
+    /// just leading spaces, so neither is annotated.
+    pub fn aozora(&self, multiline: bool) -> Option<(String, String)> {
+        let (first, rest) = (self.first_line_indent(), self.indent);
+        let (annotation, end) = match self.align {
+            Align::End if self.end_indent > 0 => (
+                format!("地から{}字上げ", zenkaku(self.end_indent)),
+                "字上げ",
+            ),
+            Align::End => ("地付き".to_owned(), "地付き"),
+            _ if rest == 0 => return None,
+            _ if first == rest => (format!("{}字下げ", zenkaku(rest)), "字下げ"),
+            _ => {
+                let annotation = format!(
+                    "ここから{}字下げ、折り返して{}字下げ",
+                    zenkaku(first),
+                    zenkaku(rest)
+                );
+                let end = "\n［＃ここで字下げ終わり］".to_owned();
+                return Some((format!("［＃{annotation}］\n"), end));
+            }
+        };
+        if multiline {
+            Some((
+                format!("［＃ここから{annotation}］\n"),
+                format!("\n［＃ここで{end}終わり］"),
+            ))
+        } else {
+            Some((format!("［＃{annotation}］"), String::new()))
+        }
+    }
+}
+
+/// Full-width digits, as in Aozora Bunko annotations
+fn zenkaku(n: u32) -> String {
+    n.to_string()
+        .chars()
+        .map(|ch| char::from_u32(ch as u32 - '0' as u32 + '０' as u32).unwrap_or(ch))
+        .collect()
+}
+
+impl LayoutMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        let mode = match mode {
+            "plain" => LayoutMode::Plain,
+            "spaces" => LayoutMode::Spaces,
+            "aozora" => LayoutMode::Aozora,
+            _ => return None,
+        };
+        Some(mode)
+    }
+}
+
+#[test]
+fn test_layout_classes() {
+    let mut layout = Layout::default();
+    layout.apply_class("jisage_3");
+    assert_eq!(layout.indent, 3);
+    layout.apply_class("calibre4");
+    assert_eq!(
+        layout,
+        Layout {
+            indent: 3,
+            ..Default::default()
+        }
+    );
+
+    let mut layout = Layout::default();
+    layout.apply_class("jiage-2");
+    assert_eq!((layout.align, layout.end_indent), (Align::End, 2));
+    let mut layout = Layout::default();
+    layout.apply_class("align-center");
+    assert_eq!(layout.align, Align::Center);
+
+    let multiline = |layout: Layout| layout.aozora(true);
+    let line = |layout: Layout| layout.aozora(false).map(|(open, _)| open);
+    let jisage = Layout {
+        indent: 2,
+        ..Default::default()
+    };
+    assert_eq!(line(jisage).as_deref(), Some("［＃２字下げ］"));
+    assert_eq!(
+        multiline(jisage),
+        Some((
+            "［＃ここから２字下げ］\n".to_owned(),
+            "\n［＃ここで字下げ終わり］".to_owned()
+        ))
+    );
+    let hanging = Layout {
+        indent: 3,
+        text_indent: -2,
+        ..Default::default()
+    };
+    assert_eq!(
+        line(hanging).as_deref(),
+        Some("［＃ここから１字下げ、折り返して３字下げ］\n")
+    );
+    let signature = Layout {
+        align: Align::End,
+        end_indent: 1,
+        ..Default::default()
+    };
+    assert_eq!(line(signature).as_deref(), Some("［＃地から１字上げ］"));
+    assert_eq!(line(Layout::default()), None);
+}
@@ -2,30 +2,46 @@ use std::{
     collections::HashMap,
     fs::{File, create_dir_all},
     io::Write,
-    path::Path,
+    ops::Not,
+    path::{Path, PathBuf},
     process::exit,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use chapters::Chapter;
 use epub::{Epub, Meta};
 use error::{OrDie, 即死, 死};
 use global_str::GlobalStr;
+use layout::LayoutMode;
+use txt::TxtOptions;
+use yomi::RubyFormat;
 
 mod chapters;
+mod dialogue;
+mod dictionary;
+mod emphasis;
 mod epub;
 mod error;
+mod font;
 mod gaiji;
 mod global_str;
+mod glyph;
 mod heuristics;
+mod inline;
+mod layout;
 mod markov;
+mod png;
+mod readings;
+mod small_kana;
 mod txt;
 mod yomi;
 
 const SEP: char = '\t'; // For file I/O
 static EPUB_FNAME: GlobalStr = GlobalStr::new();
 static PHASE: GlobalStr = GlobalStr::new();
+static LENIENT: AtomicBool = AtomicBool::new(false); // Recover from malformed XHTML instead of dying
 
-pub fn prepare(epub_fname: &Path, output_path: &Path) -> (Epub, Vec<Chapter>) {
+pub fn prepare(epub_fname: &Path, output_path: &Path) -> (Epub, Meta, Vec<Chapter>) {
     let mut file = File::open(epub_fname).or_(死!("failed to open EPUB file"));
     let epub = Epub::new(&mut file);
 
@@ -40,31 +56,122 @@ pub fn prepare(epub_fname: &Path, output_path: &Path) -> (Epub, Vec<Chapter>) {
         chapters
     });
 
-    (epub, chapters)
+    (epub, meta, chapters)
 }
 
 pub fn output_book(
     epub: &Epub,
     meta: &Meta,
     chapters: &[Chapter],
-    gaiji: &mut HashMap<String, char>,
+    gaiji: &mut HashMap<String, String>,
     output_path: &Path,
+    options: &TxtOptions,
 ) {
-    let txt_fname = output_path.join(&meta.title).with_extension("txt");
-    let yomi_fname = output_path.join(&meta.title).with_extension("ruby.yomi");
+    // the books of a series in one EPUB are named in chapters.tsv
+    let title = chapters
+        .first()
+        .map_or(meta.title.as_str(), |chapter| chapter.book_name.as_str());
+    let txt_fname = output_path.join(title).with_extension("txt");
+    let ruby_extension = options.ruby.extension();
+    let yomi_fname = output_path
+        .join(title)
+        .with_extension(format!("ruby.{ruby_extension}"));
+    let emphasis_fname = output_path.join(title).with_extension("emphasis");
+    let notes_fname = output_path.join(title).with_extension("notes");
+    let speech_fname = output_path.join(title).with_extension("speech");
+    let policies = inline::read(&output_path.join("inline.tsv"));
 
-    let (txt, yomi) = txt::produce_txt_yomi(gaiji, epub, chapters);
+    let (txt, annotations) = txt::produce_txt_yomi(options, gaiji, &policies, epub, chapters);
     let mut txt_file = File::create(&txt_fname).or_(死!());
     txt_file.write_all(txt.as_bytes()).or_(死!());
 
     let yomi_file = File::create(&yomi_fname).or_(死!());
-    yomi::write_yomi(&yomi, yomi_file, &txt);
+    options.ruby.write(&annotations.yomi, yomi_file, &txt);
+
+    // the further tiers of double-sided ruby go to .ruby.2.yomi etc.
+    for (idx, tier) in annotations.ruby_tiers.iter().enumerate() {
+        let tier_fname = output_path
+            .join(title)
+            .with_extension(format!("ruby.{}.{ruby_extension}", idx + 2));
+        let tier_file = File::create(&tier_fname).or_(死!());
+        options.ruby.write(tier, tier_file, &txt);
+    }
+
+    let emphasis_file = File::create(&emphasis_fname).or_(死!());
+    emphasis::write_emphasis(&annotations.emphasis, emphasis_file, &txt);
+
+    let notes_file = File::create(&notes_fname).or_(死!());
+    epub::write_notes(&annotations.notes, notes_file);
+
+    let speech_file = File::create(&speech_fname).or_(死!());
+    dialogue::write_speech(&annotations.speech, speech_file);
+}
+
+/// `epub_textractor dictionary <dirs>…`: a reading dictionary of the ruby of the books
+/// output to the directories (or their subdirectories)
+fn dictionary_command(dirs: &[String]) {
+    if dirs.is_empty() {
+        eprintln!("Give the output directories of the books as parameters!");
+        exit(1);
+    }
+    let mut dictionary = dictionary::Dictionary::default();
+    let mut n_books = 0;
+    for dir in dirs {
+        n_books += dictionary.add_dir(Path::new(dir));
+    }
+    let entries = dictionary.ranked();
+    eprintln!(
+        "Collected {} readings from {n_books} books. Writing \"reading_dict.csv\", \"reading_dict.tsv\" and \"SKK-JISYO.reading\"",
+        entries.len()
+    );
+    let mecab_file = File::create("reading_dict.csv").or_(死!());
+    dictionary::write_mecab(&entries, mecab_file);
+    let training_file = File::create("reading_dict.tsv").or_(死!());
+    dictionary::write_training(&entries, training_file);
+    let skk_file = File::create("SKK-JISYO.reading").or_(死!());
+    dictionary::write_skk(&entries, skk_file);
 }
 
 fn main() {
-    let epub_fname = std::env::args().nth(1);
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if let Some((command, dirs)) = args.split_first()
+        && command == "dictionary"
+    {
+        dictionary_command(dirs);
+        return;
+    }
+
+    let mut epub_fname = None;
+    let mut options = TxtOptions::default();
+    let mut gaiji_db_fname = None;
+    for arg in args {
+        match arg.as_str() {
+            "--lenient" => LENIENT.store(true, Ordering::Relaxed),
+            arg if arg.starts_with("--layout=") => {
+                let mode = &arg["--layout=".len()..];
+                options.layout = LayoutMode::parse(mode).or_(死!(
+                    "Unknown layout {mode:?}: expected plain, spaces or aozora"
+                ));
+            }
+            arg if arg.starts_with("--gaiji-db=") => {
+                gaiji_db_fname = Some(PathBuf::from(&arg["--gaiji-db=".len()..]));
+            }
+            arg if arg.starts_with("--ruby=") => {
+                let format = &arg["--ruby=".len()..];
+                options.ruby = RubyFormat::parse(format).or_(死!(
+                    "Unknown ruby format {format:?}: expected offsets, aozora, html or json"
+                ));
+            }
+            arg if arg.starts_with("--scene-break=") => {
+                options.scene_break = arg["--scene-break=".len()..].to_owned();
+            }
+            _ => epub_fname = Some(arg),
+        }
+    }
     let Some(epub_fname) = epub_fname else {
-        eprintln!("Give a filename as a parameter!");
+        eprintln!(
+            "Give a filename as a parameter! (optionally: --lenient, --layout=plain|spaces|aozora, --ruby=offsets|aozora|html|json, --scene-break=◇, --gaiji-db=path)"
+        );
         exit(1);
     };
 
@@ -75,19 +182,93 @@ fn main() {
     let output_path = Path::new(epub_fname).with_extension("");
     create_dir_all(&output_path).or_(死!("failed to create output directory"));
 
-    let (_epub, chapters) = prepare(epub_fname, &output_path);
+    let (epub, meta, chapters) = prepare(epub_fname, &output_path);
+
+    // the gaiji database is shared by the books in the same directory, unless given
+    let gaiji_db_fname =
+        gaiji_db_fname.unwrap_or_else(|| epub_fname.with_file_name("gaiji_db.txt"));
+    let mut gaiji_db = gaiji::read_db(&gaiji_db_fname);
+    let book_gaiji = epub.gaiji();
+
+    // private use characters are drawn by embedded fonts, which might name their glyphs
+    let private_use: Vec<_> = book_gaiji
+        .iter()
+        .filter_map(|gaiji| match gaiji.glyph {
+            epub::Glyph::Char(ch) => Some(ch),
+            _ => None,
+        })
+        .collect();
+    let font_glyphs = if private_use.is_empty() {
+        Vec::new()
+    } else {
+        font::find_glyphs(&epub.fonts(), &private_use)
+    };
+    if font_glyphs.is_empty().not() {
+        let font_glyphs_fname = output_path.join("font_gaiji.tsv");
+        eprintln!(
+            "Found {} private use gaiji in the embedded fonts. Writing {font_glyphs_fname:?}",
+            font_glyphs.len()
+        );
+        font::write_font_glyphs(&font_glyphs, &font_glyphs_fname);
+    }
+    let images = gaiji::image_hashes(&epub, &book_gaiji, &font_glyphs);
 
     let gaiji_fname = output_path.join("gaiji.txt");
-    let gaiji = gaiji::read(&gaiji_fname).unwrap_or_default();
-    let gaiji_orig_size = gaiji.len();
+    let (mut gaiji, mut suggested) = gaiji::read(&gaiji_fname).unwrap_or_default();
+    let gaiji_orig = gaiji.clone();
+    gaiji::inherit(&mut gaiji, &mut suggested, &gaiji_db, &images);
+    let n_alt = gaiji::suggest_alt(&mut gaiji, &mut suggested, &book_gaiji);
+    if n_alt > 0 {
+        eprintln!("Suggested {n_alt} gaiji from their alt texts. Confirm them in {gaiji_fname:?}.");
+    }
+    let n_named = gaiji::suggest_glyph_names(&mut gaiji, &mut suggested, &font_glyphs);
+    if n_named > 0 {
+        eprintln!(
+            "Suggested {n_named} gaiji from their glyph names. Confirm them in {gaiji_fname:?}."
+        );
+    }
+    let n_suggested = gaiji::suggest(&mut gaiji, &mut suggested, &gaiji_db, &images);
+    if n_suggested > 0 {
+        eprintln!(
+            "Suggested {n_suggested} gaiji from similar looking ones. Confirm them in {gaiji_fname:?}."
+        );
+    }
+
+    let unresolved = gaiji::export_unresolved(
+        &epub,
+        &book_gaiji,
+        &font_glyphs,
+        &gaiji,
+        &suggested,
+        &output_path,
+    );
+    if unresolved > 0 {
+        eprintln!("{unresolved} gaiji are unresolved. See \"gaiji.html\" for annotating them.");
+    }
 
     let books = chapters.chunk_by(|a, b| a.book_name == b.book_name);
-    for _chapters in books {
-        //output_book(&epub, chapters, &mut gaiji, &output_path); // TODO
+    for chapters in books {
+        output_book(&epub, &meta, chapters, &mut gaiji, &output_path, &options);
     }
 
-    if gaiji_orig_size != gaiji.len() {
+    if gaiji_orig != gaiji {
         eprintln!("New gaiji found! Updating/creating the gaiji file.");
-        gaiji::write_gaiji(&gaiji, &gaiji_fname);
+        gaiji::write_gaiji(&gaiji, &suggested, &gaiji_fname);
+    }
+
+    let promoted = gaiji::promote(&gaiji, &suggested, &mut gaiji_db, &images);
+    if promoted > 0 {
+        eprintln!("Adding {promoted} gaiji to the gaiji database {gaiji_db_fname:?}");
+        gaiji::write_db(&gaiji_db, &gaiji_db_fname);
+    }
+
+    let repairs = epub::take_repairs();
+    if repairs.is_empty().not() {
+        let repairs_fname = output_path.join("repairs.tsv");
+        eprintln!(
+            "Repaired {} spots of malformed XHTML. Writing {repairs_fname:?}",
+            repairs.len()
+        );
+        epub::write_repairs(&repairs, &repairs_fname);
     }
 }
@@ -0,0 +1,250 @@
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+/// The starting column and row, and the steps between them, of the Adam7 passes
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// A decoded PNG as 8-bit samples: gray + alpha, or RGBA
+#[derive(Debug)]
+pub struct Png {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize, // 2 or 4
+    pub samples: Vec<u8>,
+}
+
+struct Header {
+    width: usize,
+    height: usize,
+    depth: usize,
+    color: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let [w0, w1, w2, w3, h0, h1, h2, h3, depth, color, 0, 0, interlace] = *data else {
+            return None;
+        };
+        Some(Header {
+            width: u32::from_be_bytes([w0, w1, w2, w3]) as usize,
+            height: u32::from_be_bytes([h0, h1, h2, h3]) as usize,
+            depth: depth as usize,
+            color,
+            interlaced: interlace == 1,
+        })
+    }
+
+    /// The samples per pixel in the image data
+    fn channels(&self) -> Option<usize> {
+        match (self.color, self.depth) {
+            (0, 1 | 2 | 4 | 8 | 16) => Some(1),
+            (3, 1 | 2 | 4 | 8) => Some(1), // a palette index
+            (2 | 6, 8 | 16) => Some(self.color as usize / 2 + 2),
+            (4, 8 | 16) => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// A sample of `depth` bits from a row of image data
+fn sample(row: &[u8], idx: usize, depth: usize) -> u16 {
+    match depth {
+        16 => u16::from_be_bytes([row[2 * idx], row[2 * idx + 1]]),
+        8 => row[idx] as u16,
+        _ => {
+            let bit = idx * depth;
+            (row[bit / 8] >> (8 - depth - bit % 8)) as u16 & ((1 << depth) - 1)
+        }
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses the filter of a row, given the already unfiltered row above it
+fn unfilter(filter: u8, row: &mut [u8], prior: &[u8], bpp: usize) -> Option<()> {
+    for idx in 0..row.len() {
+        let a = if idx >= bpp { row[idx - bpp] } else { 0 };
+        let b = prior[idx];
+        let c = if idx >= bpp { prior[idx - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => return None,
+        };
+        row[idx] = row[idx].wrapping_add(predicted);
+    }
+    Some(())
+}
+
+/// Decodes a PNG of any color type, bit depth and interlacing. The CRCs aren't checked.
+pub fn decode(bytes: &[u8]) -> Option<Png> {
+    let mut chunks = bytes.strip_prefix(b"\x89PNG\r\n\x1A\n")?;
+    let mut header = None;
+    let (mut palette, mut transparency, mut compressed) = (&[][..], &[][..], Vec::new());
+    while chunks.len() >= 12 {
+        let len = u32::from_be_bytes(chunks[..4].try_into().ok()?) as usize;
+        let data = chunks.get(8..8 + len)?;
+        match &chunks[4..8] {
+            b"IHDR" => header = Header::parse(data),
+            b"PLTE" => palette = data,
+            b"tRNS" => transparency = data,
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => (),
+        }
+        chunks = chunks.get(12 + len..)?;
+    }
+    let header = header?;
+    let in_channels = header.channels()?;
+    let data = decompress_to_vec_zlib(&compressed).ok()?;
+
+    let Header {
+        width,
+        height,
+        depth,
+        color,
+        ..
+    } = header;
+    let channels = if color == 0 || color == 4 { 2 } else { 4 };
+    let mut samples = vec![0; width.checked_mul(height)?.checked_mul(channels)?];
+    let max = (1u32 << depth) - 1;
+    let to_8bit = |value: u16| match depth {
+        16 => (value >> 8) as u8,
+        _ => (value as u32 * 255 / max) as u8,
+    };
+    let key = |len| (transparency.len() == len).then_some(transparency);
+
+    let bits_per_pixel = in_channels * depth;
+    let bpp = bits_per_pixel.div_ceil(8);
+    let passes = if header.interlaced {
+        &ADAM7[..]
+    } else {
+        &[(0, 0, 1, 1)][..]
+    };
+    let mut pos = 0;
+    for &(x0, y0, dx, dy) in passes {
+        let pass_width = width.saturating_sub(x0).div_ceil(dx);
+        let pass_height = height.saturating_sub(y0).div_ceil(dy);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let stride = (pass_width * bits_per_pixel).div_ceil(8);
+        let mut prior = vec![0; stride];
+        for py in 0..pass_height {
+            let filter = *data.get(pos)?;
+            let mut row = data.get(pos + 1..pos + 1 + stride)?.to_vec();
+            pos += 1 + stride;
+            unfilter(filter, &mut row, &prior, bpp)?;
+            for px in 0..pass_width {
+                let value = |c| sample(&row, px * in_channels + c, depth);
+                let pixel = match color {
+                    0 => {
+                        let gray = value(0);
+                        let transparent = key(2).is_some_and(|key| key == gray.to_be_bytes());
+                        [to_8bit(gray), if transparent { 0 } else { 255 }, 0, 0]
+                    }
+                    4 => [to_8bit(value(0)), to_8bit(value(1)), 0, 0],
+                    2 => {
+                        let rgb = [value(0), value(1), value(2)];
+                        let transparent = key(6).is_some_and(|key| {
+                            key.chunks(2).zip(rgb).all(|(k, v)| k == v.to_be_bytes())
+                        });
+                        let [r, g, b] = rgb.map(to_8bit);
+                        [r, g, b, if transparent { 0 } else { 255 }]
+                    }
+                    6 => [0, 1, 2, 3].map(|c| to_8bit(value(c))),
+                    _ => {
+                        let idx = value(0) as usize;
+                        let [r, g, b] = *palette.get(3 * idx..3 * idx + 3)? else {
+                            return None;
+                        };
+                        [r, g, b, transparency.get(idx).copied().unwrap_or(255)]
+                    }
+                };
+                let offset = ((y0 + py * dy) * width + x0 + px * dx) * channels;
+                samples[offset..offset + channels].copy_from_slice(&pixel[..channels]);
+            }
+            prior = row;
+        }
+    }
+    Some(Png {
+        width,
+        height,
+        channels,
+        samples,
+    })
+}
+
+/// A chunk with a zero CRC, which the decoder ignores
+#[cfg(test)]
+fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&[0; 4]);
+    chunk
+}
+
+/// A single-row PNG of the filtered `raw` data, with the `extra` chunks before it
+#[cfg(test)]
+pub fn encode_row(width: u32, depth: u8, color: u8, extra: &[Vec<u8>], raw: &[u8]) -> Vec<u8> {
+    let mut ihdr = width.to_be_bytes().to_vec();
+    ihdr.extend_from_slice(&1u32.to_be_bytes());
+    ihdr.extend_from_slice(&[depth, color, 0, 0, 0]);
+    let mut png = b"\x89PNG\r\n\x1A\n".to_vec();
+    png.extend(chunk(b"IHDR", &ihdr));
+    extra.iter().for_each(|extra| png.extend_from_slice(extra));
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(raw, 6);
+    png.extend(chunk(b"IDAT", &compressed));
+    png.extend(chunk(b"IEND", &[]));
+    png
+}
+
+#[test]
+fn test_decode() {
+    let png = encode_row;
+
+    // gray + alpha, with the Sub filter
+    let decoded = decode(&png(2, 8, 4, &[], &[1, 10, 255, 20, 0])).unwrap();
+    assert_eq!(decoded.samples, [10, 255, 30, 255]);
+
+    // 1-bit palette with a transparent entry
+    let extra = [chunk(b"PLTE", &[0, 0, 0, 255, 255, 255]), chunk(b"tRNS", &[255, 0])];
+    let decoded = decode(&png(3, 1, 3, &extra, &[0, 0b0100_0000])).unwrap();
+    assert_eq!(
+        decoded.samples,
+        [0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255]
+    );
+
+    // 16-bit RGB
+    let decoded = decode(&png(1, 16, 2, &[], &[0, 0xFF, 0, 0x80, 0, 0, 0])).unwrap();
+    assert_eq!(decoded.samples, [255, 128, 0, 255]);
+
+    assert!(decode(b"\x89PNG\r\n\x1A\n").is_none());
+    assert!(decode(&png(1, 3, 0, &[], &[0, 0])).is_none());
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::Not,
+    sync::LazyLock,
+};
+
+use crate::{emphasis::Mark, yomi::is_kanji};
+
+/// `kanji<TAB>on readings<TAB>kun readings`, the on readings in katakana and the kun readings
+/// in hiragana with the okurigana after a dot, like `食<TAB>ショク ジキ<TAB>く.う た.べる`.
+/// Lines starting with `#` are comments, in both tables.
+const KANJI_READINGS: &str = include_str!("../aux_data/kanji_readings.tsv");
+
+/// `word<TAB>reading`: readings of words as a whole, like 今日 きょう
+const JUKUJIKUN: &str = include_str!("../aux_data/jukujikun.tsv");
+
+/// The kana a kanji that isn't in the table can read as, at most
+const MAX_UNKNOWN_READING: usize = 4;
+
+static TABLE: LazyLock<Table> = LazyLock::new(|| Table::parse(KANJI_READINGS, JUKUJIKUN));
+
+/// What a ruby annotation is, as far as the script and the kanji readings tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RubyClass {
+    Reading,   // the on and kun readings of the kanji, like 漢字《かんじ》 or 食べる《たべる》
+    Jukujikun, // a reading of the word as a whole, like 今日《きょう》
+    Gloss,     // gikun, substitutions and meanings: 本気《マジ》, 彼《あいつ》, ruby on loanwords
+    Emphasis,  // 傍点 written as ruby, like ﹅﹅
+}
+
+impl RubyClass {
+    pub fn parse(class: &str) -> Option<Self> {
+        let class = match class {
+            "reading" => RubyClass::Reading,
+            "jukujikun" => RubyClass::Jukujikun,
+            "gloss" => RubyClass::Gloss,
+            "emphasis" => RubyClass::Emphasis,
+            _ => return None,
+        };
+        Some(class)
+    }
+
+    /// Whether the ruby says how the base is read, i.e. belongs in a reading dictionary
+    pub fn is_reading(self) -> bool {
+        matches!(self, RubyClass::Reading | RubyClass::Jukujikun)
+    }
+}
+
+impl Display for RubyClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let class = match self {
+            RubyClass::Reading => "reading",
+            RubyClass::Jukujikun => "jukujikun",
+            RubyClass::Gloss => "gloss",
+            RubyClass::Emphasis => "emphasis",
+        };
+        f.write_str(class)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    kanji: HashMap<char, Vec<Vec<char>>>, // the folded readings of each kanji
+    written: HashMap<char, Vec<Vec<char>>>, // the readings as written, the on ones in hiragana
+    jukujikun: HashSet<(String, Vec<char>)>,
+}
+
+/// Folds the differences that don't matter for matching readings: katakana and hiragana,
+/// small and big kana (old-style ruby has no small kana), and ぢ づ for じ ず
+fn fold(ch: char) -> char {
+    let ch = to_hiragana(ch);
+    match ch {
+        'ぁ' | 'ぃ' | 'ぅ' | 'ぇ' | 'ぉ' | 'っ' | 'ゃ' | 'ゅ' | 'ょ' | 'ゎ' => {
+            char::from_u32(ch as u32 + 1).unwrap_or(ch)
+        }
+        'ゕ' => 'か',
+        'ゖ' => 'け',
+        'ぢ' => 'じ',
+        'づ' => 'ず',
+        ch => ch,
+    }
+}
+
+fn to_hiragana(ch: char) -> char {
+    match ch {
+        'ァ'..='ヶ' => char::from_u32(ch as u32 - 0x60).unwrap_or(ch),
+        ch => ch,
+    }
+}
+
+fn is_kana(ch: char) -> bool {
+    matches!(ch, 'ぁ'..='ゖ' | 'ゝ' | 'ゞ' | 'ァ'..='ヺ' | 'ー' | 'ヽ' | 'ヾ')
+}
+
+/// The verb stem in its continuative form, which nouns are made of: 話す → はなし, 帯びる → おび
+fn continuative(stem: &str, okurigana: &str) -> Option<String> {
+    let mut okurigana: Vec<char> = okurigana.chars().collect();
+    let last = okurigana.pop()?;
+    if okurigana.is_empty().not() {
+        // 食べる → たべ, 美しい → うつくし
+        return Some(format!("{stem}{}", String::from_iter(okurigana)));
+    }
+    let continuative = match last {
+        'う' => 'い',
+        'く' => 'き',
+        'ぐ' => 'ぎ',
+        'す' => 'し',
+        'つ' => 'ち',
+        'ぬ' => 'に',
+        'ぶ' => 'び',
+        'む' => 'み',
+        'る' => 'り',
+        _ => return None,
+    };
+    Some(format!("{stem}{continuative}"))
+}
+
+impl Table {
+    fn parse(kanji_readings: &str, jukujikun: &str) -> Self {
+        let mut table = Table::default();
+        for line in kanji_readings.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let (Some(kanji), Some(on), Some(kun)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let Some(kanji) = kanji.chars().next() else {
+                continue;
+            };
+            let mut readings: Vec<String> = on.split_whitespace().map(str::to_owned).collect();
+            for kun in kun.split_whitespace() {
+                match kun.split_once('.') {
+                    Some((stem, okurigana)) => {
+                        readings.push(stem.to_owned());
+                        readings.push(format!("{stem}{okurigana}"));
+                        readings.extend(continuative(stem, okurigana));
+                    }
+                    None => readings.push(kun.to_owned()),
+                }
+            }
+            let written = table.written.entry(kanji).or_default();
+            for reading in &readings {
+                written.push(reading.chars().map(to_hiragana).collect());
+            }
+            let folded = table.kanji.entry(kanji).or_default();
+            for reading in readings_of(&readings) {
+                if folded.contains(&reading).not() {
+                    folded.push(reading);
+                }
+            }
+        }
+        for line in jukujikun.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((word, reading)) = line.split_once('\t') {
+                table
+                    .jukujikun
+                    .insert((word.to_owned(), reading.chars().map(fold).collect()));
+            }
+        }
+        table
+    }
+
+    /// Whether the ruby is spelled out by readings of the kanji as written, allowing for 連濁
+    fn spells(&self, rb: &[char], rt: &[char], is_first: bool) -> bool {
+        let Some((kanji, rb)) = rb.split_first() else {
+            return rt.is_empty();
+        };
+        let Some(readings) = self.written.get(kanji) else {
+            return false;
+        };
+        readings.iter().any(|reading| {
+            reading.len() <= rt.len()
+                && reading
+                    .iter()
+                    .zip(rt)
+                    .enumerate()
+                    .all(|(idx, (&expected, &ch))| {
+                        expected == ch
+                            || (idx == 0 && is_first.not() && voiced(expected).contains(&Some(ch)))
+                    })
+                && self.spells(rb, &rt[reading.len()..], false)
+        })
+    }
+
+    /// Whether the reading can be split into readings of the characters of the base
+    fn aligns(&self, rb: &[char], rt: &[char]) -> bool {
+        let mut failed = HashSet::new();
+        self.align(rb, rt, 0, 0, &mut failed)
+    }
+
+    fn align(
+        &self,
+        rb: &[char],
+        rt: &[char],
+        base: usize,
+        pos: usize,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> bool {
+        if base == rb.len() {
+            return pos == rt.len();
+        }
+        if pos == rt.len() || failed.contains(&(base, pos)) {
+            return false;
+        }
+        let rest = &rt[pos..];
+        let next = |len: usize, failed: &mut HashSet<_>| {
+            len <= rest.len() && self.align(rb, rt, base + 1, pos + len, failed)
+        };
+        let ch = rb[base];
+        let aligned = match ch {
+            // 一ヶ月, 三ヶ所
+            'ヶ' | 'ケ' | 'ヵ' | 'ゖ' | 'ゕ' => {
+                matches!(rest[0], 'か' | 'が' | 'こ' | 'け') && next(1, failed)
+            }
+            ch if is_kana(ch) && matches!(ch, 'ゝ' | 'ゞ' | 'ヽ' | 'ヾ' | 'ー').not() => {
+                fold(ch) == rest[0] && next(1, failed)
+            }
+            ch if is_kanji(ch) || matches!(ch, 'ゝ' | 'ゞ' | 'ヽ' | 'ヾ') => {
+                // the iteration marks read as the character before them
+                let kanji = match ch {
+                    '々' | 'ゝ' | 'ゞ' | 'ヽ' | 'ヾ' if base > 0 => rb[base - 1],
+                    ch => ch,
+                };
+                match self.kanji.get(&kanji) {
+                    Some(readings) => readings.iter().any(|reading| {
+                        let is_first = base == 0;
+                        let is_last = base + 1 == rb.len();
+                        matches_reading(reading, rest, is_first, is_last)
+                            && next(reading.len(), failed)
+                    }),
+                    None if is_kanji(kanji) => {
+                        (1..=MAX_UNKNOWN_READING).any(|len| next(len, failed))
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        };
+        if aligned.not() {
+            failed.insert((base, pos));
+        }
+        aligned
+    }
+}
+
+/// The folded readings, the on readings in hiragana
+fn readings_of(readings: &[String]) -> Vec<Vec<char>> {
+    readings
+        .iter()
+        .map(|reading| reading.chars().map(fold).collect())
+        .collect()
+}
+
+/// 連濁, the first kana voiced after another kanji: 本棚 ほん・だな, 出発 しゅっ・ぱつ
+fn voiced(ch: char) -> [Option<char>; 2] {
+    let voiced = |ch: char| char::from_u32(ch as u32 + 1);
+    match ch {
+        'か' | 'き' | 'く' | 'け' | 'こ' | 'さ' | 'し' | 'す' | 'せ' | 'そ' | 'た' | 'て'
+        | 'と' => [voiced(ch), None],
+        'ち' => [Some('じ'), None],
+        'つ' => [Some('ず'), None],
+        'は' | 'ひ' | 'ふ' | 'へ' | 'ほ' => [voiced(ch), char::from_u32(ch as u32 + 2)],
+        _ => [None, None],
+    }
+}
+
+/// Whether the reading starts the rest of the ruby, allowing for 連濁 and for 促音 at its end,
+/// like 学校 がっ・こう (っ is folded into つ)
+fn matches_reading(reading: &[char], rest: &[char], is_first: bool, is_last: bool) -> bool {
+    let Some(rest) = rest.get(..reading.len()) else {
+        return false;
+    };
+    reading
+        .iter()
+        .zip(rest)
+        .enumerate()
+        .all(|(idx, (&expected, &ch))| {
+            expected == ch
+                || (idx == 0 && is_first.not() && voiced(expected).contains(&Some(ch)))
+                || (idx + 1 == reading.len()
+                    && idx > 0
+                    && is_last.not()
+                    && matches!(expected, 'く' | 'き' | 'ち' | 'つ')
+                    && ch == 'つ')
+        })
+}
+
+/// Classifies a ruby annotation by the script of the base and of the ruby, and by whether
+/// the ruby is made of the readings of the kanji in `aux_data/kanji_readings.tsv`
+pub fn classify(rb: &str, rt: &str) -> RubyClass {
+    if Mark::from_rt(rt).is_some() {
+        return RubyClass::Emphasis;
+    }
+    // readings spaced out over the characters, or with dots between the words
+    let rt: Vec<char> = rt
+        .chars()
+        .filter(|&ch| ch.is_whitespace().not() && ch != '・')
+        .map(fold)
+        .collect();
+    if rt.is_empty() || rt.iter().all(|&ch| is_kana(ch)).not() {
+        return RubyClass::Gloss;
+    }
+    if rb.chars().any(is_kanji).not() {
+        // ruby on kana only says how they're read if it's the same kana
+        let rb: Vec<char> = rb.chars().map(fold).collect();
+        return if rb == rt {
+            RubyClass::Reading
+        } else {
+            RubyClass::Gloss
+        };
+    }
+    let table = &*TABLE;
+    if table.jukujikun.contains(&(rb.to_owned(), rt.clone())) {
+        return RubyClass::Jukujikun;
+    }
+    let rb: Vec<char> = rb.chars().collect();
+    if table.aligns(&rb, &rt) {
+        RubyClass::Reading
+    } else {
+        RubyClass::Gloss
+    }
+}
+
+/// Whether the ruby is the readings of the kanji in the table as they are written, big kana and
+/// all: 清 きよ as in 清い, or 月夜 つき・よ, where the kana alone would rather have きょ
+pub fn is_written_reading(rb: &str, rt: &str) -> bool {
+    let rb: Vec<char> = rb.chars().collect();
+    let rt: Vec<char> = rt.chars().map(to_hiragana).collect();
+    rb.is_empty().not() && TABLE.spells(&rb, &rt, true)
+}
+
+#[test]
+fn test_classify() {
+    for (rb, rt, expected) in [
+        ("漢字", "かんじ", RubyClass::Reading),
+        ("食べる", "たべる", RubyClass::Reading),
+        ("話", "はなし", RubyClass::Reading),
+        ("学校", "がつこう", RubyClass::Reading),
+        ("出発", "しゅっぱつ", RubyClass::Reading),
+        ("本棚", "ほんだな", RubyClass::Reading),
+        ("人々", "ひとびと", RubyClass::Reading),
+        ("一ヶ月", "いっかげつ", RubyClass::Reading),
+        ("出来る", "できる", RubyClass::Reading),
+        ("魑魅", "ちみ", RubyClass::Reading), // not in the table
+        ("親", "お や", RubyClass::Reading),
+        ("今日", "きょう", RubyClass::Jukujikun),
+        ("大人", "オトナ", RubyClass::Jukujikun),
+        ("本気", "マジ", RubyClass::Gloss),
+        ("運命", "さだめ", RubyClass::Gloss),
+        ("彼", "あいつ", RubyClass::Gloss),
+        ("怪物", "モンスター", RubyClass::Gloss),
+        ("業", "Karma", RubyClass::Gloss),
+        ("ストレージ", "くら", RubyClass::Gloss),
+        ("ほんき", "ホンキ", RubyClass::Reading),
+        ("本当", "﹅﹅", RubyClass::Emphasis),
+    ] {
+        assert_eq!(classify(rb, rt), expected, "{rb} {rt}");
+    }
+    assert_eq!(RubyClass::parse("gloss"), Some(RubyClass::Gloss));
+    assert!(is_written_reading("清", "キヨ"));
+    assert!(is_written_reading("車", "しや").not());
+    assert!(is_written_reading("月夜", "つきよ"));
+    assert!(is_written_reading("本棚", "ほんだな"));
+    assert!(is_written_reading("出発", "しゆつぱつ").not());
+    assert!(is_written_reading("清水", "きよ").not());
+    assert!(is_written_reading("", "").not());
+    assert_eq!(RubyClass::Jukujikun.to_string(), "jukujikun");
+}
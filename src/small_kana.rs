@@ -0,0 +1,196 @@
+use std::{collections::HashMap, ops::Not, sync::LazyLock};
+
+use crate::readings;
+
+/// Modern-style ruby, `base<TAB>reading` per line, the readings the model learns the small kana
+/// from; lines starting with `#` are comments. It's built in: extend it with the
+/// `reading_dict.tsv` of books with modern-style ruby and rebuild.
+const TRAINING: &str = include_str!("../aux_data/small_kana_train.tsv");
+
+/// Interpolation weights of the trigram, bigram and unigram estimates
+const WEIGHTS: [f64; 3] = [0.6, 0.3, 0.1];
+
+/// How much likelier the small kana must be: old-style ruby also has the big kana right
+const MARGIN: f64 = 1.0;
+
+const START: char = '^';
+
+static MODEL: LazyLock<Model> = LazyLock::new(|| Model::train(TRAINING));
+
+/// A kana trigram model of modern-style readings, katakana folded into hiragana
+#[derive(Debug, Default)]
+struct Model {
+    counts: HashMap<Vec<char>, u32>,   // the 1-, 2- and 3-grams
+    contexts: HashMap<Vec<char>, u32>, // the 0-, 1- and 2-grams followed by a kana
+    vocabulary: usize,
+}
+
+fn to_hiragana(ch: char) -> char {
+    match ch {
+        'ァ'..='ヶ' => char::from_u32(ch as u32 - 0x60).unwrap_or(ch),
+        ch => ch,
+    }
+}
+
+/// The small kana of a big one, in the same script
+fn small(ch: char) -> Option<char> {
+    let small = match to_hiragana(ch) {
+        'あ' => 'ぁ',
+        'い' => 'ぃ',
+        'う' => 'ぅ',
+        'え' => 'ぇ',
+        'お' => 'ぉ',
+        'つ' => 'っ',
+        'や' => 'ゃ',
+        'ゆ' => 'ゅ',
+        'よ' => 'ょ',
+        'わ' => 'ゎ',
+        _ => return None,
+    };
+    if ch == to_hiragana(ch) {
+        Some(small)
+    } else {
+        char::from_u32(small as u32 + 0x60)
+    }
+}
+
+/// Whether the small kana can follow at all; the model decides whether it does
+fn can_follow(previous: char, small: char) -> bool {
+    let previous = to_hiragana(previous);
+    let is_kana = matches!(previous, 'ぁ'..='ゖ') && matches!(previous, 'ん' | 'っ').not();
+    match to_hiragana(small) {
+        'ゃ' | 'ゅ' | 'ょ' => matches!(
+            previous,
+            'き' | 'ぎ'
+                | 'し'
+                | 'じ'
+                | 'ち'
+                | 'ぢ'
+                | 'に'
+                | 'ひ'
+                | 'び'
+                | 'ぴ'
+                | 'み'
+                | 'り'
+                | 'て'
+                | 'で'
+                | 'ふ'
+                | 'ゔ'
+        ),
+        // しゅっ, but not ゃぁ
+        'っ' => is_kana,
+        _ => is_kana && "ぁぃぅぇぉゃゅょゎ".contains(previous).not(),
+    }
+}
+
+impl Model {
+    fn train(training: &str) -> Self {
+        let mut model = Model::default();
+        for line in training.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some((_, rt)) = line.split_once('\t') else {
+                continue;
+            };
+            let kana: Vec<char> = [START, START]
+                .into_iter()
+                .chain(rt.chars().map(to_hiragana))
+                .collect();
+            for end in 2..kana.len() {
+                for n in 1..=3 {
+                    let gram = &kana[end + 1 - n..=end];
+                    *model.counts.entry(gram.to_vec()).or_default() += 1;
+                    *model.contexts.entry(gram[..n - 1].to_vec()).or_default() += 1;
+                }
+            }
+        }
+        model.vocabulary = model.counts.keys().filter(|gram| gram.len() == 1).count();
+        model
+    }
+
+    /// The interpolated probability of the last char of the trigram
+    fn probability(&self, trigram: &[char]) -> f64 {
+        let vocabulary = self.vocabulary as f64;
+        WEIGHTS
+            .iter()
+            .zip((1..=3).rev())
+            .map(|(weight, n)| {
+                let gram = &trigram[3 - n..];
+                let count = self.counts.get(gram).copied().unwrap_or(0) as f64;
+                let context = self.contexts.get(&gram[..n - 1]).copied().unwrap_or(0) as f64;
+                let estimate = if n == 1 {
+                    (count + 1.0) / (context + vocabulary + 1.0)
+                } else if context > 0.0 {
+                    count / context
+                } else {
+                    0.0
+                };
+                weight * estimate
+            })
+            .sum()
+    }
+
+    /// The log-probability of the readings' kana around a position
+    fn score(&self, kana: &[char], idx: usize) -> f64 {
+        let padded: Vec<char> = [START, START]
+            .into_iter()
+            .chain(kana.iter().map(|&ch| to_hiragana(ch)))
+            .collect();
+        // the trigrams that contain the position
+        (idx + 2..(idx + 5).min(padded.len()))
+            .map(|end| self.probability(&padded[end - 2..=end]).ln())
+            .sum()
+    }
+}
+
+/// Restores the small kana of old-style ruby, where e.g. しゃべ was typeset しやべ: per position,
+/// the small kana if the model finds it likelier. `None` if the reading stays as it is, which
+/// it also does if the kanji table writes the readings that way.
+pub fn restore(rb: &str, rt: &str) -> Option<String> {
+    if readings::is_written_reading(rb, rt) {
+        return None;
+    }
+    let model = &*MODEL;
+    let mut kana: Vec<char> = rt.chars().collect();
+    let mut restored = false;
+    for idx in 1..kana.len() {
+        let Some(small) = small(kana[idx]) else {
+            continue;
+        };
+        if can_follow(kana[idx - 1], small).not()
+            || (to_hiragana(small) == 'っ' && idx + 1 == kana.len())
+        {
+            continue;
+        }
+        let big = kana[idx];
+        let big_score = model.score(&kana, idx);
+        kana[idx] = small;
+        if model.score(&kana, idx) > big_score + MARGIN {
+            restored = true;
+        } else {
+            kana[idx] = big;
+        }
+    }
+    restored.then(|| kana.into_iter().collect())
+}
+
+#[test]
+fn test_restore() {
+    for (rb, rt, expected) in [
+        ("待", "まつた", Some("まった")),
+        ("襯衣", "シヤツ", Some("シャツ")),
+        ("提琴", "ヴアイオリン", Some("ヴァイオリン")),
+        ("一緒", "いつしよ", Some("いっしょ")),
+        ("出発", "しゆつぱつ", Some("しゅっぱつ")),
+        ("", "コツプ", Some("コップ")),
+        ("熱", "ねつ", None),
+        ("祭", "まつり", None),
+        ("都", "みやこ", None),
+        ("清", "きよ", None),
+        ("普通", "ふつう", None),
+        ("月夜", "つきよ", None),
+    ] {
+        assert_eq!(restore(rb, rt).as_deref(), expected, "{rb} {rt}");
+    }
+}
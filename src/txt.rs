@@ -1,23 +1,209 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Not};
 
 use crate::{
     PHASE,
     chapters::Chapter,
-    epub::{Epub, PType, Paragraph},
-    yomi::Yomi,
+    dialogue::{Classifier, SpeechSpan},
+    emphasis::Emphasis,
+    epub::{Annotations, Epub, Inline, NoteRef, PType, Paragraph, RubyAnnotation, walk},
+    error::{OrDie, 死},
+    gaiji,
+    inline::{Policies, Policy},
+    layout::LayoutMode,
+    readings::{self, RubyClass},
+    yomi::{RubyFormat, Yomi},
 };
 
+/// Renders the text of a paragraph, collecting the readings, 傍点 and notes on the way
+pub fn with_fmt_stripped<'b, 'src>(
+    paragraph: &Paragraph<'src>,
+    mode: LayoutMode,
+    gaiji: &mut HashMap<String, String>,
+    policies: &Policies,
+    annotations: &mut Annotations<'src>,
+    out: &'b mut String,
+) -> &'b str {
+    let layout = paragraph.layout;
+    let (open, close) = match mode {
+        LayoutMode::Aozora => {
+            let multiline = contains_break(&paragraph.content);
+            layout.aozora(multiline).unwrap_or_default()
+        }
+        LayoutMode::Plain | LayoutMode::Spaces => Default::default(),
+    };
+    let (first, rest) = match mode {
+        LayoutMode::Plain => (0, 0),
+        LayoutMode::Spaces => (layout.first_line_indent(), layout.indent),
+        // an ordinary paragraph indent is just leading spaces
+        LayoutMode::Aozora if open.is_empty() => (layout.first_line_indent(), 0),
+        LayoutMode::Aozora => (0, 0),
+    };
+    let spaces = |n| "　".repeat(n as usize);
+    out.push_str(&open);
+    out.push_str(&spaces(first));
+    let mut renderer = Renderer {
+        gaiji,
+        policies,
+        annotations,
+        out,
+        line_prefix: spaces(rest),
+    };
+    renderer.render(&paragraph.content);
+    out.push_str(&close);
+    out.push('\n');
+    out.as_str()
+}
+
+fn contains_break(content: &[Inline]) -> bool {
+    let mut contains = false;
+    walk(content, &mut |inline| contains |= *inline == Inline::Break);
+    contains
+}
+
+struct Renderer<'a, 'src> {
+    gaiji: &'a mut HashMap<String, String>,
+    policies: &'a Policies,
+    annotations: &'a mut Annotations<'src>,
+    out: &'a mut String,
+    line_prefix: String, // the indentation of the lines after breaks
+}
+
+impl<'src> Renderer<'_, 'src> {
+    fn render(&mut self, content: &[Inline<'src>]) {
+        let mut first_cell = true;
+        for inline in content {
+            let start = self.out.len();
+            match inline {
+                Inline::Text(text) => self.out.push_str(text),
+                Inline::Break => {
+                    self.out.push('\n');
+                    self.out.push_str(&self.line_prefix);
+                }
+                Inline::Gaiji { glyph, .. } => {
+                    let replacement = self
+                        .gaiji
+                        .entry(glyph.key().into_owned())
+                        .or_insert_with(|| gaiji::UNKNOWN.to_owned());
+                    self.out.push_str(replacement);
+                }
+                Inline::Ruby(groups) => {
+                    for group in groups {
+                        let start = self.out.len();
+                        self.render(&group.base);
+                        let span = start..self.out.len();
+                        for (tier, annotation) in group.annotations.iter().enumerate() {
+                            match annotation {
+                                RubyAnnotation::None => (),
+                                RubyAnnotation::Reading(rt) | RubyAnnotation::Gloss(rt) => {
+                                    let class = match annotation {
+                                        RubyAnnotation::Gloss(_) => RubyClass::Gloss,
+                                        _ => readings::classify(&self.out[span.clone()], rt),
+                                    };
+                                    let yomi = Yomi {
+                                        span: span.clone(),
+                                        rt,
+                                        class,
+                                    };
+                                    match tier {
+                                        0 => self.annotations.yomi.push(yomi),
+                                        tier => {
+                                            let tiers = &mut self.annotations.ruby_tiers;
+                                            if tiers.len() < tier {
+                                                tiers.resize_with(tier, Vec::new);
+                                            }
+                                            tiers[tier - 1].push(yomi);
+                                        }
+                                    }
+                                }
+                                RubyAnnotation::Emphasis(mark) => {
+                                    self.annotations.emphasis.push(Emphasis {
+                                        span: span.clone(),
+                                        mark: mark.clone(),
+                                    })
+                                }
+                            }
+                        }
+                    }
+                }
+                Inline::Emphasis { mark, children } => {
+                    self.render(children);
+                    self.annotations.emphasis.push(Emphasis {
+                        span: start..self.out.len(),
+                        mark: mark.clone(),
+                    });
+                }
+                Inline::NoteRef { marker, note } => self.annotations.notes.push(NoteRef {
+                    offset: self.out.len(),
+                    marker: marker.clone(),
+                    text: note,
+                }),
+                Inline::Link { children, .. } => self.render(children),
+                Inline::Cell(children) => {
+                    // table rows are flattened to a line, with the cells separated by a space
+                    if first_cell.not() {
+                        self.out.push('　');
+                    }
+                    first_cell = false;
+                    self.render(children);
+                }
+                Inline::Element { tag, children } => {
+                    let policy = self.policies.get(tag.name).or_(死!(
+                        at: tag,
+                        "unknown formatting <{}>",
+                        tag.name
+                    ));
+                    match policy {
+                        Policy::Keep => self.render(children),
+                        Policy::Drop => (),
+                        Policy::Wrap(open, close) => {
+                            self.out.push_str(open);
+                            self.render(children);
+                            self.out.push_str(close);
+                        }
+                        Policy::Annotate(label) => {
+                            self.render(children);
+                            let text = self.out[start..].to_owned();
+                            self.out.push_str(&format!("［＃「{text}」は{label}］"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The options of the txt output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxtOptions {
+    pub layout: LayoutMode,
+    pub scene_break: String, // the line that separates scenes
+    pub ruby: RubyFormat,
+}
+
+impl Default for TxtOptions {
+    fn default() -> Self {
+        TxtOptions {
+            layout: LayoutMode::Plain,
+            scene_break: "◇".to_owned(),
+            ruby: RubyFormat::default(),
+        }
+    }
+}
+
 pub fn produce_txt_yomi<'src>(
-    gaiji: &mut HashMap<String, char>,
+    options: &TxtOptions,
+    gaiji: &mut HashMap<String, String>,
+    policies: &Policies,
     epub: &'src Epub,
     chapters: &[Chapter],
-) -> (String, Vec<Yomi<'src>>) {
-    let mut yomi = Vec::new();
+) -> (String, Annotations<'src>) {
+    let mut annotations = Annotations::default();
     let mut output = String::new();
     for chapter in chapters.iter().filter(|c| !c.skip) {
         let paragraphs = epub.paragraph_iter(chapter);
         PHASE.set(format!("produce: {}", chapter.chap_name));
         let mut chapter_break_done = false;
+        let mut classifier = Classifier::default();
         for paragraph in paragraphs {
             match paragraph {
                 p @ Paragraph {
@@ -28,18 +214,223 @@ pub fn produce_txt_yomi<'src>(
                         output.push_str("\n\n\n\n");
                         chapter_break_done = true;
                     }
-                    p.with_fmt_stripped(gaiji, &mut yomi, &mut output);
+                    let start = output.len();
+                    let text = with_fmt_stripped(
+                        &p,
+                        options.layout,
+                        gaiji,
+                        policies,
+                        &mut annotations,
+                        &mut output,
+                    );
+                    if p.kind == PType::BodyText {
+                        let speech = classifier.classify(&text[start..]);
+                        annotations.speech.push(SpeechSpan {
+                            span: start..output.len() - 1, // without the newline
+                            speech,
+                        });
+                    }
                 }
                 Paragraph {
                     kind: PType::Empty, ..
                 } => output.push('\n'),
                 Paragraph {
-                    kind: PType::StandaloneImage | PType::Transparent,
+                    kind: PType::SceneBreak,
+                    ..
+                } => {
+                    output.push_str(&options.scene_break);
+                    output.push('\n');
+                    classifier = Classifier::default();
+                }
+                Paragraph {
+                    kind: PType::StandaloneImage | PType::Transparent | PType::Hidden | PType::Note,
                     ..
                 } => (),
             }
         }
     }
 
-    (output, yomi)
+    (output, annotations)
+}
+
+#[test]
+fn test_strip_formating() {
+    let mut buf = String::new();
+    let mut gaiji = HashMap::new();
+    let mut annotations = Annotations::default();
+    let css = Default::default();
+    let notes = Default::default();
+    let policies = Policies::default();
+    let para = |text| Paragraph::body_text(text, "text/a.xhtml", &css, &notes);
+
+    let result = with_fmt_stripped(
+        &para(
+            r#"金髪に染めた<span class="tcy">20</span>歳くらいの男<span class="tcy">!!</span>（だとか）"#,
+        ),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "金髪に染めた20歳くらいの男!!（だとか）\n");
+
+    buf.clear();
+    let result = with_fmt_stripped(
+        &para(
+            r#"<ruby><rb>山</rb><rt>やま</rt><rb>野</rb><rt>の</rt><rb>光</rb><rt>みつ</rt><rb>波</rb><rt>は</rt></ruby>、<span class="tcy">18</span>歳。"#,
+        ),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "山野光波、18歳。\n");
+
+    buf.clear();
+    let result = with_fmt_stripped(
+        &para(r#"<ruby><rb>漢</rb><rb>字</rb><rt>kan</rt><rt>ji</rt></ruby>"#),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "漢字\n");
+
+    buf.clear();
+    let result = with_fmt_stripped(
+        &para(r#"<ruby>漢<rt>Kan</rt>字<rt>ji</rt>!</ruby>"#),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "漢字!\n");
+
+    // nested phrasing content
+    buf.clear();
+    let result = with_fmt_stripped(
+        &para(
+            r#"<strong>本当<b>に</b><ruby>嫌<rt>いや</rt></ruby></strong>だ<wbr/>よ<sup>1</sup>"#,
+        ),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "本当に嫌だよ1\n");
+    assert_eq!(&result[annotations.yomi.last().unwrap().span.clone()], "嫌");
+
+    let policies = Policies::parse("sup\tdrop\nb\twrap\t【\t】\nsub\tannotate\t下付き小文字\n");
+    buf.clear();
+    let result = with_fmt_stripped(
+        &para(r#"H<sub>2</sub>Oは<b>水</b>だ<sup>※1</sup>"#),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "H2［＃「2」は下付き小文字］Oは【水】だ\n");
+
+    // 傍点 as classes without a stylesheet, and as ruby
+    buf.clear();
+    annotations.emphasis.clear();
+    let yomi_count = annotations.yomi.len();
+    let result = with_fmt_stripped(
+        &para(r#"<em class="em-sesame">本当</em>に<ruby>嘘<rt>・</rt></ruby>だ"#),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "本当に嘘だ\n");
+    assert_eq!(annotations.yomi.len(), yomi_count);
+    let marks: Vec<_> = annotations
+        .emphasis
+        .iter()
+        .map(|em| (&result[em.span.clone()], em.mark.to_string()))
+        .collect();
+    assert_eq!(
+        marks,
+        [("本当", "filled sesame".to_owned()), ("嘘", "filled dot".to_owned())]
+    );
+
+    // the tiers of double-sided ruby
+    buf.clear();
+    annotations.yomi.clear();
+    let result = with_fmt_stripped(
+        &para("<ruby><rb>東</rb><rb>京</rb><rt>とう</rt><rt>きょう</rt><rtc>Tokyo</rtc></ruby>へ"),
+        LayoutMode::Plain,
+        &mut gaiji,
+        &policies,
+        &mut annotations,
+        &mut buf,
+    );
+    assert_eq!(result, "東京へ\n");
+    let spans = |yomi: &[Yomi<'_>]| -> Vec<_> {
+        yomi.iter()
+            .map(|yomi| format!("{}:{}", &result[yomi.span.clone()], yomi.rt))
+            .collect()
+    };
+    assert_eq!(spans(&annotations.yomi), ["東:とう", "京:きょう"]);
+    assert_eq!(spans(&annotations.ruby_tiers[0]), ["東京:Tokyo"]);
+}
+
+#[test]
+fn test_render_layout() {
+    use crate::layout::{Align, Layout};
+
+    let css = Default::default();
+    let notes = Default::default();
+    let render = |text, layout, mode| {
+        let mut p = Paragraph::body_text(text, "text/a.xhtml", &css, &notes);
+        p.layout = layout;
+        let mut buf = String::new();
+        let mut annotations = Annotations::default();
+        with_fmt_stripped(
+            &p,
+            mode,
+            &mut HashMap::new(),
+            &Policies::default(),
+            &mut annotations,
+            &mut buf,
+        );
+        buf
+    };
+    let jisage = Layout {
+        indent: 2,
+        ..Default::default()
+    };
+    assert_eq!(render("前略", jisage, LayoutMode::Plain), "前略\n");
+    assert_eq!(
+        render("前略<br/>草々", jisage, LayoutMode::Spaces),
+        "　　前略\n　　草々\n"
+    );
+    assert_eq!(
+        render("前略", jisage, LayoutMode::Aozora),
+        "［＃２字下げ］前略\n"
+    );
+    assert_eq!(
+        render("前略<br/>草々", jisage, LayoutMode::Aozora),
+        "［＃ここから２字下げ］\n前略\n草々\n［＃ここで字下げ終わり］\n"
+    );
+    let sign = Layout {
+        align: Align::End,
+        ..Default::default()
+    };
+    assert_eq!(
+        render("太郎", sign, LayoutMode::Aozora),
+        "［＃地付き］太郎\n"
+    );
+    let indented = Layout {
+        text_indent: 1,
+        ..Default::default()
+    };
+    assert_eq!(render("本文", indented, LayoutMode::Aozora), "　本文\n");
 }
@@ -3,12 +3,95 @@ use std::{
     ops::{Not, Range},
 };
 
-use crate::{error::OrDie, 死};
+use crate::{error::OrDie, readings::RubyClass, small_kana, 即死, 死};
+
+/// Aozora Bunko notations for the characters that are the ruby markup
+const AOZORA_ESCAPES: &[(char, &str)] = &[
+    ('｜', "※［＃縦線、1-1-35］"),
+    ('《', "※［＃始め二重山括弧、1-1-52］"),
+    ('》', "※［＃終わり二重山括弧、1-1-53］"),
+];
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Yomi<'src> {
     pub span: Range<usize>,
     pub rt: &'src str,
+    pub class: RubyClass,
+}
+
+/// A ruby read back from one of the outputs; the span points to the txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ruby {
+    pub span: Range<usize>,
+    pub rt: String,
+}
+
+/// How the ruby is output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RubyFormat {
+    #[default]
+    Offsets, // `start:end:rb:rt:class` lines with byte offsets into the txt
+    Aozora, // the txt with ｜漢字《かんじ》 in it
+    Html,   // the txt as HTML with <ruby> markup
+    Json,   // JSON lines with byte and character offsets
+}
+
+impl RubyFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        let format = match format {
+            "offsets" => RubyFormat::Offsets,
+            "aozora" => RubyFormat::Aozora,
+            "html" => RubyFormat::Html,
+            "json" => RubyFormat::Json,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    /// From the extension of the file, after `.ruby`
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        [RubyFormat::Offsets, RubyFormat::Aozora, RubyFormat::Html, RubyFormat::Json]
+            .into_iter()
+            .find(|format| format.extension() == ext)
+    }
+
+    /// Of the file, after `.ruby`
+    pub fn extension(self) -> &'static str {
+        match self {
+            RubyFormat::Offsets => "yomi",
+            RubyFormat::Aozora => "txt",
+            RubyFormat::Html => "html",
+            RubyFormat::Json => "jsonl",
+        }
+    }
+
+    pub fn write(self, yomi: &[Yomi], file: impl Write, txt: &str) {
+        match self {
+            RubyFormat::Offsets => write_yomi(yomi, file, txt),
+            RubyFormat::Aozora => write_aozora(yomi, file, txt),
+            RubyFormat::Html => write_html(yomi, file, txt),
+            RubyFormat::Json => write_json(yomi, file, txt),
+        }
+    }
+
+    /// Reads the output back into the txt and its ruby. The offset formats point to the txt,
+    /// which they get from `txt`; the others have the text in them.
+    pub fn read(self, source: &str, txt: impl FnOnce() -> String) -> (String, Vec<Ruby>) {
+        match self {
+            RubyFormat::Offsets => {
+                let txt = txt();
+                let ruby = read_yomi(source, &txt);
+                (txt, ruby)
+            }
+            RubyFormat::Aozora => read_aozora(source),
+            RubyFormat::Html => read_html(source),
+            RubyFormat::Json => {
+                let txt = txt();
+                let ruby = read_json(source, &txt);
+                (txt, ruby)
+            }
+        }
+    }
 }
 
 pub fn write_yomi(yomi: &[Yomi], mut file: impl Write, txt: &str) {
@@ -16,53 +99,360 @@ pub fn write_yomi(yomi: &[Yomi], mut file: impl Write, txt: &str) {
     for &Yomi {
         span: Range { start, end },
         rt,
-        ..
+        class,
     } in yomi
     {
         let rb = &txt[start..end];
         let rt = fix_little_yomi(rb, rt, &mut buf);
-        writeln!(file, "{start}:{end}:{rb}:{rt}").or_(死!());
+        writeln!(file, "{start}:{end}:{rb}:{rt}:{class}").or_(死!());
+    }
+}
+
+/// Reads the `start:end:rb:rt:class` lines, checking that the bases are still where they were.
+/// The class is optional, as it's worked out again from the base and the ruby anyway.
+fn read_yomi(source: &str, txt: &str) -> Vec<Ruby> {
+    let mut ruby = Vec::new();
+    for line in source.lines() {
+        let mut fields = line.splitn(3, ':');
+        let mut offset = || {
+            fields
+                .next()
+                .and_then(|offset| offset.parse().ok())
+                .or_(死!(
+                    "Invalid yomi file: should have start:end: on every line"
+                ))
+        };
+        let (start, end): (usize, usize) = (offset(), offset());
+        // the base can have : in it, but it's as long as the span
+        let rest = fields.next().unwrap_or("");
+        let (rb, rt) = rest
+            .split_at_checked(end.saturating_sub(start))
+            .and_then(|(rb, rt)| Some((rb, rt.strip_prefix(':')?)))
+            .or_(死!("Invalid yomi file: should have rb:rt after the span"));
+        let rt = match rt.rsplit_once(':') {
+            Some((rt, class)) if RubyClass::parse(class).is_some() => rt,
+            _ => rt,
+        };
+        if txt.get(start..end) != Some(rb) {
+            即死!(
+                "The yomi file doesn't match the txt at {start}:{end}:{rb}; has the txt been edited?"
+            );
+        }
+        ruby.push(Ruby {
+            span: start..end,
+            rt: rt.to_owned(),
+        });
     }
+    ruby
 }
 
-fn fix_little_yomi<'s>(rb: &str, rt: &'s str, fixed: &'s mut String) -> &'s str {
-    let Some(little_idx) = rt.find(['や', 'ゆ', 'よ']) else {
-        return rt;
+/// The txt with the ruby in Aozora Bunko style: ｜漢字《かんじ》. Every base is marked
+/// with ｜, and the ｜《》 in the text are written as ※［＃…］ notations.
+pub fn write_aozora(yomi: &[Yomi], mut file: impl Write, txt: &str) {
+    let mut out = String::with_capacity(txt.len() * 5 / 4);
+    let push_escaped = |out: &mut String, text: &str| {
+        for ch in text.chars() {
+            match AOZORA_ESCAPES.iter().find(|&&(escaped, _)| escaped == ch) {
+                Some((_, notation)) => out.push_str(notation),
+                None => out.push(ch),
+            }
+        }
     };
-    if little_idx < 3 {
-        return rt;
+    let mut buf = String::new();
+    let mut pos = 0;
+    for Yomi { span, rt, .. } in yomi {
+        if span.start < pos {
+            continue; // overlapping ruby can't be inline
+        }
+        let rb = &txt[span.clone()];
+        push_escaped(&mut out, &txt[pos..span.start]);
+        out.push('｜');
+        push_escaped(&mut out, rb);
+        out.push('《');
+        push_escaped(&mut out, fix_little_yomi(rb, rt, &mut buf));
+        out.push('》');
+        pos = span.end;
     }
-    let previous = little_idx - 3;
+    push_escaped(&mut out, &txt[pos..]);
+    file.write_all(out.as_bytes()).or_(死!());
+}
 
-    // "お や じ" "み や こ" etc.
-    if rt.is_char_boundary(previous).not() {
-        fixed.clear();
-        fixed.extend(rt.chars().filter(|&ch| ch != ' '));
-        return fixed.as_str();
+/// Whether a character can be a base of ruby without ｜ in Aozora Bunko texts
+pub fn is_kanji(ch: char) -> bool {
+    matches!(ch, '々' | '〆' | 'ヶ' | '〇' | '\u{3400}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{3FFFF}')
+}
+
+/// Reads an Aozora Bunko style text back into the txt and its ruby. Without ｜,
+/// the base is the run of kanji before 《.
+fn read_aozora(source: &str) -> (String, Vec<Ruby>) {
+    let mut txt = String::with_capacity(source.len());
+    let mut ruby = Vec::new();
+    let mut base_start = None;
+    let mut rest = source;
+    while let Some(ch) = rest.chars().next() {
+        if let Some(&(escaped, notation)) = AOZORA_ESCAPES
+            .iter()
+            .find(|(_, notation)| rest.starts_with(notation))
+        {
+            txt.push(escaped);
+            rest = &rest[notation.len()..];
+            continue;
+        }
+        rest = &rest[ch.len_utf8()..];
+        match ch {
+            '｜' => base_start = Some(txt.len()),
+            '《' => {
+                let (rt, after) = rest
+                    .split_once('》')
+                    .or_(死!("Invalid Aozora text: 《 without 》"));
+                let start = base_start.take().unwrap_or_else(|| {
+                    let kanji = txt.chars().rev().take_while(|&ch| is_kanji(ch));
+                    txt.len() - kanji.map(char::len_utf8).sum::<usize>()
+                });
+                let rt = AOZORA_ESCAPES
+                    .iter()
+                    .fold(rt.to_owned(), |rt, &(escaped, notation)| {
+                        rt.replace(notation, &escaped.to_string())
+                    });
+                ruby.push(Ruby {
+                    span: start..txt.len(),
+                    rt,
+                });
+                rest = after;
+            }
+            ch => txt.push(ch),
+        }
+    }
+    (txt, ruby)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The txt as a standalone HTML page, a <p> per line, with the ruby as <ruby> markup
+pub fn write_html(yomi: &[Yomi], mut file: impl Write, txt: &str) {
+    let mut body = String::with_capacity(txt.len() * 3 / 2);
+    let mut buf = String::new();
+    let mut pos = 0;
+    for Yomi { span, rt, .. } in yomi {
+        if span.start < pos {
+            continue;
+        }
+        let rb = &txt[span.clone()];
+        body.push_str(&escape_html(&txt[pos..span.start]));
+        let rt = escape_html(fix_little_yomi(rb, rt, &mut buf));
+        body.push_str(&format!("<ruby>{}<rt>{rt}</rt></ruby>", escape_html(rb)));
+        pos = span.end;
     }
-    match &rt[previous..little_idx] {
-        "き" | "ぎ" | "し" | "じ" | "ち" | "ぢ" | "に" | "ひ" | "び" | "ぴ" | "み" | "り" =>
-            {}
-        _ => return rt,
+    body.push_str(&escape_html(&txt[pos..]));
+    writeln!(
+        file,
+        "<!DOCTYPE html>\n<html lang=\"ja\">\n<head>\n<meta charset=\"utf-8\">\n</head>\n<body>"
+    )
+    .or_(死!());
+    for line in body.split('\n') {
+        writeln!(file, "<p>{line}</p>").or_(死!());
     }
+    writeln!(file, "</body>\n</html>").or_(死!());
+}
 
-    if ["清", "日和"].contains(&rb) {
-        return rt;
+/// Reads the HTML written by `write_html` back into the txt and its ruby
+fn read_html(source: &str) -> (String, Vec<Ruby>) {
+    let body = source
+        .split_once("<body>\n")
+        .and_then(|(_, body)| body.rsplit_once("</body>"))
+        .map(|(body, _)| body)
+        .or_(死!("Invalid ruby HTML: should have a <body>"));
+    let unescape = |text: &str| {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&")
+    };
+    let mut txt = String::with_capacity(source.len());
+    let mut ruby = Vec::new();
+    for (idx, line) in body.lines().enumerate() {
+        let mut line = line
+            .strip_prefix("<p>")
+            .and_then(|line| line.strip_suffix("</p>"))
+            .or_(死!("Invalid ruby HTML: should have a <p> on every line"));
+        if idx > 0 {
+            txt.push('\n');
+        }
+        while let Some((text, after)) = line.split_once("<ruby>") {
+            txt.push_str(&unescape(text));
+            let (rb, rt, after) = after
+                .split_once("<rt>")
+                .and_then(|(rb, after)| {
+                    let (rt, after) = after.split_once("</rt></ruby>")?;
+                    Some((rb, rt, after))
+                })
+                .or_(死!("Invalid ruby HTML: <ruby> should have <rt>"));
+            let start = txt.len();
+            txt.push_str(&unescape(rb));
+            ruby.push(Ruby {
+                span: start..txt.len(),
+                rt: unescape(rt),
+            });
+            line = after;
+        }
+        txt.push_str(&unescape(line));
+    }
+    (txt, ruby)
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// JSON lines like
+/// `{"start":0,"end":6,"char_start":0,"char_end":2,"rb":"漢字","rt":"かんじ","class":"reading"}`;
+/// the character offsets are for the tools that don't count in UTF-8 bytes
+pub fn write_json(yomi: &[Yomi], mut file: impl Write, txt: &str) {
+    let mut buf = String::new();
+    // the character offset of a byte offset, counted on from the previous one
+    let (mut byte_pos, mut char_pos) = (0, 0);
+    let mut char_offset = |offset: usize| {
+        if offset < byte_pos {
+            (byte_pos, char_pos) = (0, 0);
+        }
+        char_pos += txt[byte_pos..offset].chars().count();
+        byte_pos = offset;
+        char_pos
+    };
+    for Yomi { span, rt, class } in yomi {
+        let rb = &txt[span.clone()];
+        let rt = fix_little_yomi(rb, rt, &mut buf);
+        let (char_start, char_end) = (char_offset(span.start), char_offset(span.end));
+        writeln!(
+            file,
+            r#"{{"start":{},"end":{},"char_start":{char_start},"char_end":{char_end},"rb":{},"rt":{},"class":"{class}"}}"#,
+            span.start,
+            span.end,
+            escape_json(rb),
+            escape_json(rt),
+        )
+        .or_(死!());
+    }
+}
+
+/// A JSON value of the flat objects of `write_json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum JsonValue {
+    Number(usize),
+    String(String),
+}
+
+/// Parses a flat object of unsigned integers and strings
+fn parse_json_object(line: &str) -> Option<Vec<(String, JsonValue)>> {
+    let mut chars = line.trim().strip_prefix('{')?.chars().peekable();
+    let parse_string = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut string = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(string),
+                '\\' => match chars.next()? {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    ch => string.push(ch),
+                },
+                ch => string.push(ch),
+            }
+        }
+    };
+    let mut object = Vec::new();
+    loop {
+        match chars.next()? {
+            '}' => return Some(object),
+            ',' | ' ' => continue,
+            '"' => {
+                let key = parse_string(&mut chars)?;
+                while chars.next_if(|&ch| ch == ' ' || ch == ':').is_some() {}
+                let value = if chars.next_if_eq(&'"').is_some() {
+                    JsonValue::String(parse_string(&mut chars)?)
+                } else {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(digit);
+                    }
+                    JsonValue::Number(digits.parse().ok()?)
+                };
+                object.push((key, value));
+            }
+            _ => return None,
+        }
     }
+}
 
-    let next = little_idx + 3;
-    debug_assert!(rt.is_char_boundary(next));
+/// Reads the JSON lines back, by the byte offsets, checking that the bases are still
+/// where they were
+fn read_json(source: &str, txt: &str) -> Vec<Ruby> {
+    let mut ruby = Vec::new();
+    for line in source.lines().filter(|line| line.trim().is_empty().not()) {
+        let object = parse_json_object(line).or_(死!("Invalid ruby JSON: {line}"));
+        let field = |name: &str| {
+            object
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .or_(死!("Invalid ruby JSON: should have {name} in {line}"))
+        };
+        let (
+            JsonValue::Number(start),
+            JsonValue::Number(end),
+            JsonValue::String(rb),
+            JsonValue::String(rt),
+        ) = (field("start"), field("end"), field("rb"), field("rt"))
+        else {
+            即死!("Invalid ruby JSON: wrong types in {line}");
+        };
+        if txt.get(*start..*end) != Some(rb) {
+            即死!(
+                "The ruby JSON doesn't match the txt at {start}:{end}:{rb}; has the txt been edited?"
+            );
+        }
+        ruby.push(Ruby {
+            span: *start..*end,
+            rt: rt.clone(),
+        });
+    }
+    ruby
+}
 
-    fixed.clear();
-    fixed.push_str(&rt[..little_idx]);
-    match &rt[little_idx..next] {
-        "や" => fixed.push('ゃ'),
-        "ゆ" => fixed.push('ゅ'),
-        "よ" => fixed.push('ょ'),
-        _ => unreachable!(),
+/// Restores the small kana of old-style ruby (see `small_kana`), and joins readings spaced
+/// out over the characters of the base, e.g. "お や じ"
+pub fn fix_little_yomi<'s>(rb: &str, rt: &'s str, fixed: &'s mut String) -> &'s str {
+    if rt.contains(' ') && rt.chars().any(|ch| ch.is_ascii_alphanumeric()).not() {
+        let joined: String = rt.chars().filter(|&ch| ch != ' ').collect();
+        *fixed = small_kana::restore(rb, &joined).unwrap_or(joined);
+        return fixed.as_str();
+    }
+    match small_kana::restore(rb, rt) {
+        Some(restored) => {
+            *fixed = restored;
+            fixed.as_str()
+        }
+        None => rt,
     }
-    fixed.push_str(&rt[next..]);
-    fixed.as_str()
 }
 
 #[test]
@@ -92,6 +482,79 @@ fn test_fix_little_yomi() {
         ("玩具", "おもちや", "おもちゃ"),
         ("親父", "お や じ", "おやじ"),
     ] {
-        assert_eq!(fix_little_yomi(rb, rt, &mut fixed), expected);
+        assert_eq!(fix_little_yomi(rb, rt, &mut fixed), expected, "{rb} {rt}");
+    }
+}
+
+#[test]
+fn test_ruby_formats() {
+    let txt = "｜山《やま》へ\n清らかな\"a&b\"。\n";
+    let yomi = [
+        Yomi {
+            span: 3..6,
+            rt: "やま",
+            class: RubyClass::Reading,
+        },
+        Yomi {
+            span: 22..25,
+            rt: "きよ",
+            class: RubyClass::Reading,
+        },
+        Yomi {
+            span: 34..37,
+            rt: "<&>",
+            class: RubyClass::Gloss,
+        },
+    ];
+    let expected: Vec<_> = yomi
+        .iter()
+        .map(|yomi| Ruby {
+            span: yomi.span.clone(),
+            rt: yomi.rt.to_owned(),
+        })
+        .collect();
+    let render = |format: RubyFormat| {
+        let mut out = Vec::new();
+        format.write(&yomi, &mut out, txt);
+        String::from_utf8(out).unwrap()
+    };
+
+    let offsets = render(RubyFormat::Offsets);
+    assert!(offsets.starts_with("3:6:山:やま:reading\n"));
+    assert_eq!(read_yomi(&offsets, txt), expected);
+    // without the class, as written before there were classes
+    assert_eq!(read_yomi("3:6:山:やま\n", txt), expected[..1]);
+
+    let aozora = render(RubyFormat::Aozora);
+    assert!(aozora.starts_with(
+        "※［＃縦線、1-1-35］｜山《やま》※［＃始め二重山括弧、1-1-52］やま※［＃終わり二重山括弧、1-1-53］へ"
+    ));
+    assert_eq!(read_aozora(&aozora), (txt.to_owned(), expected.clone()));
+    // without ｜, the kanji before 《 are the base
+    let (plain, ruby) = read_aozora("その漢字《かんじ》は");
+    assert_eq!(plain, "その漢字は");
+    assert_eq!(&plain[ruby[0].span.clone()], "漢字");
+
+    let html = render(RubyFormat::Html);
+    assert!(html.contains("<p>｜<ruby>山<rt>やま</rt></ruby>《やま》へ</p>"));
+    assert_eq!(read_html(&html), (txt.to_owned(), expected.clone()));
+
+    let json = render(RubyFormat::Json);
+    assert!(json.starts_with(
+        r#"{"start":3,"end":6,"char_start":1,"char_end":2,"rb":"山","rt":"やま","class":"reading"}"#
+    ));
+    assert!(
+        json.contains(r#""char_start":12,"char_end":15,"rb":"\"a&","rt":"<&>","class":"gloss"}"#)
+    );
+    assert_eq!(read_json(&json, txt), expected);
+
+    // read back by the extension of the file
+    for format in [RubyFormat::Offsets, RubyFormat::Aozora, RubyFormat::Html, RubyFormat::Json] {
+        let format = RubyFormat::from_extension(format.extension()).unwrap();
+        assert_eq!(
+            format.read(&render(format), || txt.to_owned()),
+            (txt.to_owned(), expected.clone())
+        );
     }
+    assert_eq!(RubyFormat::from_extension("md"), None);
 }